@@ -0,0 +1,357 @@
+//! Local accounts and their API tokens. Passwords are hashed with argon2, tokens are random and
+//! only their sha256 is stored, so neither can be read from the database. An account may belong
+//! to an alliance, its private data like watchlists is shared with the accounts of that alliance.
+
+use std::fmt::Write;
+
+use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// may read the private data of its alliance
+    ReadOnly,
+    /// may also change it, i.e. create watchlists
+    Member,
+    /// may do everything, for every alliance, and manage the accounts
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::ReadOnly, Role::Member, Role::Admin];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read_only",
+            Role::Member => "member",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Account {
+    pub id: i64,
+    pub name: String,
+    pub role: Role,
+    /// the alliance whose private data the account may see, None for no alliance
+    pub alliance_id: Option<u32>,
+}
+
+impl Account {
+    /// whether the account may see data that belongs to the given alliance. Data without an
+    /// alliance is for admins only.
+    pub fn can_see(&self, alliance_id: Option<u32>) -> bool {
+        self.role == Role::Admin || (alliance_id.is_some() && alliance_id == self.alliance_id)
+    }
+}
+
+impl<'a> TryFrom<&Row<'a>> for Account {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let role: String = row.get(2)?;
+        Ok(Self {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            role: Role::from_name(&role).unwrap_or(Role::ReadOnly),
+            alliance_id: row.get(3)?,
+        })
+    }
+}
+
+/// an API token as it is listed, without the token itself
+#[derive(Debug, Serialize)]
+pub struct TokenInfo {
+    pub id: i64,
+    pub label: String,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02x}");
+        out
+    })
+}
+
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// a new random token, 64 hex characters
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+const ACCOUNT_COLUMNS: &str = "id, name, role, alliance_id";
+
+pub fn list(conn: &Connection) -> rusqlite::Result<Vec<Account>> {
+    conn.prepare(&format!(
+        "SELECT {ACCOUNT_COLUMNS} FROM accounts ORDER BY name"
+    ))?
+    .query([])?
+    .mapped(|r| Account::try_from(r))
+    .collect()
+}
+
+/// create an account and return it. Fails if the name is taken.
+pub fn create(
+    conn: &Connection,
+    name: &str,
+    password: Option<&str>,
+    role: Role,
+    alliance_id: Option<u32>,
+) -> rusqlite::Result<Account> {
+    conn.execute(
+        "INSERT INTO accounts(name, password_hash, role, alliance_id) VALUES(?1, ?2, ?3, ?4)",
+        (name, password.map(hash_password), role.name(), alliance_id),
+    )?;
+    Ok(Account {
+        id: conn.last_insert_rowid(),
+        name: name.to_string(),
+        role,
+        alliance_id,
+    })
+}
+
+/// change the role and alliance of an account. Returns whether it exists.
+pub fn update(
+    conn: &Connection,
+    id: i64,
+    role: Role,
+    alliance_id: Option<u32>,
+) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        "UPDATE accounts SET role = ?2, alliance_id = ?3 WHERE id = ?1",
+        (id, role.name(), alliance_id),
+    )?;
+    Ok(updated > 0)
+}
+
+pub fn set_password(conn: &Connection, id: i64, password: &str) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        "UPDATE accounts SET password_hash = ?2 WHERE id = ?1",
+        (id, hash_password(password)),
+    )?;
+    Ok(updated > 0)
+}
+
+/// remove the account and its tokens. Returns whether it existed.
+pub fn delete(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.execute("DELETE FROM api_tokens WHERE account_id = ?1", [id])?;
+    let deleted = conn.execute("DELETE FROM accounts WHERE id = ?1", [id])?;
+    Ok(deleted > 0)
+}
+
+/// the account with the given name, if the password is right
+pub fn check_password(
+    conn: &Connection,
+    name: &str,
+    password: &str,
+) -> rusqlite::Result<Option<Account>> {
+    let found: Option<(Account, Option<String>)> = conn
+        .query_row(
+            &format!("SELECT {ACCOUNT_COLUMNS}, password_hash FROM accounts WHERE name = ?1"),
+            [name],
+            |r| Ok((Account::try_from(r)?, r.get(4)?)),
+        )
+        .optional()?;
+    let Some((account, Some(hash))) = found else {
+        return Ok(None);
+    };
+    let valid = PasswordHash::new(&hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    });
+    Ok(valid.then_some(account))
+}
+
+/// create a token for the account and return it. It can't be recovered later. Expired tokens of
+/// all accounts are deleted on the way, every login creates one.
+pub fn create_token(
+    conn: &Connection,
+    account_id: i64,
+    label: &str,
+    expires: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<String> {
+    conn.execute("DELETE FROM api_tokens WHERE expires <= ?1", [now])?;
+    let token = new_token();
+    conn.execute(
+        "INSERT INTO api_tokens(account_id, token_hash, label, created, expires)
+            VALUES(?1, ?2, ?3, ?4, ?5)",
+        (account_id, hash_token(&token), label, now, expires),
+    )?;
+    Ok(token)
+}
+
+/// the account the token belongs to, unless the token is unknown or expired
+pub fn account_for_token(
+    conn: &Connection,
+    token: &str,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<Option<Account>> {
+    conn.query_row(
+        "SELECT a.id, a.name, a.role, a.alliance_id FROM api_tokens t
+            JOIN accounts a ON a.id = t.account_id
+            WHERE t.token_hash = ?1 AND (t.expires IS NULL OR t.expires > ?2)",
+        (hash_token(token), now),
+        |r| Account::try_from(r),
+    )
+    .optional()
+}
+
+pub fn tokens(conn: &Connection, account_id: i64) -> rusqlite::Result<Vec<TokenInfo>> {
+    conn.prepare(
+        "SELECT id, label, created, expires FROM api_tokens WHERE account_id = ?1 ORDER BY id",
+    )?
+    .query([account_id])?
+    .mapped(|r| {
+        Ok(TokenInfo {
+            id: r.get(0)?,
+            label: r.get(1)?,
+            created: r.get(2)?,
+            expires: r.get(3)?,
+        })
+    })
+    .collect()
+}
+
+/// remove a token of the account. Returns whether it existed.
+pub fn revoke_token(conn: &Connection, account_id: i64, token_id: i64) -> rusqlite::Result<bool> {
+    let deleted = conn.execute(
+        "DELETE FROM api_tokens WHERE id = ?1 AND account_id = ?2",
+        (token_id, account_id),
+    )?;
+    Ok(deleted > 0)
+}
+
+/// remove the token itself, used to log out
+pub fn revoke_by_token(conn: &Connection, token: &str) -> rusqlite::Result<bool> {
+    let deleted = conn.execute(
+        "DELETE FROM api_tokens WHERE token_hash = ?1",
+        [hash_token(token)],
+    )?;
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        "2024-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn passwords_and_tokens() {
+        let conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        let account = create(&conn, "odysseus", Some("ithaca"), Role::Member, Some(7)).unwrap();
+        assert!(create(&conn, "odysseus", None, Role::Admin, None).is_err());
+
+        assert!(check_password(&conn, "odysseus", "troy").unwrap().is_none());
+        assert!(check_password(&conn, "penelope", "ithaca")
+            .unwrap()
+            .is_none());
+        let found = check_password(&conn, "odysseus", "ithaca")
+            .unwrap()
+            .unwrap();
+        assert_eq!((found.id, found.role), (account.id, Role::Member));
+        let stored: String = conn
+            .query_row("SELECT password_hash FROM accounts", [], |r| r.get(0))
+            .unwrap();
+        assert!(!stored.contains("ithaca"));
+
+        let token = create_token(&conn, account.id, "cli", None, now()).unwrap();
+        let expiring = create_token(
+            &conn,
+            account.id,
+            "login",
+            Some(now() + Duration::try_hours(1).unwrap()),
+            now(),
+        )
+        .unwrap();
+        assert_eq!(
+            account_for_token(&conn, &token, now()).unwrap().unwrap().id,
+            account.id
+        );
+        assert!(account_for_token(&conn, &expiring, now())
+            .unwrap()
+            .is_some());
+        let later = now() + Duration::try_hours(2).unwrap();
+        assert!(account_for_token(&conn, &expiring, later)
+            .unwrap()
+            .is_none());
+        // creating the next token removes the expired one
+        assert_eq!(tokens(&conn, account.id).unwrap().len(), 2);
+        create_token(&conn, account.id, "login", None, later).unwrap();
+        let labels: Vec<String> = tokens(&conn, account.id)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.label)
+            .collect();
+        assert_eq!(labels, ["cli", "login"]);
+        assert!(account_for_token(&conn, "guessed", now())
+            .unwrap()
+            .is_none());
+
+        assert!(revoke_by_token(&conn, &token).unwrap());
+        assert!(account_for_token(&conn, &token, now()).unwrap().is_none());
+        assert_eq!(tokens(&conn, account.id).unwrap().len(), 1);
+        assert!(delete(&conn, account.id).unwrap());
+        assert!(tokens(&conn, account.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn alliance_scopes() {
+        let member = Account {
+            id: 1,
+            name: String::from("odysseus"),
+            role: Role::Member,
+            alliance_id: Some(7),
+        };
+        assert!(member.can_see(Some(7)));
+        assert!(!member.can_see(Some(8)));
+        assert!(!member.can_see(None));
+        let admin = Account {
+            role: Role::Admin,
+            alliance_id: None,
+            ..member
+        };
+        assert!(admin.can_see(Some(8)));
+        assert!(admin.can_see(None));
+        let loner = Account {
+            role: Role::Member,
+            alliance_id: None,
+            ..admin
+        };
+        assert!(!loner.can_see(None));
+        assert!(!loner.can_see(Some(7)));
+        assert!(Role::ReadOnly < Role::Member && Role::Member < Role::Admin);
+    }
+}
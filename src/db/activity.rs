@@ -0,0 +1,80 @@
+//! Since when the points of every player have not changed. A player whose points stay the same
+//! for a long time is most likely inactive, their towns are easy targets.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+
+use crate::model::database::DataTable;
+
+/// update the activity of every player in the snapshot, all in one transaction. Players whose
+/// points changed are active as of the time the snapshot was loaded.
+pub fn update_activity(conn: &mut Connection, dt: &DataTable) -> rusqlite::Result<()> {
+    let transaction = conn.transaction()?;
+    let mut statement = transaction.prepare(
+        "INSERT INTO player_activity VALUES(?1, ?2, ?3)
+            ON CONFLICT(player_id) DO UPDATE SET
+                unchanged_since = CASE WHEN points = excluded.points
+                    THEN unchanged_since ELSE excluded.unchanged_since END,
+                points = excluded.points",
+    )?;
+    for player in dt.players.values() {
+        statement.execute((player.id, player.points, dt.loaded))?;
+    }
+    drop(statement);
+    transaction.commit()
+}
+
+/// the players whose points did not change since at least `before`, with the time since when
+pub fn inactive_players(
+    conn: &Connection,
+    before: DateTime<Utc>,
+) -> rusqlite::Result<HashMap<u32, DateTime<Utc>>> {
+    conn.prepare(
+        "SELECT player_id, unchanged_since FROM player_activity WHERE unchanged_since <= ?1",
+    )?
+    .query([before])?
+    .mapped(|r| Ok((r.get(0)?, r.get(1)?)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::model::fixtures::{player, world};
+
+    #[test]
+    fn unchanged_since_is_kept_until_the_points_change() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        let start: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+        let at = |hours| start + Duration::try_hours(hours).unwrap();
+        let snapshot = |hours, hector_points| {
+            let mut hector = player(1, "Hector", None);
+            hector.points = hector_points;
+            world(
+                at(hours),
+                vec![],
+                vec![hector, player(2, "Paris", None)],
+                vec![],
+            )
+        };
+
+        update_activity(&mut conn, &snapshot(0, 5000)).unwrap();
+        update_activity(&mut conn, &snapshot(24, 5000)).unwrap();
+        let inactive = inactive_players(&conn, at(24)).unwrap();
+        assert_eq!(inactive, HashMap::from([(1, at(0)), (2, at(0))]));
+
+        // Hector grew, Paris didn't
+        update_activity(&mut conn, &snapshot(48, 5200)).unwrap();
+        let inactive = inactive_players(&conn, at(48)).unwrap();
+        assert_eq!(inactive, HashMap::from([(1, at(48)), (2, at(0))]));
+        assert_eq!(
+            inactive_players(&conn, at(24)).unwrap(),
+            HashMap::from([(2, at(0))])
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! How long ghost towns stay ghost towns and who takes them, from the conquered ghost towns that
+//! are linked to their appearance (see [`super::link_conquests`]).
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::model::region::ocean_at;
+
+/// the upper bounds in hours of the buckets of the lifetime distribution, the last bucket has none
+pub const BUCKET_HOURS: [u32; 5] = [6, 24, 72, 168, 672];
+
+#[derive(Debug, Default, Serialize)]
+pub struct Lifetimes {
+    /// the number of linked conquests
+    pub count: usize,
+    pub average_hours: Option<f64>,
+    pub median_hours: Option<f64>,
+    /// how many ghost towns were conquered within each of `BUCKET_HOURS`, plus the ones after
+    pub buckets: [usize; BUCKET_HOURS.len() + 1],
+}
+
+impl Lifetimes {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(mut seconds: Vec<i64>) -> Self {
+        if seconds.is_empty() {
+            return Self::default();
+        }
+        seconds.sort_unstable();
+        let hours = |s: i64| s as f64 / 3600.0;
+        let n = seconds.len();
+        let median = if n % 2 == 1 {
+            hours(seconds[n / 2])
+        } else {
+            f64::midpoint(hours(seconds[n / 2 - 1]), hours(seconds[n / 2]))
+        };
+        let mut buckets = [0; BUCKET_HOURS.len() + 1];
+        for s in &seconds {
+            let bucket = BUCKET_HOURS
+                .iter()
+                .position(|h| *s < i64::from(*h) * 3600)
+                .unwrap_or(BUCKET_HOURS.len());
+            buckets[bucket] += 1;
+        }
+        Self {
+            count: n,
+            average_hours: Some(seconds.iter().map(|s| hours(*s)).sum::<f64>() / n as f64),
+            median_hours: Some(median),
+            buckets,
+        }
+    }
+}
+
+/// a player or alliance ranked by the number of ghost towns it conquered
+#[derive(Debug, Serialize)]
+pub struct Conqueror {
+    /// unknown for conquests recorded before the ids were stored
+    pub id: Option<u32>,
+    /// the name at the latest conquest
+    pub name: String,
+    pub conquests: u32,
+    pub points: u32,
+    /// the average time the towns were ghost towns, over the linked conquests
+    pub average_ghost_hours: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GhostTownStats {
+    pub since: Option<DateTime<Utc>>,
+    /// conquests that could not be linked, because the town was a ghost town before it was tracked
+    pub unlinked: usize,
+    pub overall: Lifetimes,
+    pub oceans: BTreeMap<u16, Lifetimes>,
+    pub top_players: Vec<Conqueror>,
+    pub top_alliances: Vec<Conqueror>,
+}
+
+/// the conquerors with the most conquests, `kind` is either `player` or `alliance`
+fn top_conquerors(
+    conn: &Connection,
+    kind: &str,
+    since: DateTime<Utc>,
+    limit: usize,
+) -> rusqlite::Result<Vec<Conqueror>> {
+    // conquests without id are grouped by name. The bare name column takes the value of the
+    // row with the latest date.
+    conn.prepare(&format!(
+        "SELECT {kind}_id, {kind}, MAX(date), COUNT(*), SUM(points), AVG(ghost_seconds) / 3600.0
+            FROM gs_conquered
+            WHERE date >= ?1 AND {kind} IS NOT NULL
+            GROUP BY IFNULL({kind}_id, -1), CASE WHEN {kind}_id IS NULL THEN {kind} END
+            ORDER BY COUNT(*) DESC, SUM(points) DESC
+            LIMIT ?2"
+    ))?
+    .query((since, i64::try_from(limit).unwrap_or(i64::MAX)))?
+    .mapped(|r| {
+        Ok(Conqueror {
+            id: r.get(0)?,
+            name: r.get(1)?,
+            conquests: r.get(3)?,
+            points: r.get(4)?,
+            average_ghost_hours: r.get(5)?,
+        })
+    })
+    .collect()
+}
+
+/// the ghost town statistics of the conquests recorded at or after `since`, with the `limit`
+/// most successful players and alliances
+pub fn ghost_town_stats(
+    conn: &Connection,
+    since: Option<DateTime<Utc>>,
+    limit: usize,
+) -> rusqlite::Result<GhostTownStats> {
+    let from = since.unwrap_or_default();
+    let mut all = Vec::new();
+    let mut per_ocean: BTreeMap<u16, Vec<i64>> = BTreeMap::new();
+    let mut unlinked = 0;
+    let mut statement =
+        conn.prepare("SELECT x, y, ghost_seconds FROM gs_conquered WHERE date >= ?1")?;
+    let mut rows = statement.query([from])?;
+    while let Some(row) = rows.next()? {
+        let Some(seconds) = row.get::<_, Option<i64>>(2)? else {
+            unlinked += 1;
+            continue;
+        };
+        all.push(seconds);
+        per_ocean
+            .entry(ocean_at(row.get(0)?, row.get(1)?))
+            .or_default()
+            .push(seconds);
+    }
+
+    Ok(GhostTownStats {
+        since,
+        unlinked,
+        overall: Lifetimes::new(all),
+        oceans: per_ocean
+            .into_iter()
+            .map(|(ocean, seconds)| (ocean, Lifetimes::new(seconds)))
+            .collect(),
+        top_players: top_conquerors(conn, "player", from, limit)?,
+        top_alliances: top_conquerors(conn, "alliance", from, limit)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifetimes_are_bucketed_by_hours() {
+        let lifetimes = Lifetimes::new(vec![3600, 7 * 3600, 2 * 86400, 40 * 86400]);
+        assert_eq!(lifetimes.count, 4);
+        assert_eq!(lifetimes.median_hours, Some(27.5));
+        assert_eq!(lifetimes.buckets, [1, 1, 1, 0, 0, 1]);
+        assert_eq!(Lifetimes::new(Vec::new()).average_hours, None);
+    }
+
+    fn at(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn conquests_are_linked_to_the_latest_appearance_before_them() {
+        let conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        let appeared = "INSERT INTO gs_appeared (date, name, points, x, y, player, town_id) VALUES (?1, 't', 100, 500, 500, 'q', ?2)";
+        conn.execute(appeared, (at("2024-05-01T00:00:00Z"), 1))
+            .unwrap();
+        conn.execute(appeared, (at("2024-05-03T00:00:00Z"), 1))
+            .unwrap();
+        conn.execute(appeared, (at("2024-05-05T00:00:00Z"), 1))
+            .unwrap();
+        let conquered = "INSERT INTO gs_conquered (date, name, points, x, y, player, town_id, player_id) VALUES (?1, 't', 100, 500, 500, 'p', ?2, 7)";
+        conn.execute(conquered, (at("2024-05-04T06:00:00Z"), 1))
+            .unwrap();
+        conn.execute(conquered, (at("2024-05-04T06:00:00Z"), 2))
+            .unwrap();
+
+        assert_eq!(super::super::link_conquests(&conn).unwrap(), 1);
+        let stats = ghost_town_stats(&conn, None, 10).unwrap();
+        assert_eq!(stats.unlinked, 1);
+        assert_eq!(stats.overall.median_hours, Some(30.0));
+        assert_eq!(stats.oceans[&55].count, 1);
+        assert_eq!(stats.top_players.len(), 1);
+        assert_eq!(stats.top_players[0].id, Some(7));
+        assert_eq!(stats.top_players[0].conquests, 2);
+
+        // the conquest of town 2 is not tried again, unless town 2 shows up
+        assert_eq!(super::super::link_conquests(&conn).unwrap(), 0);
+        conn.execute(appeared, (at("2024-05-02T00:00:00Z"), 2))
+            .unwrap();
+        assert_eq!(super::super::link_conquests(&conn).unwrap(), 0);
+        assert_eq!(super::super::link_conquests_of(&conn, [2]).unwrap(), 1);
+        assert_eq!(ghost_town_stats(&conn, None, 10).unwrap().unlinked, 0);
+    }
+}
@@ -0,0 +1,158 @@
+//! Who conquered and lost the most towns in a time window. Everything is counted by the database,
+//! the `date` indexes of `gs_conquered` and `town_changed_owner` keep this fast on a long history.
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// what the players and alliances are ranked by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ranking {
+    /// towns taken from other players and ghost towns
+    #[default]
+    Conquests,
+    GhostTowns,
+    /// towns conquered by other players, towns that become ghost towns don't count
+    Lost,
+}
+
+impl Ranking {
+    pub const ALL: [Ranking; 3] = [Ranking::Conquests, Ranking::GhostTowns, Ranking::Lost];
+
+    /// the name of the column, the same as in the query parameters
+    pub fn column(self) -> &'static str {
+        match self {
+            Ranking::Conquests => "conquests",
+            Ranking::GhostTowns => "ghost_towns",
+            Ranking::Lost => "lost",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub id: u32,
+    /// the name at the latest event in the window
+    pub name: String,
+    pub conquests: u32,
+    pub ghost_towns: u32,
+    pub lost: u32,
+}
+
+/// the players or alliances, depending on `kind`, with the highest `ranking` among the events
+/// recorded at or after `since` and before `until`. Events recorded before the ids were stored are
+/// left out.
+pub fn leaderboard(
+    conn: &Connection,
+    kind: &str,
+    ranking: Ranking,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    limit: usize,
+) -> rusqlite::Result<Vec<Entry>> {
+    // one row per town gained or lost, the bare name column takes the value of the latest row
+    conn.prepare(&format!(
+        "SELECT id, name, MAX(date), SUM(conquests) AS conquests, SUM(ghost_towns) AS ghost_towns,
+                SUM(lost) AS lost
+            FROM (
+                SELECT after_{kind}_id AS id, after_{kind} AS name, date,
+                    1 AS conquests, 0 AS ghost_towns, 0 AS lost
+                FROM town_changed_owner WHERE date >= ?1 AND date < ?2
+                UNION ALL
+                SELECT {kind}_id, {kind}, date, 1, 1, 0
+                FROM gs_conquered WHERE date >= ?1 AND date < ?2
+                UNION ALL
+                SELECT before_{kind}_id, before_{kind}, date, 0, 0, 1
+                FROM town_changed_owner WHERE date >= ?1 AND date < ?2
+            )
+            WHERE id IS NOT NULL
+            GROUP BY id
+            HAVING {column} > 0
+            ORDER BY {column} DESC, conquests DESC, lost
+            LIMIT ?3",
+        column = ranking.column(),
+    ))?
+    .query((since, until, i64::try_from(limit).unwrap_or(i64::MAX)))?
+    .mapped(|r| {
+        Ok(Entry {
+            id: r.get(0)?,
+            name: r.get(1)?,
+            conquests: r.get(3)?,
+            ghost_towns: r.get(4)?,
+            lost: r.get(5)?,
+        })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::orm::{OrmOwnerChange, TownState},
+        messages::MessageFromModelToDB,
+    };
+
+    fn at(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    fn town(player_id: u32) -> TownState {
+        TownState {
+            name: String::from("t"),
+            points: 100,
+            player_id: Some(player_id),
+            player_name: Some(format!("p{player_id}")),
+            alliance_id: None,
+            alliance_name: None,
+        }
+    }
+
+    fn change(date: &str, from: u32, to: u32) -> OrmOwnerChange {
+        OrmOwnerChange {
+            date: at(date),
+            observed_between: [at(date), at(date)],
+            town_id: 1,
+            x: 500.0,
+            y: 500.0,
+            before: town(from),
+            after: town(to),
+        }
+    }
+
+    #[test]
+    fn counts_gains_and_losses_within_the_window() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        let changes = vec![
+            change("2024-05-02T00:00:00Z", 1, 2),
+            change("2024-05-02T00:00:00Z", 1, 3),
+            // outside the window
+            change("2024-04-01T00:00:00Z", 2, 1),
+        ];
+        super::super::insert_events(
+            &mut conn,
+            &[MessageFromModelToDB::TownsChangedOwner(changes)],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO gs_conquered (date, name, points, x, y, player, player_id)
+                VALUES (?1, 't', 100, 500, 500, 'c', 3)",
+            [at("2024-05-03T00:00:00Z")],
+        )
+        .unwrap();
+
+        let (since, until) = (at("2024-05-01T00:00:00Z"), at("2024-05-08T00:00:00Z"));
+        let top = leaderboard(&conn, "player", Ranking::Conquests, since, until, 10).unwrap();
+        let top: Vec<_> = top
+            .iter()
+            .map(|e| (e.id, e.conquests, e.ghost_towns, e.lost))
+            .collect();
+        assert_eq!(top, vec![(3, 2, 1, 0), (2, 1, 0, 0)]);
+
+        let lost = leaderboard(&conn, "player", Ranking::Lost, since, until, 10).unwrap();
+        assert_eq!(lost.len(), 1);
+        assert_eq!((lost[0].id, lost[0].lost), (1, 2));
+    }
+}
@@ -1,173 +1,753 @@
 use crate::{
-    db::orm::{OrmGS, OrmPlayer},
-    messages::{MessageFromDBToWeb, MessageFromModelToDB},
+    db::orm::{OrmGS, OrmOwnerChange, OrmPlayer, OrmRename, PLAYER_COLUMNS},
+    messages::{MessageFromDBToWeb, MessageFromDBToWebhooks, MessageFromModelToDB},
+    metrics, model, status,
     web::CachedDBState,
+    webhooks,
 };
 use chrono::offset::Utc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, SendError, Sender};
 
+pub mod accounts;
+pub mod activity;
+pub mod ghost_stats;
+pub mod leaderboard;
 pub mod orm;
+pub mod queries;
+pub mod territory;
+pub mod watchlist;
 
 use tracing::error;
 use tracing::{info, trace};
 
+const DB_PATH: &str = "db.sqlite";
+/// stored as `user_version` in the database. Increase it whenever `ensure_schema` changes.
+pub const SCHEMA_VERSION: i32 = 13;
+
+/// open a second connection to the database for reading. Used by the webserver to answer requests
+/// that need more than the data the DB pushes to it.
+pub fn open_read_only() -> rusqlite::Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open_with_flags(
+        DB_PATH,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    return Ok(conn);
+}
+
+/// open a second connection to the database for writing. Used for the tables the DB thread does
+/// not own, like the webhook subscriptions and their delivery queue.
+pub fn open_read_write() -> rusqlite::Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open(DB_PATH)?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    return Ok(conn);
+}
+
+/// the schema version stored in the database, see `SCHEMA_VERSION`
+pub fn schema_version(conn: &rusqlite::Connection) -> rusqlite::Result<i32> {
+    conn.pragma_query_value(None, "user_version", |r| r.get(0))
+}
+
+/// store the events of one diff in a single transaction. Used to import old snapshots while the
+/// server is not running, nothing is announced to the webhooks.
+pub fn insert_events(
+    conn: &mut rusqlite::Connection,
+    msgs: &[MessageFromModelToDB],
+) -> rusqlite::Result<()> {
+    let transaction = conn.transaction()?;
+    for msg in msgs {
+        DB::insert_events(&transaction, msg);
+    }
+    transaction.commit()
+}
+
+/// create the tables, add the columns newer versions need and record `SCHEMA_VERSION`
+#[allow(clippy::too_many_lines)]
+pub fn ensure_schema(conn: &rusqlite::Connection) {
+    // Every event was observed between two snapshots. `date` is the time the newer one was
+    // loaded and `observed_from` the time of the older one, the event happened in between.
+    // `observed_from` and the `before_`/`after_` columns are empty for events recorded before
+    // they were added.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS player_disappeared (
+                date TEXT NOT NULL,
+                -- the player as in the older snapshot
+                name TEXT NOT NULL,
+                towns INTEGER NOT NULL,
+                points INTEGER NOT NULL,
+                rank INTEGER NOT NULL,
+                alliance TEXT,
+                observed_from TEXT,
+                player_id INTEGER,
+                alliance_id INTEGER
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    for table in ["gs_appeared", "gs_conquered"] {
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    date TEXT NOT NULL,
+                    -- the town in the older snapshot for gs_appeared, the owner is the player
+                    -- that left it. The town in the newer snapshot for gs_conquered, the owner
+                    -- is the conqueror.
+                    name TEXT NOT NULL,
+                    points INTEGER NOT NULL,
+                    x REAL NOT NULL,
+                    y REAL NOT NULL,
+                    player TEXT NOT NULL,
+                    alliance TEXT,
+                    town_id INTEGER,
+                    player_id INTEGER,
+                    alliance_id INTEGER,
+                    observed_from TEXT,
+                    -- the town in the older snapshot
+                    before_name TEXT,
+                    before_points INTEGER,
+                    before_player_id INTEGER,
+                    before_player TEXT,
+                    before_alliance_id INTEGER,
+                    before_alliance TEXT,
+                    -- the town in the newer snapshot
+                    after_name TEXT,
+                    after_points INTEGER,
+                    after_player_id INTEGER,
+                    after_player TEXT,
+                    after_alliance_id INTEGER,
+                    after_alliance TEXT
+                );"
+            ),
+            (),
+        )
+        .expect("Failed to define the Database Schema");
+        // the ids were added later, databases from before lack them, the same for the rest
+        for column in ["town_id", "player_id", "alliance_id"] {
+            ensure_column(conn, table, column, "INTEGER");
+        }
+        ensure_column(conn, table, "observed_from", "TEXT");
+        for side in ["before", "after"] {
+            for (column, typ) in [
+                ("name", "TEXT"),
+                ("points", "INTEGER"),
+                ("player_id", "INTEGER"),
+                ("player", "TEXT"),
+                ("alliance_id", "INTEGER"),
+                ("alliance", "TEXT"),
+            ] {
+                ensure_column(conn, table, &format!("{side}_{column}"), typ);
+            }
+        }
+    }
+    // when the conquered ghost town had appeared and how many seconds it was a ghost town
+    // in between, see `link_conquests`. `link_tried` is set once a conquest was looked up.
+    ensure_column(conn, "gs_conquered", "appeared_date", "TEXT");
+    ensure_column(conn, "gs_conquered", "ghost_seconds", "INTEGER");
+    ensure_column(conn, "gs_conquered", "link_tried", "INTEGER");
+    for (index, table) in [
+        ("gs_appeared_town", "gs_appeared(town_id, date)"),
+        ("gs_conquered_town", "gs_conquered(town_id)"),
+    ] {
+        conn.execute(
+            &format!("CREATE INDEX IF NOT EXISTS {index} ON {table}"),
+            (),
+        )
+        .expect("Failed to define the Database Schema");
+    }
+    for table in ["player_renamed", "alliance_renamed"] {
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {table}(
+                    date TEXT NOT NULL,
+                    id INTEGER NOT NULL,
+                    old_name TEXT NOT NULL,
+                    new_name TEXT NOT NULL,
+                    observed_from TEXT
+                );"
+            ),
+            (),
+        )
+        .expect("Failed to define the Database Schema");
+    }
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS player_changed_alliance(
+                date TEXT NOT NULL,
+                player_id INTEGER NOT NULL,
+                -- the name in the newer snapshot
+                player TEXT NOT NULL,
+                old_alliance_id INTEGER,
+                old_alliance TEXT,
+                new_alliance_id INTEGER,
+                new_alliance TEXT,
+                observed_from TEXT
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    for table in [
+        "player_disappeared",
+        "player_renamed",
+        "alliance_renamed",
+        "player_changed_alliance",
+    ] {
+        ensure_column(conn, table, "observed_from", "TEXT");
+    }
+    // the ids were added later, departed players from before have none
+    for column in ["player_id", "alliance_id"] {
+        ensure_column(conn, "player_disappeared", column, "INTEGER");
+    }
+    // the town with the player that lost it and the town with the conqueror, as in `gs_appeared`
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS town_changed_owner(
+                date TEXT NOT NULL,
+                observed_from TEXT NOT NULL,
+                town_id INTEGER NOT NULL,
+                x REAL NOT NULL,
+                y REAL NOT NULL,
+                before_name TEXT NOT NULL,
+                before_points INTEGER NOT NULL,
+                before_player_id INTEGER,
+                before_player TEXT,
+                before_alliance_id INTEGER,
+                before_alliance TEXT,
+                after_name TEXT NOT NULL,
+                after_points INTEGER NOT NULL,
+                after_player_id INTEGER,
+                after_player TEXT,
+                after_alliance_id INTEGER,
+                after_alliance TEXT
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    // the leaderboards only look at the events of a time window
+    for table in ["gs_conquered", "town_changed_owner"] {
+        conn.execute(
+            &format!("CREATE INDEX IF NOT EXISTS {table}_date ON {table}(date)"),
+            (),
+        )
+        .expect("Failed to define the Database Schema");
+    }
+    // the territory of every alliance per ocean, one set of rows per snapshot. The shares are
+    // between 0 and 1, x and y the centroid of the towns. See `model::territory`.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS alliance_territory(
+                date TEXT NOT NULL,
+                ocean INTEGER NOT NULL,
+                alliance_id INTEGER NOT NULL,
+                alliance TEXT NOT NULL,
+                towns INTEGER NOT NULL,
+                points INTEGER NOT NULL,
+                town_share REAL NOT NULL,
+                points_share REAL NOT NULL,
+                x REAL NOT NULL,
+                y REAL NOT NULL
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS alliance_territory_date ON alliance_territory(date)",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    // the points of every player and since when they have not changed, see `activity`
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS player_activity(
+                player_id INTEGER PRIMARY KEY,
+                points INTEGER NOT NULL,
+                unchanged_since TEXT NOT NULL
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    webhooks::subscription::ensure_schema(conn).expect("Failed to define the Database Schema");
+    // named watchlists, see `watchlist`. The ids are stored comma separated, the regions as json.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS watchlists(
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                player_ids TEXT NOT NULL,
+                alliance_ids TEXT NOT NULL,
+                regions TEXT NOT NULL,
+                webhook_url TEXT,
+                email TEXT
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS watchlist_events(
+                id INTEGER PRIMARY KEY,
+                watchlist_id INTEGER NOT NULL,
+                date TEXT NOT NULL,
+                type TEXT NOT NULL,
+                summary TEXT NOT NULL,
+                data TEXT NOT NULL
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS watchlist_events_watchlist ON watchlist_events(watchlist_id, id)",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    // deliveries of watchlists instead of subscriptions
+    ensure_column(conn, "webhook_deliveries", "watchlist_id", "INTEGER");
+    // the alliance a watchlist belongs to, see `accounts`. Empty for watchlists of accounts
+    // without an alliance and those created before accounts existed.
+    ensure_column(conn, "watchlists", "alliance_id", "INTEGER");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS accounts(
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                password_hash TEXT,
+                role TEXT NOT NULL,
+                alliance_id INTEGER
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS api_tokens(
+                id INTEGER PRIMARY KEY,
+                account_id INTEGER NOT NULL,
+                token_hash TEXT NOT NULL UNIQUE,
+                label TEXT NOT NULL,
+                created TEXT NOT NULL,
+                expires TEXT
+            );",
+        (),
+    )
+    .expect("Failed to define the Database Schema");
+    let linked = link_conquests(conn).expect("Failed to link the conquered ghost towns");
+    if linked > 0 {
+        info!("Linked {linked} conquered ghost towns to their appearance");
+    }
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+        .expect("Failed to store the schema version");
+}
+
+/// the columns of `gs_appeared` and `gs_conquered` written for every event, in the order of
+/// `insert_gs`
+const GS_COLUMNS: [&str; 23] = [
+    "date",
+    "name",
+    "points",
+    "x",
+    "y",
+    "player",
+    "alliance",
+    "town_id",
+    "player_id",
+    "alliance_id",
+    "observed_from",
+    "before_name",
+    "before_points",
+    "before_player_id",
+    "before_player",
+    "before_alliance_id",
+    "before_alliance",
+    "after_name",
+    "after_points",
+    "after_player_id",
+    "after_player",
+    "after_alliance_id",
+    "after_alliance",
+];
+
+/// Link the conquered ghost towns that match `condition` and aren't linked yet to the latest
+/// appearance of the same town before them, and mark them as tried. The ghost duration is
+/// measured between the dates of both events, so it is only as exact as the time between two
+/// fetches. Returns the number of linked conquests.
+fn link(
+    conn: &rusqlite::Connection,
+    condition: &str,
+    params: impl rusqlite::Params + Copy,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        &format!(
+            "UPDATE gs_conquered SET appeared_date = (
+                SELECT MAX(a.date) FROM gs_appeared a
+                WHERE a.town_id = gs_conquered.town_id AND a.date <= gs_conquered.date
+            )
+            WHERE appeared_date IS NULL AND {condition}"
+        ),
+        params,
+    )?;
+    let linked = conn.execute(
+        &format!(
+            "UPDATE gs_conquered
+            SET ghost_seconds = CAST(ROUND((julianday(date) - julianday(appeared_date)) * 86400) AS INTEGER)
+            WHERE appeared_date IS NOT NULL AND ghost_seconds IS NULL AND {condition}"
+        ),
+        params,
+    )?;
+    // marked last, the condition of `link_conquests` asks for the mark
+    conn.execute(
+        &format!("UPDATE gs_conquered SET link_tried = 1 WHERE {condition}"),
+        params,
+    )?;
+    return Ok(linked);
+}
+
+/// Link every conquest that was never looked up, i.e. those recorded before the links were
+/// stored. Conquests of towns that were ghost towns before they were tracked stay unlinked and
+/// are not tried again.
+pub fn link_conquests(conn: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    link(conn, "link_tried IS NULL", ())
+}
+
+/// Link the conquests of the given towns, after their conquests or appearances were stored.
+/// Imported snapshots can add appearances of towns conquered already, so these are tried again.
+pub fn link_conquests_of(
+    conn: &rusqlite::Connection,
+    town_ids: impl IntoIterator<Item = u32>,
+) -> rusqlite::Result<usize> {
+    let mut linked = 0;
+    for town_id in town_ids {
+        linked += link(conn, "town_id = ?1", [town_id])?;
+    }
+    return Ok(linked);
+}
+
+/// add the column to the table unless it exists already
+fn ensure_column(conn: &rusqlite::Connection, table: &str, column: &str, typ: &str) {
+    let exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+            (table, column),
+            |r| r.get(0),
+        )
+        .expect("Failed to read the Database Schema");
+    if !exists {
+        info!("Adding column {column} to table {table}");
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {typ}"),
+            (),
+        )
+        .expect("Failed to update the Database Schema");
+    }
+}
+
 pub struct DB {
     rx: Receiver<MessageFromModelToDB>,
     tx: Sender<MessageFromDBToWeb>,
+    tx_webhooks: Sender<MessageFromDBToWebhooks>,
     conn: rusqlite::Connection,
 }
 
 impl DB {
-    pub fn new(rx: Receiver<MessageFromModelToDB>, tx: Sender<MessageFromDBToWeb>) -> Self {
-        let conn =
-            rusqlite::Connection::open("db.sqlite").expect("failed to open the database file");
+    pub fn new(
+        rx: Receiver<MessageFromModelToDB>,
+        tx: Sender<MessageFromDBToWeb>,
+        tx_webhooks: Sender<MessageFromDBToWebhooks>,
+    ) -> Self {
+        let conn = rusqlite::Connection::open(DB_PATH).expect("failed to open the database file");
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .expect("failed to set the busy timeout");
 
-        Self { rx, tx, conn }
+        Self {
+            rx,
+            tx,
+            tx_webhooks,
+            conn,
+        }
     }
 
+    #[allow(clippy::too_many_lines)]
     pub fn start(&mut self) {
         //ensure DB Schema
 
-        self.ensure_db_schema();
+        ensure_schema(&self.conn);
 
         // bring the webserver up to speed on the data we already have.
         self.send_update_to_webserver();
+        // and let the webhooks deliver whatever is still queued
+        self.notify_webhooks(0);
 
         for msg in &self.rx {
-            let now = Utc::now();
-            let transaction = self.conn.transaction().expect("Failed to open transaction");
-            match &msg {
-                MessageFromModelToDB::PlayersDisappeared(players) => {
-                    info!("Got Message from Model to DB: {msg}");
-                    if !players.is_empty() {
-                        let mut prepared_statement = transaction
-                            .prepare(
-                                "INSERT INTO player_disappeared VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
-                            )
-                            .expect("failed to prepare statement");
-                        for p in players {
-                            trace!("Inserting {p:?} into DB");
-                            let res = prepared_statement.execute((
-                                now,
-                                p.name.as_str(),
-                                p.towns,
-                                p.points,
-                                p.rank,
-                                p.alliance.as_deref(),
-                            ));
-                            if let Err(err) = res {
-                                error!("Failed to insert player into DB: {err:?}");
-                            }
-                        }
-                    }
+            metrics::received(metrics::MODEL_TO_DB);
+            if let MessageFromModelToDB::NewSnapshot(dt) = msg {
+                info!(
+                    "Got Message from Model to DB: NewSnapshot(loaded={})",
+                    dt.loaded
+                );
+                let territories = model::territory::territories(&dt);
+                let res = territory::insert_territory(&mut self.conn, dt.loaded, &territories);
+                if let Err(err) = res {
+                    error!("Failed to store the territory of the alliances: {err:?}");
                 }
-                MessageFromModelToDB::GSAppeared(gss) => {
-                    info!("Got Message from Model to DB: {msg}");
-                    if !gss.is_empty() {
-                        let mut prepared_statement = transaction
-                            .prepare("INSERT INTO gs_appeared VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)")
-                            .expect("failed to prepare statement");
-                        for gs in gss {
-                            trace!("Inserting {gs:?} into DB.gs_appeared");
-                            let res = prepared_statement.execute((
-                                now,
-                                gs.name.as_str(),
-                                gs.points,
-                                gs.x,
-                                gs.y,
-                                gs.player_name.as_deref(),
-                                gs.alliance_name.as_deref(),
-                            ));
-                            if let Err(err) = res {
-                                error!("Failed to insert gs into DB: {err:?}");
-                            }
-                        }
-                    }
+                let res = activity::update_activity(&mut self.conn, &dt);
+                if let Err(err) = res {
+                    error!("Failed to update the activity of the players: {err:?}");
                 }
-                MessageFromModelToDB::GSConquered(gss) => {
-                    info!("Got Message from Model to DB: {msg}");
-                    if !gss.is_empty() {
-                        let mut prepared_statement = transaction
-                            .prepare("INSERT INTO gs_conquered VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)")
-                            .expect("failed to prepare statement");
-                        for gs in gss {
-                            trace!("Inserting {gs:?} into DB.gs_conquered");
-                            let res = prepared_statement.execute((
-                                now,
-                                gs.name.as_str(),
-                                gs.points,
-                                gs.x,
-                                gs.y,
-                                gs.player_name.as_deref(),
-                                gs.alliance_name.as_deref(),
-                            ));
-                            if let Err(err) = res {
-                                error!("Failed to insert gs into DB: {err:?}");
-                            }
-                        }
-                    }
+                let res = self.send_to_web(MessageFromDBToWeb::NewSnapshot(dt));
+                if let Err(err) = res {
+                    error!("Failed to send snapshot to webserver: {err:?}");
                 }
+                continue;
             }
+
+            let now = Utc::now();
+            let transaction = self.conn.transaction().expect("Failed to open transaction");
+            info!("Got Message from Model to DB: {msg}");
+            Self::insert_events(&transaction, &msg);
+            // queued in the same transaction, so that no event is announced that isn't stored
+            let queued = webhooks::subscription::enqueue(&transaction, &msg, now)
+                + watchlist::record(&transaction, &msg, now);
             transaction
                 .commit()
                 .expect("Failed to commit transaction for table offsets");
+            status::record_commit();
+            if queued > 0 {
+                self.notify_webhooks(queued);
+            }
 
             // Send the new data to the web part
             // for this we turn the db table into a vector of tuples. Limited in length to keep it managable
 
             // TODO: make web and db more coupled and allow for filtering by position x/y
             self.send_update_to_webserver();
+            let res = self.send_to_web(MessageFromDBToWeb::EventsCommitted(msg));
+            if let Err(err) = res {
+                error!("Failed to send new events to webserver: {err:?}");
+            }
         }
     }
 
-    fn ensure_db_schema(&self) {
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS player_disappeared (
-                    date TEXT NOT NULL,
-                    name TEXT NOT NULL,
-                    towns INTEGER NOT NULL,
-                    points INTEGER NOT NULL,
-                    rank INTEGER NOT NULL,
-                    alliance TEXT
-                );",
-                (),
-            )
-            .expect("Failed to define the Database Schema");
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS gs_appeared (
-                    date TEXT NOT NULL,
-                    name TEXT NOT NULL,
-                    points INTEGER NOT NULL,
-                    x REAL NOT NULL,
-                    y REAL NOT NULL,
-                    player TEXT NOT NULL,
-                    alliance TEXT
-                );",
-                (),
-            )
-            .expect("Failed to define the Database Schema");
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS gs_conquered(
-                    date TEXT NOT NULL,
-                    name TEXT NOT NULL,
-                    points INTEGER NOT NULL,
-                    x REAL NOT NULL,
-                    y REAL NOT NULL,
-                    player TEXT NOT NULL,
-                    alliance TEXT 
-                );",
-                (),
-            )
-            .expect("Failed to define the Database Schema");
+    fn send_to_web(&self, msg: MessageFromDBToWeb) -> Result<(), SendError<MessageFromDBToWeb>> {
+        self.tx
+            .send(msg)
+            .inspect(|()| metrics::sent(metrics::DB_TO_WEB))
+    }
+
+    fn notify_webhooks(&self, queued: usize) {
+        let res = self
+            .tx_webhooks
+            .send(MessageFromDBToWebhooks::NewDeliveries(queued))
+            .inspect(|()| metrics::sent(metrics::DB_TO_WEBHOOKS));
+        if let Err(err) = res {
+            error!("Failed to notify the webhooks: {err:?}");
+        }
+    }
+
+    /// insert the events into their tables, each with the date it was observed at
+    #[allow(clippy::too_many_lines)]
+    fn insert_events(transaction: &rusqlite::Transaction, msg: &MessageFromModelToDB) {
+        match &msg {
+            MessageFromModelToDB::PlayersDisappeared(players) => {
+                if !players.is_empty() {
+                    let placeholders: Vec<_> = (1..=PLAYER_COLUMNS.len())
+                        .map(|i| format!("?{i}"))
+                        .collect();
+                    let mut prepared_statement = transaction
+                        .prepare(&format!(
+                            "INSERT INTO player_disappeared ({}) VALUES({})",
+                            PLAYER_COLUMNS.join(", "),
+                            placeholders.join(", ")
+                        ))
+                        .expect("failed to prepare statement");
+                    for p in players {
+                        trace!("Inserting {p:?} into DB");
+                        let res = prepared_statement.execute((
+                            p.date,
+                            p.name.as_str(),
+                            p.towns,
+                            p.points,
+                            p.rank,
+                            p.alliance.as_deref(),
+                            p.observed_between.map(|w| w[0]),
+                            p.player_id,
+                            p.alliance_id,
+                        ));
+                        if let Err(err) = res {
+                            metrics::DB_INSERT_FAILURES
+                                .with_label_values(&["player_disappeared"])
+                                .inc();
+                            error!("Failed to insert player into DB: {err:?}");
+                        }
+                    }
+                }
+            }
+            MessageFromModelToDB::GSAppeared(gss) => {
+                Self::insert_gs(transaction, "gs_appeared", gss);
+                Self::link_towns(transaction, gss);
+            }
+            MessageFromModelToDB::GSConquered(gss) => {
+                Self::insert_gs(transaction, "gs_conquered", gss);
+                Self::link_towns(transaction, gss);
+            }
+            MessageFromModelToDB::PlayersRenamed(renames) => {
+                Self::insert_renames(transaction, "player_renamed", renames);
+            }
+            MessageFromModelToDB::AlliancesRenamed(renames) => {
+                Self::insert_renames(transaction, "alliance_renamed", renames);
+            }
+            MessageFromModelToDB::PlayersChangedAlliance(changes) => {
+                if !changes.is_empty() {
+                    let mut prepared_statement = transaction
+                        .prepare(
+                            "INSERT INTO player_changed_alliance VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        )
+                        .expect("failed to prepare statement");
+                    for change in changes {
+                        trace!("Inserting {change:?} into DB.player_changed_alliance");
+                        let res = prepared_statement.execute((
+                            change.date,
+                            change.player_id,
+                            change.player_name.as_str(),
+                            change.old_alliance_id,
+                            change.old_alliance_name.as_deref(),
+                            change.new_alliance_id,
+                            change.new_alliance_name.as_deref(),
+                            change.observed_between.map(|w| w[0]),
+                        ));
+                        if let Err(err) = res {
+                            metrics::DB_INSERT_FAILURES
+                                .with_label_values(&["player_changed_alliance"])
+                                .inc();
+                            error!("Failed to insert alliance change into DB: {err:?}");
+                        }
+                    }
+                }
+            }
+            MessageFromModelToDB::TownsChangedOwner(changes) => {
+                Self::insert_owner_changes(transaction, changes);
+            }
+            MessageFromModelToDB::NewSnapshot(_) => {}
+        }
+    }
+
+    fn insert_owner_changes(transaction: &rusqlite::Transaction, changes: &[OrmOwnerChange]) {
+        if changes.is_empty() {
+            return;
+        }
+        let placeholders: Vec<_> = (1..=17).map(|i| format!("?{i}")).collect();
+        let mut prepared_statement = transaction
+            .prepare(&format!(
+                "INSERT INTO town_changed_owner VALUES({})",
+                placeholders.join(", ")
+            ))
+            .expect("failed to prepare statement");
+        for change in changes {
+            trace!("Inserting {change:?} into DB.town_changed_owner");
+            let (before, after) = (&change.before, &change.after);
+            let res = prepared_statement.execute(rusqlite::params![
+                change.date,
+                change.observed_between[0],
+                change.town_id,
+                change.x,
+                change.y,
+                before.name.as_str(),
+                before.points,
+                before.player_id,
+                before.player_name.as_deref(),
+                before.alliance_id,
+                before.alliance_name.as_deref(),
+                after.name.as_str(),
+                after.points,
+                after.player_id,
+                after.player_name.as_deref(),
+                after.alliance_id,
+                after.alliance_name.as_deref(),
+            ]);
+            if let Err(err) = res {
+                metrics::DB_INSERT_FAILURES
+                    .with_label_values(&["town_changed_owner"])
+                    .inc();
+                error!("Failed to insert owner change into DB: {err:?}");
+            }
+        }
+    }
+
+    /// link the conquests of the towns to their appearances, a failure only leaves them unlinked
+    fn link_towns(transaction: &rusqlite::Transaction, gss: &[OrmGS]) {
+        let res = link_conquests_of(transaction, gss.iter().filter_map(|gs| gs.town_id));
+        if let Err(err) = res {
+            error!("Failed to link the conquered ghost towns: {err:?}");
+        }
+    }
+
+    fn insert_gs(transaction: &rusqlite::Transaction, table: &str, gss: &[OrmGS]) {
+        if gss.is_empty() {
+            return;
+        }
+        let placeholders: Vec<_> = (1..=GS_COLUMNS.len()).map(|i| format!("?{i}")).collect();
+        let mut prepared_statement = transaction
+            .prepare(&format!(
+                "INSERT INTO {table} ({}) VALUES({})",
+                GS_COLUMNS.join(", "),
+                placeholders.join(", ")
+            ))
+            .expect("failed to prepare statement");
+        for gs in gss {
+            trace!("Inserting {gs:?} into DB.{table}");
+            let (before, after) = (gs.before.as_ref(), gs.after.as_ref());
+            let res = prepared_statement.execute(rusqlite::params![
+                gs.date,
+                gs.name.as_str(),
+                gs.points,
+                gs.x,
+                gs.y,
+                gs.player_name.as_deref(),
+                gs.alliance_name.as_deref(),
+                gs.town_id,
+                gs.player_id,
+                gs.alliance_id,
+                gs.observed_between.map(|w| w[0]),
+                before.map(|t| t.name.as_str()),
+                before.map(|t| t.points),
+                before.and_then(|t| t.player_id),
+                before.and_then(|t| t.player_name.as_deref()),
+                before.and_then(|t| t.alliance_id),
+                before.and_then(|t| t.alliance_name.as_deref()),
+                after.map(|t| t.name.as_str()),
+                after.map(|t| t.points),
+                after.and_then(|t| t.player_id),
+                after.and_then(|t| t.player_name.as_deref()),
+                after.and_then(|t| t.alliance_id),
+                after.and_then(|t| t.alliance_name.as_deref()),
+            ]);
+            if let Err(err) = res {
+                metrics::DB_INSERT_FAILURES
+                    .with_label_values(&[table])
+                    .inc();
+                error!("Failed to insert gs into DB: {err:?}");
+            }
+        }
+    }
+
+    fn insert_renames(transaction: &rusqlite::Transaction, table: &str, renames: &[OrmRename]) {
+        if renames.is_empty() {
+            return;
+        }
+        let mut prepared_statement = transaction
+            .prepare(&format!("INSERT INTO {table} VALUES(?1, ?2, ?3, ?4, ?5)"))
+            .expect("failed to prepare statement");
+        for rename in renames {
+            trace!("Inserting {rename:?} into DB.{table}");
+            let res = prepared_statement.execute((
+                rename.date,
+                rename.id,
+                rename.old_name.as_str(),
+                rename.new_name.as_str(),
+                rename.observed_between.map(|w| w[0]),
+            ));
+            if let Err(err) = res {
+                metrics::DB_INSERT_FAILURES
+                    .with_label_values(&[table])
+                    .inc();
+                error!("Failed to insert rename into DB: {err:?}");
+            }
+        }
     }
 
     fn send_update_to_webserver(&self) {
         let gs_conquered = self
             .conn
-            .prepare("SELECT * FROM gs_conquered ORDER BY date LIMIT 200")
+            .prepare("SELECT * FROM gs_conquered ORDER BY date DESC LIMIT 200")
             .expect("failed to prepare gs conquered extraction statement")
             .query([])
             .expect("Failed to query db for gs appeared")
@@ -176,7 +756,7 @@ impl DB {
             .expect("Failed to collect the rows from the DB");
         let gs_appeared = self
             .conn
-            .prepare("SELECT * FROM gs_appeared ORDER BY date LIMIT 200")
+            .prepare("SELECT * FROM gs_appeared ORDER BY date DESC LIMIT 200")
             .expect("failed to prepare gs appeared extraction statement")
             .query([])
             .expect("Failed to query db for gs appeared")
@@ -185,7 +765,10 @@ impl DB {
             .expect("Failed to collect the rows from the DB");
         let players_left = self
             .conn
-            .prepare("SELECT * FROM player_disappeared ORDER BY date LIMIT 200")
+            .prepare(&format!(
+                "SELECT {} FROM player_disappeared ORDER BY date DESC LIMIT 200",
+                PLAYER_COLUMNS.join(", ")
+            ))
             .expect("failed to prepare gs appeared extraction statement")
             .query([])
             .expect("Failed to query db for gs appeared")
@@ -193,7 +776,7 @@ impl DB {
             .collect::<Result<Vec<_>, rusqlite::Error>>()
             .expect("Failed to collect the rows from the DB");
 
-        let res = self.tx.send(MessageFromDBToWeb::NewData(CachedDBState {
+        let res = self.send_to_web(MessageFromDBToWeb::NewData(CachedDBState {
             gs_conquered,
             gs_appeared,
             players_left,
@@ -203,3 +786,78 @@ impl DB {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use rusqlite::Connection;
+
+    use super::queries::{players_disappeared_filtered, EventFilter};
+    use super::*;
+
+    fn at(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn departed_players_survive_an_upgrade_from_the_first_schema() {
+        let first = "CREATE TABLE player_disappeared (
+            date TEXT NOT NULL,
+            name TEXT NOT NULL,
+            towns INTEGER NOT NULL,
+            points INTEGER NOT NULL,
+            rank INTEGER NOT NULL,
+            alliance TEXT
+        );";
+        // schema version 12 added the ids before `observed_from` on upgraded databases
+        let version_12 = "ALTER TABLE player_disappeared ADD COLUMN player_id INTEGER;
+            ALTER TABLE player_disappeared ADD COLUMN alliance_id INTEGER;";
+        for layout in [first.to_string(), format!("{first}{version_12}")] {
+            let mut conn = Connection::open_in_memory().unwrap();
+            conn.execute_batch(&layout).unwrap();
+            conn.execute(
+                "INSERT INTO player_disappeared (date, name, towns, points, rank, alliance)
+                    VALUES(?1, 'Paris', 2, 3000, 20, 'Troy')",
+                [at("2024-03-01T12:00:00Z")],
+            )
+            .unwrap();
+            ensure_schema(&conn);
+
+            let player = OrmPlayer {
+                date: at("2024-03-02T12:00:00Z"),
+                observed_between: Some([at("2024-03-02T11:50:00Z"), at("2024-03-02T12:00:00Z")]),
+                player_id: Some(4),
+                alliance_id: Some(9),
+                name: String::from("Hector"),
+                towns: 3,
+                points: 4000,
+                rank: 12,
+                alliance: Some(String::from("Troy")),
+            };
+            insert_events(
+                &mut conn,
+                &[MessageFromModelToDB::PlayersDisappeared(vec![
+                    player.clone()
+                ])],
+            )
+            .unwrap();
+
+            let players = players_disappeared_filtered(&conn, &EventFilter::default()).unwrap();
+            assert_eq!(players.len(), 2);
+            assert_eq!(players[0], player);
+            assert_eq!(players[1].name, "Paris");
+            assert_eq!(
+                (players[1].player_id, players[1].observed_between),
+                (None, None)
+            );
+            let stored: Option<u32> = conn
+                .query_row(
+                    "SELECT player_id FROM player_disappeared WHERE name = 'Hector'",
+                    [],
+                    |r| r.get(0),
+                )
+                .unwrap();
+            assert_eq!(stored, Some(4));
+        }
+    }
+}
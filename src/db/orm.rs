@@ -2,50 +2,114 @@ use std::collections::HashMap;
 
 use chrono::{DateTime, Utc};
 use rusqlite::Row;
+use serde::Serialize;
 
-use crate::model::database::{Alliance, Player, Town};
+use crate::model::database::{Alliance, DataTable, Player, Town};
 
+/// The loading times of the two snapshots an event was observed between. The event happened at
+/// some point in this window, `date` is always its end.
+pub type Window = [DateTime<Utc>; 2];
+
+/// the window of a row, events recorded before the start was stored don't have one
+fn window(start: Option<DateTime<Utc>>, date: DateTime<Utc>) -> Option<Window> {
+    start.map(|start| [start, date])
+}
+
+/// a town with its owner as it was in one of the two snapshots of an event
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TownState {
+    pub name: String,
+    pub points: u16,
+    pub player_id: Option<u32>,
+    pub player_name: Option<String>,
+    pub alliance_id: Option<u32>,
+    pub alliance_name: Option<String>,
+}
+
+impl TownState {
+    pub fn of(dt: &DataTable, town: &Town) -> Self {
+        let player = town.player_id.and_then(|id| dt.players.get(&id));
+        let alliance = player
+            .and_then(|p| p.alliance_id)
+            .and_then(|id| dt.alliances.get(&id));
+        Self {
+            name: town.name.clone(),
+            points: town.points,
+            player_id: town.player_id,
+            player_name: player.map(|p| p.name.clone()),
+            alliance_id: alliance.map(|a| a.id),
+            alliance_name: alliance.map(|a| a.name.clone()),
+        }
+    }
+
+    /// read the six columns starting at `i`, None if the row is older than them
+    fn from_row(row: &Row, i: usize) -> Option<Self> {
+        let name: Option<String> = row.get(i).unwrap();
+        Some(Self {
+            name: name?,
+            points: row.get(i + 1).unwrap(),
+            player_id: row.get(i + 2).unwrap(),
+            player_name: row.get(i + 3).unwrap(),
+            alliance_id: row.get(i + 4).unwrap(),
+            alliance_name: row.get(i + 5).unwrap(),
+        })
+    }
+}
+
+/// A town that became a ghost town or a ghost town that was conquered. The top level fields
+/// describe the town in the snapshot that matters for the event: the old one for a new ghost town,
+/// so the owner is the player that left it, and the new one for a conquered ghost town, so the
+/// owner is the conqueror. `before` and `after` hold the town in both snapshots.
 #[allow(clippy::module_name_repetitions)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct OrmGS {
     pub date: DateTime<Utc>,
+    pub observed_between: Option<Window>,
     pub name: String,
     pub points: u16,
     pub x: f32,
     pub y: f32,
     pub player_name: Option<String>,
     pub alliance_name: Option<String>,
+    /// the ids are only known for events recorded since they are stored
+    pub town_id: Option<u32>,
+    pub player_id: Option<u32>,
+    pub alliance_id: Option<u32>,
+    /// the town in the old snapshot, None if it didn't exist or the event is older than this field
+    pub before: Option<TownState>,
+    /// the town in the new snapshot, None if it vanished or the event is older than this field
+    pub after: Option<TownState>,
 }
 
-impl
-    From<(
-        DateTime<Utc>,
-        &Town,
-        &HashMap<u32, Player>,
-        &HashMap<u32, Alliance>,
-    )> for OrmGS
-{
-    fn from(
-        (now, town, players, alliances): (
-            DateTime<Utc>,
-            &Town,
-            &HashMap<u32, Player>,
-            &HashMap<u32, Alliance>,
-        ),
-    ) -> Self {
-        let opt_player = town.player_id.and_then(|id| players.get(&id));
-        Self {
-            date: now,
-            name: town.name.clone(),
-            points: town.points,
+impl OrmGS {
+    /// the event for the town with the values of the old snapshot at the top level
+    pub fn as_before(old: &DataTable, new: &DataTable, town_id: u32) -> Option<Self> {
+        Self::observe(old, new, town_id, old)
+    }
+
+    /// the event for the town with the values of the new snapshot at the top level
+    pub fn as_after(old: &DataTable, new: &DataTable, town_id: u32) -> Option<Self> {
+        Self::observe(old, new, town_id, new)
+    }
+
+    fn observe(old: &DataTable, new: &DataTable, town_id: u32, main: &DataTable) -> Option<Self> {
+        let town = main.towns.get(&town_id)?;
+        let state = TownState::of(main, town);
+        Some(Self {
+            date: new.loaded,
+            observed_between: Some([old.loaded, new.loaded]),
+            name: state.name,
+            points: state.points,
             x: town.actual_x,
             y: town.actual_y,
-            player_name: opt_player.map(|p| p.name.clone()),
-            alliance_name: opt_player
-                .and_then(|p| p.alliance_id)
-                .and_then(|id| alliances.get(&id))
-                .map(|a| a.name.clone()),
-        }
+            player_name: state.player_name,
+            alliance_name: state.alliance_name,
+            town_id: Some(town.id),
+            player_id: state.player_id,
+            alliance_id: state.alliance_id,
+            before: old.towns.get(&town_id).map(|t| TownState::of(old, t)),
+            after: new.towns.get(&town_id).map(|t| TownState::of(new, t)),
+        })
     }
 }
 
@@ -53,22 +117,47 @@ impl<'a> TryFrom<&Row<'a>> for OrmGS {
     type Error = rusqlite::Error;
 
     fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let date = row.get(0).unwrap();
         Ok(Self {
-            date: row.get(0).unwrap(),
+            date,
             name: row.get(1).unwrap(),
             points: row.get(2).unwrap(),
             x: row.get(3).unwrap(),
             y: row.get(4).unwrap(),
             player_name: row.get(5).unwrap(),
             alliance_name: row.get(6).unwrap(),
+            town_id: row.get(7).unwrap(),
+            player_id: row.get(8).unwrap(),
+            alliance_id: row.get(9).unwrap(),
+            observed_between: window(row.get(10).unwrap(), date),
+            before: TownState::from_row(row, 11),
+            after: TownState::from_row(row, 17),
         })
     }
 }
 
+/// the columns of `player_disappeared` in the order `OrmPlayer` reads them. Databases that were
+/// upgraded have them in a different order, so always name them.
+pub const PLAYER_COLUMNS: [&str; 9] = [
+    "date",
+    "name",
+    "towns",
+    "points",
+    "rank",
+    "alliance",
+    "observed_from",
+    "player_id",
+    "alliance_id",
+];
+
 #[allow(clippy::module_name_repetitions)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct OrmPlayer {
     pub date: DateTime<Utc>,
+    pub observed_between: Option<Window>,
+    /// None for players recorded before the ids were stored, the same for the alliance
+    pub player_id: Option<u32>,
+    pub alliance_id: Option<u32>,
     pub name: String,
     pub towns: u16,
     pub points: u32,
@@ -76,10 +165,16 @@ pub struct OrmPlayer {
     pub alliance: Option<String>,
 }
 
-impl From<(DateTime<Utc>, &Player, &HashMap<u32, Alliance>)> for OrmPlayer {
-    fn from((now, player, alliances): (DateTime<Utc>, &Player, &HashMap<u32, Alliance>)) -> Self {
+/// the player as it was in the old snapshot
+impl From<(Window, &Player, &HashMap<u32, Alliance>)> for OrmPlayer {
+    fn from(
+        (observed_between, player, alliances): (Window, &Player, &HashMap<u32, Alliance>),
+    ) -> Self {
         Self {
-            date: now,
+            date: observed_between[1],
+            observed_between: Some(observed_between),
+            player_id: Some(player.id),
+            alliance_id: player.alliance_id,
             name: player.name.clone(),
             towns: player.towns,
             points: player.points,
@@ -96,8 +191,12 @@ impl<'a> TryFrom<&Row<'a>> for OrmPlayer {
     type Error = rusqlite::Error;
 
     fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let date = row.get(0).unwrap();
         Ok(Self {
-            date: row.get(0).unwrap(),
+            date,
+            observed_between: window(row.get(6).unwrap(), date),
+            player_id: row.get(7).unwrap(),
+            alliance_id: row.get(8).unwrap(),
             name: row.get(1).unwrap(),
             towns: row.get(2).unwrap(),
             points: row.get(3).unwrap(),
@@ -106,3 +205,132 @@ impl<'a> TryFrom<&Row<'a>> for OrmPlayer {
         })
     }
 }
+
+/// A name change of a player or an alliance. Both are stored in separate tables with the same
+/// layout, the id refers to the player or alliance respectively.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrmRename {
+    pub date: DateTime<Utc>,
+    pub observed_between: Option<Window>,
+    pub id: u32,
+    pub old_name: String,
+    pub new_name: String,
+}
+
+impl<'a> TryFrom<&Row<'a>> for OrmRename {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let date = row.get(0).unwrap();
+        Ok(Self {
+            date,
+            observed_between: window(row.get(4).unwrap(), date),
+            id: row.get(1).unwrap(),
+            old_name: row.get(2).unwrap(),
+            new_name: row.get(3).unwrap(),
+        })
+    }
+}
+
+/// A town that was taken from one player by another. Towns that become or stop being ghost towns
+/// are `OrmGS` instead.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrmOwnerChange {
+    pub date: DateTime<Utc>,
+    pub observed_between: Window,
+    pub town_id: u32,
+    pub x: f32,
+    pub y: f32,
+    /// the town with the player that lost it
+    pub before: TownState,
+    /// the town with the conqueror
+    pub after: TownState,
+}
+
+impl<'a> TryFrom<&Row<'a>> for OrmOwnerChange {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let date = row.get(0).unwrap();
+        Ok(Self {
+            date,
+            observed_between: [row.get(1).unwrap(), date],
+            town_id: row.get(2).unwrap(),
+            x: row.get(3).unwrap(),
+            y: row.get(4).unwrap(),
+            before: TownState::from_row(row, 5).expect("the name of the town is never null"),
+            after: TownState::from_row(row, 11).expect("the name of the town is never null"),
+        })
+    }
+}
+
+/// A player that left an alliance, joined an alliance or switched from one alliance to another.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrmAllianceChange {
+    pub date: DateTime<Utc>,
+    pub observed_between: Option<Window>,
+    pub player_id: u32,
+    pub player_name: String,
+    pub old_alliance_id: Option<u32>,
+    pub old_alliance_name: Option<String>,
+    pub new_alliance_id: Option<u32>,
+    pub new_alliance_name: Option<String>,
+}
+
+impl
+    From<(
+        Window,
+        &Player,
+        &HashMap<u32, Alliance>,
+        &Player,
+        &HashMap<u32, Alliance>,
+    )> for OrmAllianceChange
+{
+    fn from(
+        (observed_between, player_old, alliances_old, player_new, alliances_new): (
+            Window,
+            &Player,
+            &HashMap<u32, Alliance>,
+            &Player,
+            &HashMap<u32, Alliance>,
+        ),
+    ) -> Self {
+        Self {
+            date: observed_between[1],
+            observed_between: Some(observed_between),
+            player_id: player_new.id,
+            player_name: player_new.name.clone(),
+            old_alliance_id: player_old.alliance_id,
+            old_alliance_name: player_old
+                .alliance_id
+                .and_then(|id| alliances_old.get(&id))
+                .map(|a| a.name.clone()),
+            new_alliance_id: player_new.alliance_id,
+            new_alliance_name: player_new
+                .alliance_id
+                .and_then(|id| alliances_new.get(&id))
+                .map(|a| a.name.clone()),
+        }
+    }
+}
+
+impl<'a> TryFrom<&Row<'a>> for OrmAllianceChange {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let date = row.get(0).unwrap();
+        Ok(Self {
+            date,
+            observed_between: window(row.get(7).unwrap(), date),
+            player_id: row.get(1).unwrap(),
+            player_name: row.get(2).unwrap(),
+            old_alliance_id: row.get(3).unwrap(),
+            old_alliance_name: row.get(4).unwrap(),
+            new_alliance_id: row.get(5).unwrap(),
+            new_alliance_name: row.get(6).unwrap(),
+        })
+    }
+}
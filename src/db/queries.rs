@@ -0,0 +1,359 @@
+//! Read only queries against the database. These are run by the webserver on its own connection,
+//! see [`super::open_read_only`].
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params_from_iter, types::ValueRef, Connection, ToSql};
+
+use crate::model::region::Region;
+
+use super::orm::{OrmAllianceChange, OrmGS, OrmPlayer, OrmRename, PLAYER_COLUMNS};
+
+/// all renames of the player or alliance with the given id, oldest first.
+/// `table` is either `player_renamed` or `alliance_renamed`
+pub fn renames(conn: &Connection, table: &str, id: u32) -> rusqlite::Result<Vec<OrmRename>> {
+    conn.prepare(&format!(
+        "SELECT * FROM {table} WHERE id = ?1 ORDER BY date"
+    ))?
+    .query([id])?
+    .mapped(|r| OrmRename::try_from(r))
+    .collect()
+}
+
+/// all ghost town events from `table` (`gs_appeared` or `gs_conquered`) where `column`
+/// (`player` or `alliance`) matches one of the given names. Newest first.
+///
+/// The event tables only store the names at the time of the event, so the caller should pass
+/// all names the entity ever had.
+pub fn gs_involving(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    names: &[String],
+) -> rusqlite::Result<Vec<OrmGS>> {
+    let mut statement = conn.prepare(&format!("SELECT * FROM {table} WHERE {column} = ?1"))?;
+    let mut re = Vec::new();
+    for name in names {
+        let rows = statement
+            .query([name])?
+            .mapped(|r| OrmGS::try_from(r))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        re.extend(rows);
+    }
+    re.sort_by_key(|row| std::cmp::Reverse(row.date));
+    return Ok(re);
+}
+
+/// all players that left the game while being in an alliance with one of the given names.
+/// Newest first.
+pub fn players_disappeared_from_alliance(
+    conn: &Connection,
+    names: &[String],
+) -> rusqlite::Result<Vec<OrmPlayer>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT {} FROM player_disappeared WHERE alliance = ?1",
+        PLAYER_COLUMNS.join(", ")
+    ))?;
+    let mut re = Vec::new();
+    for name in names {
+        let rows = statement
+            .query([name])?
+            .mapped(|r| OrmPlayer::try_from(r))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        re.extend(rows);
+    }
+    re.sort_by_key(|row| std::cmp::Reverse(row.date));
+    return Ok(re);
+}
+
+/// all alliance changes of the given player. Newest first.
+pub fn alliance_changes_of_player(
+    conn: &Connection,
+    player_id: u32,
+) -> rusqlite::Result<Vec<OrmAllianceChange>> {
+    conn.prepare("SELECT * FROM player_changed_alliance WHERE player_id = ?1 ORDER BY date DESC")?
+        .query([player_id])?
+        .mapped(|r| OrmAllianceChange::try_from(r))
+        .collect()
+}
+
+/// all players that joined or left the given alliance. Newest first.
+pub fn alliance_changes_of_alliance(
+    conn: &Connection,
+    alliance_id: u32,
+) -> rusqlite::Result<Vec<OrmAllianceChange>> {
+    conn.prepare(
+        "SELECT * FROM player_changed_alliance
+            WHERE old_alliance_id = ?1 OR new_alliance_id = ?1
+            ORDER BY date DESC",
+    )?
+    .query([alliance_id])?
+    .mapped(|r| OrmAllianceChange::try_from(r))
+    .collect()
+}
+
+/// all ghost town events from `table` (`gs_appeared` or `gs_conquered`) recorded after `since`.
+/// Newest first.
+pub fn gs_since(
+    conn: &Connection,
+    table: &str,
+    since: DateTime<Utc>,
+) -> rusqlite::Result<Vec<OrmGS>> {
+    conn.prepare(&format!(
+        "SELECT * FROM {table} WHERE date >= ?1 ORDER BY date DESC"
+    ))?
+    .query([since])?
+    .mapped(|r| OrmGS::try_from(r))
+    .collect()
+}
+
+/// since when each town that became a ghost town has been one, by town id. Towns that were
+/// conquered again are included, check the snapshot for whether they are still ghost towns.
+pub fn ghost_towns_since(conn: &Connection) -> rusqlite::Result<HashMap<u32, DateTime<Utc>>> {
+    conn.prepare(
+        "SELECT town_id, MAX(date) FROM gs_appeared WHERE town_id IS NOT NULL GROUP BY town_id",
+    )?
+    .query([])?
+    .mapped(|r| Ok((r.get(0)?, r.get(1)?)))
+    .collect()
+}
+
+/// the date of the newest stored event or territory, i.e. when the newest recorded snapshot was
+/// loaded. `None` if nothing was recorded yet.
+pub fn latest_date(conn: &Connection) -> rusqlite::Result<Option<DateTime<Utc>>> {
+    let tables: Vec<String> = EVENT_TABLES
+        .iter()
+        .map(|t| t.name)
+        .chain(["alliance_territory"])
+        .map(|name| format!("SELECT MAX(date) AS date FROM {name}"))
+        .collect();
+    conn.query_row(
+        &format!("SELECT MAX(date) FROM ({})", tables.join(" UNION ALL ")),
+        [],
+        |r| r.get(0),
+    )
+}
+
+/// restrictions for listing events. Fields that are `None` do not restrict anything.
+#[derive(Debug, Default, Clone)]
+pub struct EventFilter {
+    /// events within this region. Ignored for events without a position.
+    pub region: Option<Region>,
+    /// events involving an alliance with this name
+    pub alliance: Option<String>,
+    /// events recorded at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// events recorded before this time
+    pub until: Option<DateTime<Utc>>,
+    /// at most this many events, the newest ones
+    pub limit: Option<usize>,
+}
+
+/// a table of events and the columns the filters of an `EventFilter` apply to
+pub struct EventTable {
+    pub name: &'static str,
+    /// whether the table has the columns `x` and `y`
+    has_position: bool,
+    /// the columns that contain alliance names
+    alliance_columns: &'static [&'static str],
+}
+
+/// every table that stores events. All of them have a `date` column.
+pub const EVENT_TABLES: [EventTable; 7] = [
+    EventTable {
+        name: "gs_appeared",
+        has_position: true,
+        alliance_columns: &["alliance"],
+    },
+    EventTable {
+        name: "gs_conquered",
+        has_position: true,
+        alliance_columns: &["alliance"],
+    },
+    EventTable {
+        name: "player_disappeared",
+        has_position: false,
+        alliance_columns: &["alliance"],
+    },
+    EventTable {
+        name: "player_renamed",
+        has_position: false,
+        alliance_columns: &[],
+    },
+    EventTable {
+        name: "alliance_renamed",
+        has_position: false,
+        alliance_columns: &["old_name", "new_name"],
+    },
+    EventTable {
+        name: "player_changed_alliance",
+        has_position: false,
+        alliance_columns: &["old_alliance", "new_alliance"],
+    },
+    EventTable {
+        name: "town_changed_owner",
+        has_position: true,
+        alliance_columns: &["before_alliance", "after_alliance"],
+    },
+];
+
+/// the event table with the given name
+pub fn event_table(name: &str) -> Option<&'static EventTable> {
+    EVENT_TABLES.iter().find(|t| t.name == name)
+}
+
+impl EventFilter {
+    /// the WHERE and LIMIT clauses for this filter together with their parameters. The region is
+    /// ignored for tables without a position, the alliance for tables without alliance names.
+    fn to_sql(&self, table: &EventTable) -> (String, Vec<Box<dyn ToSql>>) {
+        let has_position = table.has_position;
+        let mut conditions = Vec::new();
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        if let (Some(region), true) = (self.region, has_position) {
+            conditions.push("x >= ? AND x < ? AND y >= ? AND y < ?");
+            params.push(Box::new(region.x0));
+            params.push(Box::new(region.x1));
+            params.push(Box::new(region.y0));
+            params.push(Box::new(region.y1));
+        }
+        let alliance_condition;
+        if let (Some(alliance), false) = (&self.alliance, table.alliance_columns.is_empty()) {
+            alliance_condition = format!(
+                "({})",
+                table
+                    .alliance_columns
+                    .iter()
+                    .map(|column| format!("{column} = ?"))
+                    .collect::<Vec<_>>()
+                    .join(" OR ")
+            );
+            conditions.push(&alliance_condition);
+            for _ in table.alliance_columns {
+                params.push(Box::new(alliance.clone()));
+            }
+        }
+        if let Some(since) = self.since {
+            conditions.push("date >= ?");
+            params.push(Box::new(since));
+        }
+        if let Some(until) = self.until {
+            conditions.push("date < ?");
+            params.push(Box::new(until));
+        }
+
+        let mut sql = String::new();
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY date DESC");
+        if let Some(limit) = self.limit {
+            sql.push_str(" LIMIT ?");
+            params.push(Box::new(i64::try_from(limit).unwrap_or(i64::MAX)));
+        }
+        (sql, params)
+    }
+}
+
+/// ghost town events from `table` (`gs_appeared` or `gs_conquered`) that match the filter.
+/// Newest first.
+pub fn gs_filtered(
+    conn: &Connection,
+    table: &str,
+    filter: &EventFilter,
+) -> rusqlite::Result<Vec<OrmGS>> {
+    let (clauses, params) = filter.to_sql(event_table(table).expect("not an event table"));
+    conn.prepare(&format!("SELECT * FROM {table}{clauses}"))?
+        .query(params_from_iter(params))?
+        .mapped(|r| OrmGS::try_from(r))
+        .collect()
+}
+
+/// players that left the game and match the filter. Newest first.
+pub fn players_disappeared_filtered(
+    conn: &Connection,
+    filter: &EventFilter,
+) -> rusqlite::Result<Vec<OrmPlayer>> {
+    let (clauses, params) = filter.to_sql(event_table("player_disappeared").unwrap());
+    conn.prepare(&format!(
+        "SELECT {} FROM player_disappeared{clauses}",
+        PLAYER_COLUMNS.join(", ")
+    ))?
+    .query(params_from_iter(params))?
+    .mapped(|r| OrmPlayer::try_from(r))
+    .collect()
+}
+
+/// write every row of the event table that matches the filter as csv, newest first. The header
+/// row holds the column names. Returns the number of rows written.
+pub fn export_csv<W: std::io::Write>(
+    conn: &Connection,
+    table: &EventTable,
+    filter: &EventFilter,
+    out: W,
+) -> anyhow::Result<usize> {
+    let (clauses, params) = filter.to_sql(table);
+    let mut statement = conn.prepare(&format!("SELECT * FROM {}{clauses}", table.name))?;
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(statement.column_names())?;
+
+    let column_count = statement.column_count();
+    let mut rows = statement.query(params_from_iter(params))?;
+    let mut count = 0;
+    let mut record = Vec::with_capacity(column_count);
+    while let Some(row) = rows.next()? {
+        record.clear();
+        for i in 0..column_count {
+            record.push(match row.get_ref(i)? {
+                ValueRef::Null => String::new(),
+                ValueRef::Integer(n) => n.to_string(),
+                ValueRef::Real(n) => n.to_string(),
+                ValueRef::Text(text) | ValueRef::Blob(text) => {
+                    String::from_utf8_lossy(text).into_owned()
+                }
+            });
+        }
+        writer.write_record(&record)?;
+        count += 1;
+    }
+    writer.flush()?;
+    return Ok(count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn latest_date_covers_events_and_territory() {
+        let conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        assert_eq!(latest_date(&conn).unwrap(), None);
+
+        conn.execute(
+            "INSERT INTO gs_appeared (date, name, points, x, y, player, town_id) VALUES (?1, 't', 100, 500, 500, 'q', 1)",
+            [at("2024-05-02T00:00:00Z")],
+        )
+        .unwrap();
+        assert_eq!(
+            latest_date(&conn).unwrap(),
+            Some(at("2024-05-02T00:00:00Z"))
+        );
+
+        // snapshots without any events still count through their territory
+        conn.execute(
+            "INSERT INTO alliance_territory (date, ocean, alliance_id, alliance, towns, points, town_share, points_share, x, y) VALUES (?1, 55, 1, 'a', 1, 100, 1.0, 1.0, 550.0, 550.0)",
+            [at("2024-05-03T00:00:00Z")],
+        )
+        .unwrap();
+        assert_eq!(
+            latest_date(&conn).unwrap(),
+            Some(at("2024-05-03T00:00:00Z"))
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! The territory of the alliances is stored for every snapshot, so that it can be compared over
+//! time. See `model::territory` for how it is computed.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::model::territory::Territory;
+
+/// store the territories of the snapshot loaded at `date`, unless they are stored already. Returns
+/// the number of rows written. The check and the rows are one transaction, so a snapshot is
+/// either stored completely or not at all.
+pub fn insert_territory(
+    conn: &mut Connection,
+    date: DateTime<Utc>,
+    territories: &[Territory],
+) -> rusqlite::Result<usize> {
+    let transaction = conn.transaction()?;
+    let exists: bool = transaction.query_row(
+        "SELECT COUNT(*) > 0 FROM alliance_territory WHERE date = ?1",
+        [date],
+        |r| r.get(0),
+    )?;
+    if exists {
+        return Ok(0);
+    }
+    let mut statement = transaction.prepare(
+        "INSERT INTO alliance_territory VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    )?;
+    for t in territories {
+        statement.execute(rusqlite::params![
+            date,
+            t.ocean,
+            t.alliance_id,
+            t.alliance.as_str(),
+            t.towns,
+            t.points,
+            t.town_share,
+            t.points_share,
+            t.centroid.0,
+            t.centroid.1,
+        ])?;
+    }
+    drop(statement);
+    transaction.commit()?;
+    return Ok(territories.len());
+}
+
+/// the towns and points share of an alliance in an ocean at one point in time
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Share {
+    pub towns: u32,
+    pub points_share: f64,
+}
+
+/// how the territory of an alliance in an ocean changed, `before` or `after` is None if it had
+/// no towns there
+#[derive(Debug, Serialize)]
+pub struct TerritoryChange {
+    pub ocean: u16,
+    pub alliance_id: u32,
+    /// the latest name
+    pub alliance: String,
+    pub before: Option<Share>,
+    pub after: Option<Share>,
+}
+
+impl TerritoryChange {
+    /// the change of the points share, positive if the alliance grew
+    pub fn delta(&self) -> f64 {
+        self.after.map_or(0.0, |s| s.points_share) - self.before.map_or(0.0, |s| s.points_share)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TerritoryChanges {
+    /// the dates of the two stored snapshots that are compared, None if there are not two
+    pub between: Option<[DateTime<Utc>; 2]>,
+    /// the largest changes of the points share first
+    pub changes: Vec<TerritoryChange>,
+}
+
+fn shares_at(
+    conn: &Connection,
+    date: DateTime<Utc>,
+) -> rusqlite::Result<BTreeMap<(u16, u32), (String, Share)>> {
+    conn.prepare(
+        "SELECT ocean, alliance_id, alliance, towns, points_share
+            FROM alliance_territory WHERE date = ?1",
+    )?
+    .query([date])?
+    .mapped(|r| {
+        Ok((
+            (r.get(0)?, r.get(1)?),
+            (
+                r.get(2)?,
+                Share {
+                    towns: r.get(3)?,
+                    points_share: r.get(4)?,
+                },
+            ),
+        ))
+    })
+    .collect()
+}
+
+/// Compare the latest stored territory with the last one stored at or before `since`, or the
+/// earliest one if none is that old. Changes of the points share smaller than `min_delta` are
+/// left out.
+pub fn territory_changes(
+    conn: &Connection,
+    since: DateTime<Utc>,
+    min_delta: f64,
+) -> rusqlite::Result<TerritoryChanges> {
+    let latest: Option<DateTime<Utc>> =
+        conn.query_row("SELECT MAX(date) FROM alliance_territory", [], |r| r.get(0))?;
+    let earlier: Option<DateTime<Utc>> = conn.query_row(
+        "SELECT IFNULL(
+            (SELECT MAX(date) FROM alliance_territory WHERE date <= ?1),
+            (SELECT MIN(date) FROM alliance_territory)
+        )",
+        [since],
+        |r| r.get(0),
+    )?;
+    let (Some(latest), Some(earlier)) = (latest, earlier) else {
+        return Ok(TerritoryChanges {
+            between: None,
+            changes: Vec::new(),
+        });
+    };
+    if earlier >= latest {
+        return Ok(TerritoryChanges {
+            between: None,
+            changes: Vec::new(),
+        });
+    }
+
+    let mut before = shares_at(conn, earlier)?;
+    let mut changes = Vec::new();
+    for ((ocean, alliance_id), (alliance, after)) in shares_at(conn, latest)? {
+        let before = before.remove(&(ocean, alliance_id)).map(|(_, s)| s);
+        changes.push(TerritoryChange {
+            ocean,
+            alliance_id,
+            alliance,
+            before,
+            after: Some(after),
+        });
+    }
+    // the alliances that left an ocean
+    for ((ocean, alliance_id), (alliance, before)) in before {
+        changes.push(TerritoryChange {
+            ocean,
+            alliance_id,
+            alliance,
+            before: Some(before),
+            after: None,
+        });
+    }
+    changes.retain(|c| c.delta().abs() >= min_delta);
+    changes.sort_by(|a, b| b.delta().abs().total_cmp(&a.delta().abs()));
+    Ok(TerritoryChanges {
+        between: Some([earlier, latest]),
+        changes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    fn territory(ocean: u16, alliance_id: u32, towns: u32, points_share: f64) -> Territory {
+        Territory {
+            ocean,
+            alliance_id,
+            alliance: format!("Alliance {alliance_id}"),
+            towns,
+            points: towns * 1000,
+            town_share: points_share,
+            points_share,
+            centroid: (550.0, 550.0),
+        }
+    }
+
+    #[test]
+    fn changes_between_the_latest_and_an_earlier_snapshot() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        let first = [
+            territory(55, 1, 6, 0.6),
+            territory(55, 2, 4, 0.4),
+            territory(44, 1, 1, 1.0),
+        ];
+        let second = [
+            territory(55, 1, 4, 0.4),
+            territory(55, 2, 6, 0.6),
+            territory(44, 1, 1, 1.0),
+            territory(45, 3, 1, 1.0),
+        ];
+        assert_eq!(
+            insert_territory(&mut conn, at("2024-05-01T00:00:00Z"), &first).unwrap(),
+            3
+        );
+        // stored once per snapshot
+        assert_eq!(
+            insert_territory(&mut conn, at("2024-05-01T00:00:00Z"), &first).unwrap(),
+            0
+        );
+        insert_territory(&mut conn, at("2024-05-02T00:00:00Z"), &second).unwrap();
+
+        let changes = territory_changes(&conn, at("2024-05-01T12:00:00Z"), 0.01).unwrap();
+        assert_eq!(
+            changes.between,
+            Some([at("2024-05-01T00:00:00Z"), at("2024-05-02T00:00:00Z")])
+        );
+        let summary: Vec<_> = changes
+            .changes
+            .iter()
+            .map(|c| (c.ocean, c.alliance_id, c.before.is_some()))
+            .collect();
+        assert_eq!(summary, vec![(45, 3, false), (55, 1, true), (55, 2, true)]);
+        assert!((changes.changes[1].delta() + 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn failed_snapshots_are_stored_on_retry() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        let date = at("2024-05-01T00:00:00Z");
+        // NaN is stored as NULL, which the table refuses
+        let broken = [territory(55, 1, 6, 0.6), territory(55, 2, 4, f64::NAN)];
+        assert!(insert_territory(&mut conn, date, &broken).is_err());
+        let fixed = [territory(55, 1, 6, 0.6), territory(55, 2, 4, 0.4)];
+        assert_eq!(insert_territory(&mut conn, date, &fixed).unwrap(), 2);
+    }
+}
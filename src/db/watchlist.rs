@@ -0,0 +1,428 @@
+//! Named watchlists of players, alliances and regions. Every event that involves one of them is
+//! recorded in the feed of the watchlist, and posted to its webhook or mailed to its address
+//! through the webhook queue.
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, Row, Transaction};
+use serde::{Deserialize, Serialize};
+use tracing::{error, trace};
+
+use crate::{
+    messages::{Event, MessageFromModelToDB},
+    model::region::Region,
+    webhooks::subscription,
+};
+
+/// the prefix of the delivery urls that are mailed instead of posted
+pub const MAILTO: &str = "mailto:";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watchlist {
+    /// assigned by the database, ignored when creating a watchlist
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub player_ids: Vec<u32>,
+    #[serde(default)]
+    pub alliance_ids: Vec<u32>,
+    /// events within any of these regions
+    #[serde(default)]
+    pub regions: Vec<Region>,
+    /// post every event to this url, like a webhook subscription
+    pub webhook_url: Option<String>,
+    /// mail every event to this address
+    pub email: Option<String>,
+    /// the alliance the watchlist belongs to, only its accounts see it. Set from the account
+    /// that creates it, unless that is an admin.
+    #[serde(default)]
+    pub alliance_id: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatchlistEvent {
+    pub id: i64,
+    pub date: DateTime<Utc>,
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub summary: String,
+    pub data: serde_json::Value,
+}
+
+fn ids(text: &str) -> Vec<u32> {
+    text.split(',').filter_map(|id| id.parse().ok()).collect()
+}
+
+fn join(ids: &[u32]) -> String {
+    ids.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl<'a> TryFrom<&Row<'a>> for Watchlist {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let player_ids: String = row.get(2)?;
+        let alliance_ids: String = row.get(3)?;
+        let regions: String = row.get(4)?;
+        Ok(Self {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            player_ids: ids(&player_ids),
+            alliance_ids: ids(&alliance_ids),
+            regions: serde_json::from_str(&regions).unwrap_or_default(),
+            webhook_url: row.get(5)?,
+            email: row.get(6)?,
+            alliance_id: row.get(7)?,
+        })
+    }
+}
+
+impl Watchlist {
+    /// checks the name, entries and targets, returns a description of the problem if there is one.
+    /// Blocks while the host of the webhook url is resolved.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err(String::from("No name given"));
+        }
+        if self.player_ids.is_empty() && self.alliance_ids.is_empty() && self.regions.is_empty() {
+            return Err(String::from("No players, alliances or regions given"));
+        }
+        if let Some(url) = &self.webhook_url {
+            subscription::validate_url(url)?;
+        }
+        if let Some(email) = &self.email {
+            let valid = email.contains('@')
+                && !email.starts_with('@')
+                && !email.ends_with('@')
+                && !email.chars().any(|c| c.is_whitespace() || c == ',');
+            if !valid {
+                return Err(format!("Not an email address: {email}"));
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        event
+            .player_ids
+            .iter()
+            .any(|id| self.player_ids.contains(id))
+            || event
+                .alliance_ids
+                .iter()
+                .any(|id| self.alliance_ids.contains(id))
+            || event
+                .position
+                .is_some_and(|(x, y)| self.regions.iter().any(|r| r.contains(x, y)))
+    }
+}
+
+pub fn list(conn: &Connection) -> rusqlite::Result<Vec<Watchlist>> {
+    conn.prepare("SELECT * FROM watchlists ORDER BY id")?
+        .query([])?
+        .mapped(|r| Watchlist::try_from(r))
+        .collect()
+}
+
+pub fn get(conn: &Connection, id: i64) -> rusqlite::Result<Option<Watchlist>> {
+    let mut statement = conn.prepare("SELECT * FROM watchlists WHERE id = ?1")?;
+    let mut rows = statement.query([id])?;
+    rows.next()?.map(Watchlist::try_from).transpose()
+}
+
+/// store the watchlist and return its id
+pub fn insert(conn: &Connection, watchlist: &Watchlist) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO watchlists(name, player_ids, alliance_ids, regions, webhook_url, email, alliance_id)
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            watchlist.name.trim(),
+            join(&watchlist.player_ids),
+            join(&watchlist.alliance_ids),
+            serde_json::to_string(&watchlist.regions).unwrap_or_default(),
+            watchlist.webhook_url.as_deref(),
+            watchlist.email.as_deref(),
+            watchlist.alliance_id,
+        ),
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// replace the watchlist with the given id. Returns whether it existed.
+pub fn update(conn: &Connection, id: i64, watchlist: &Watchlist) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        "UPDATE watchlists SET name = ?2, player_ids = ?3, alliance_ids = ?4, regions = ?5,
+            webhook_url = ?6, email = ?7, alliance_id = ?8 WHERE id = ?1",
+        (
+            id,
+            watchlist.name.trim(),
+            join(&watchlist.player_ids),
+            join(&watchlist.alliance_ids),
+            serde_json::to_string(&watchlist.regions).unwrap_or_default(),
+            watchlist.webhook_url.as_deref(),
+            watchlist.email.as_deref(),
+            watchlist.alliance_id,
+        ),
+    )?;
+    Ok(updated > 0)
+}
+
+/// remove the watchlist, its feed and its pending deliveries. Returns whether it existed.
+pub fn delete(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.execute(
+        "DELETE FROM webhook_deliveries WHERE watchlist_id = ?1 AND delivered IS NULL",
+        [id],
+    )?;
+    conn.execute("DELETE FROM watchlist_events WHERE watchlist_id = ?1", [id])?;
+    let deleted = conn.execute("DELETE FROM watchlists WHERE id = ?1", [id])?;
+    Ok(deleted > 0)
+}
+
+/// the latest events of the watchlist, newest first
+pub fn events(conn: &Connection, id: i64, limit: usize) -> rusqlite::Result<Vec<WatchlistEvent>> {
+    conn.prepare(
+        "SELECT id, date, type, summary, data FROM watchlist_events
+            WHERE watchlist_id = ?1 ORDER BY id DESC LIMIT ?2",
+    )?
+    .query((id, limit))?
+    .mapped(|r| {
+        let data: String = r.get(4)?;
+        Ok(WatchlistEvent {
+            id: r.get(0)?,
+            date: r.get(1)?,
+            typ: r.get(2)?,
+            summary: r.get(3)?,
+            data: serde_json::from_str(&data).unwrap_or_default(),
+        })
+    })
+    .collect()
+}
+
+/// Add every event in the message to the feeds of the watchlists it matches and queue the
+/// deliveries to their webhooks and addresses. Returns the number of queued deliveries. The
+/// deliveries belong to no subscription, their `subscription_id` is 0.
+pub fn record(transaction: &Transaction, msg: &MessageFromModelToDB, now: DateTime<Utc>) -> usize {
+    let watchlists = match list(transaction) {
+        Ok(watchlists) => watchlists,
+        Err(err) => {
+            error!("Failed to load watchlists: {err:?}");
+            return 0;
+        }
+    };
+    if watchlists.is_empty() {
+        return 0;
+    }
+
+    let mut insert_event = transaction
+        .prepare(
+            "INSERT INTO watchlist_events(watchlist_id, date, type, summary, data)
+                VALUES(?1, ?2, ?3, ?4, ?5)",
+        )
+        .expect("failed to prepare statement");
+    let mut insert_delivery = transaction
+        .prepare(
+            "INSERT INTO webhook_deliveries(subscription_id, watchlist_id, url, payload, created, attempts, next_attempt)
+                VALUES(0, ?1, ?2, ?3, ?4, 0, ?4)",
+        )
+        .expect("failed to prepare statement");
+    let mut queued = 0;
+    for event in msg.events() {
+        let mut payload = None;
+        for watchlist in watchlists.iter().filter(|w| w.matches(&event)) {
+            trace!("Event for watchlist {}: {}", watchlist.id, event.summary);
+            let res = insert_event.execute((
+                watchlist.id,
+                now,
+                event.typ,
+                event.summary.as_str(),
+                event.data.to_string(),
+            ));
+            if let Err(err) = res {
+                error!("Failed to record watchlist event: {err:?}");
+            }
+
+            let payload = payload.get_or_insert_with(|| subscription::payload(&event).to_string());
+            let targets = watchlist
+                .webhook_url
+                .clone()
+                .into_iter()
+                .chain(watchlist.email.as_ref().map(|e| format!("{MAILTO}{e}")));
+            for url in targets {
+                match insert_delivery.execute((watchlist.id, url, payload.as_str(), now)) {
+                    Ok(_) => queued += 1,
+                    Err(err) => error!("Failed to queue watchlist delivery: {err:?}"),
+                }
+            }
+        }
+    }
+    return queued;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::orm::{OrmAllianceChange, OrmGS, OrmPlayer};
+
+    fn date() -> DateTime<Utc> {
+        "2024-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn watchlist(name: &str) -> Watchlist {
+        Watchlist {
+            id: 0,
+            name: name.to_string(),
+            player_ids: Vec::new(),
+            alliance_ids: Vec::new(),
+            regions: Vec::new(),
+            webhook_url: None,
+            email: None,
+            alliance_id: None,
+        }
+    }
+
+    fn gs(player_id: u32, alliance_id: u32, x: f32, y: f32) -> OrmGS {
+        OrmGS {
+            date: date(),
+            name: String::from("Troy"),
+            points: 8234,
+            x,
+            y,
+            player_name: Some(String::from("Achilles")),
+            alliance_name: Some(String::from("Myrmidons")),
+            town_id: Some(1),
+            player_id: Some(player_id),
+            alliance_id: Some(alliance_id),
+            observed_between: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(watchlist("empty").validate().is_err());
+        let mut w = watchlist("");
+        w.player_ids = vec![1];
+        assert!(w.validate().is_err());
+        w.name = String::from("enemies");
+        assert!(w.validate().is_ok());
+        w.email = Some(String::from("not an address"));
+        assert!(w.validate().is_err());
+        w.email = Some(String::from("lookout@example.com"));
+        w.webhook_url = Some(String::from("ftp://example.com"));
+        assert!(w.validate().is_err());
+        w.webhook_url = Some(String::from("http://192.168.1.10/hook"));
+        assert!(w.validate().is_err());
+        w.webhook_url = Some(String::from("https://93.184.216.34/hook"));
+        assert!(w.validate().is_ok());
+    }
+
+    #[test]
+    fn matching_events_reach_feed_and_queue() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+
+        let mut enemies = watchlist("enemy alliance");
+        enemies.alliance_ids = vec![30];
+        enemies.email = Some(String::from("lookout@example.com"));
+        let mut ocean = watchlist("ocean 55 plus border");
+        ocean.regions = vec![Region::from_corners(490.0, 490.0, 610.0, 610.0)];
+        ocean.webhook_url = Some(String::from("https://example.com/hook"));
+        let enemies_id = insert(&conn, &enemies).unwrap();
+        let ocean_id = insert(&conn, &ocean).unwrap();
+        assert_eq!(get(&conn, enemies_id).unwrap().unwrap().alliance_ids, [30]);
+
+        let msgs = [
+            // in the region and of the alliance, far away and of the alliance, neither
+            MessageFromModelToDB::GSAppeared(vec![
+                gs(1, 30, 512.0, 512.0),
+                gs(2, 30, 100.0, 100.0),
+                gs(3, 40, 100.0, 100.0),
+            ]),
+            // no position, matched by the alliance that was left
+            MessageFromModelToDB::PlayersChangedAlliance(vec![OrmAllianceChange {
+                date: date(),
+                observed_between: None,
+                player_id: 4,
+                player_name: String::from("Paris"),
+                old_alliance_id: Some(30),
+                old_alliance_name: Some(String::from("Trojans")),
+                new_alliance_id: None,
+                new_alliance_name: None,
+            }]),
+        ];
+        let transaction = conn.transaction().unwrap();
+        let queued: usize = msgs.iter().map(|m| record(&transaction, m, date())).sum();
+        transaction.commit().unwrap();
+        assert_eq!(queued, 4);
+
+        let enemy_feed = events(&conn, enemies_id, 10).unwrap();
+        let types: Vec<_> = enemy_feed.iter().map(|e| e.typ.as_str()).collect();
+        assert_eq!(
+            types,
+            ["player_changed_alliance", "gs_appeared", "gs_appeared"]
+        );
+        assert_eq!(events(&conn, ocean_id, 10).unwrap().len(), 1);
+        let mailed: u32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM webhook_deliveries WHERE url = 'mailto:lookout@example.com'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(mailed, 3);
+
+        assert!(delete(&conn, enemies_id).unwrap());
+        assert!(events(&conn, enemies_id, 10).unwrap().is_empty());
+        let pending: u32 = conn
+            .query_row("SELECT COUNT(*) FROM webhook_deliveries", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(pending, 1);
+    }
+
+    #[test]
+    fn departing_players() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::super::ensure_schema(&conn);
+        let mut hector = watchlist("hector");
+        hector.player_ids = vec![4];
+        let mut trojans = watchlist("trojans");
+        trojans.alliance_ids = vec![30];
+        let hector_id = insert(&conn, &hector).unwrap();
+        let trojans_id = insert(&conn, &trojans).unwrap();
+
+        let player = |id, alliance_id| OrmPlayer {
+            date: date(),
+            observed_between: None,
+            player_id: Some(id),
+            alliance_id,
+            name: format!("player {id}"),
+            towns: 3,
+            points: 12000,
+            rank: 17,
+            alliance: None,
+        };
+        let msg =
+            MessageFromModelToDB::PlayersDisappeared(vec![player(4, None), player(5, Some(30))]);
+        let transaction = conn.transaction().unwrap();
+        record(&transaction, &msg, date());
+        transaction.commit().unwrap();
+
+        let summaries = |id| -> Vec<String> {
+            events(&conn, id, 10)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.summary)
+                .collect()
+        };
+        let hector_feed = summaries(hector_id);
+        assert_eq!(hector_feed.len(), 1);
+        assert!(hector_feed[0].contains("player 4"));
+        let trojan_feed = summaries(trojans_id);
+        assert_eq!(trojan_feed.len(), 1);
+        assert!(trojan_feed[0].contains("player 5"));
+    }
+}
@@ -1,47 +1,446 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::needless_return)]
 
-use std::{panic, process, sync::mpsc, thread};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc,
+};
 
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
 use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
-    db::DB,
-    messages::{MessageFromDBToWeb, MessageFromModelToDB},
-    model::Model,
+    db::{
+        accounts::{self, Role},
+        queries::{self, EventFilter},
+        DB,
+    },
+    messages::{MessageFromDBToWeb, MessageFromDBToWebhooks, MessageFromModelToDB},
+    model::{database::DataTable, region::Region, snapshot, Model},
+    supervisor::{Supervisor, Worker},
     web::Web,
+    webhooks::Webhooks,
 };
 
 mod db;
 mod messages;
+mod metrics;
 mod model;
+mod status;
+mod supervisor;
 mod web;
+mod webhooks;
+
+#[derive(Parser)]
+#[command(about = "Tracks ghost towns and other changes in a grepolis world")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// fetch the world regularly, record the changes and serve them. The default.
+    Serve,
+    /// download the world once and save it as a snapshot
+    Fetch(FetchArgs),
+    /// print the events between two snapshots
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// print every event as a line of json
+        #[arg(long)]
+        json: bool,
+    },
+    /// record the events between the snapshots in a directory in the database, oldest first.
+    /// Snapshots that are not newer than the latest recorded one are skipped.
+    Import { dir: PathBuf },
+    /// write an event table as csv
+    Export(ExportArgs),
+    /// maintain the database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// ask the running server whether its data is fresh, exits with 1 if not. Used by docker.
+    Healthcheck,
+    /// manage the accounts that may see the private views, run in the directory of `db.sqlite`
+    Account {
+        #[command(subcommand)]
+        command: AccountCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum AccountCommand {
+    /// create an account, the password is read from the first line of stdin
+    Add {
+        name: String,
+        /// any of `read_only`, `member` and `admin`
+        #[arg(long, value_parser = role_name, default_value = "member")]
+        role: Role,
+        /// the alliance whose private data the account shares
+        #[arg(long)]
+        alliance_id: Option<u32>,
+    },
+    /// print a new API token for the account
+    Token {
+        name: String,
+        /// a note to recognize the token by later
+        #[arg(long, default_value = "cli")]
+        label: String,
+    },
+    /// list the accounts
+    List,
+    /// remove the account and its tokens
+    Remove { name: String },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// bring the schema of the database up to date
+    Migrate,
+    /// rebuild the database file to reclaim the space of deleted rows
+    Vacuum,
+}
+
+#[derive(Args)]
+struct FetchArgs {
+    #[arg(long, default_value = model::WORLD)]
+    world: String,
+    /// where to save the snapshot, by default the world and the time in the current directory
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// the name of the event table, i.e. gs-appeared or player-disappeared
+    #[arg(value_parser = event_table_name)]
+    table: String,
+    /// write to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// only events recorded at or after this time (RFC 3339)
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+    /// only events recorded before this time (RFC 3339)
+    #[arg(long)]
+    until: Option<DateTime<Utc>>,
+    /// only events involving the alliance with this name
+    #[arg(long)]
+    alliance: Option<String>,
+    /// only events in this ocean
+    #[arg(long)]
+    ocean: Option<u16>,
+    #[arg(long)]
+    x0: Option<f32>,
+    #[arg(long)]
+    y0: Option<f32>,
+    #[arg(long)]
+    x1: Option<f32>,
+    #[arg(long)]
+    y1: Option<f32>,
+}
 
 fn main() {
+    let cli = Cli::parse();
+
     // setup logging
     let filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::WARN.into())
         .from_env()
         .expect("failed to create logging filter")
         .add_directive("grepolis_diff_server=trace".parse().unwrap());
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .compact()
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(io::stderr)
+                .compact(),
+        )
+        .with(status::RecentErrors)
         .init();
 
+    let res = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => process::exit(serve()),
+        Command::Fetch(args) => fetch(&args).with_context(|| "Fetch failed"),
+        Command::Diff { old, new, json } => diff(&old, &new, json).with_context(|| "Diff failed"),
+        Command::Import { dir } => import(&dir).with_context(|| "Import failed"),
+        Command::Export(args) => export(&args).with_context(|| "Export failed"),
+        Command::Db {
+            command: DbCommand::Migrate,
+        } => migrate().with_context(|| "Migration failed"),
+        Command::Db {
+            command: DbCommand::Vacuum,
+        } => vacuum().with_context(|| "Vacuum failed"),
+        Command::Healthcheck => healthcheck().with_context(|| "Unhealthy"),
+        Command::Account { command } => account(command).with_context(|| "Account failed"),
+    };
+    if let Err(err) = res {
+        eprintln!("{err:?}");
+        process::exit(1);
+    }
+}
+
+fn fetch(args: &FetchArgs) -> anyhow::Result<()> {
+    let dt = DataTable::create_for_world(&args.world)?;
+    let path = args.output.clone().unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "{}_{}.bin",
+            args.world,
+            dt.loaded.format("%Y-%m-%dT%H%M%S")
+        ))
+    });
+    snapshot::save(&path, &dt)?;
+    eprintln!(
+        "Saved {} towns, {} players and {} alliances to {}",
+        dt.towns.len(),
+        dt.players.len(),
+        dt.alliances.len(),
+        path.display()
+    );
+    return Ok(());
+}
+
+fn diff(old: &Path, new: &Path, json: bool) -> anyhow::Result<()> {
+    let old = snapshot::load(old)?;
+    let new = snapshot::load(new)?;
+    eprintln!("Changes between {} and {}", old.loaded, new.loaded);
+    let mut out = io::stdout().lock();
+    for event in model::diff::diff(&old, &new) {
+        if json {
+            writeln!(out, "{}", serde_json::to_string(&event)?)?;
+        } else {
+            writeln!(out, "{}: {event}", event.kind())?;
+        }
+    }
+    return Ok(());
+}
+
+fn import(dir: &Path) -> anyhow::Result<()> {
+    // find out the order first, keeping all snapshots in memory at once could take a lot
+    let mut snapshots = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        match snapshot::load(&path) {
+            Ok(dt) => snapshots.push((dt.loaded, path)),
+            Err(err) => eprintln!("Skipping {}: {err:#}", path.display()),
+        }
+    }
+    snapshots.sort();
+
+    let mut conn = db::open_read_write().with_context(|| "Failed to open the database")?;
+    db::ensure_schema(&conn);
+    // the events of these are already stored, the newest one is still needed to diff against
+    let latest = db::queries::latest_date(&conn)
+        .with_context(|| "Failed to read the latest recorded date")?;
+    let stored = latest.map_or(0, |latest| {
+        snapshots.partition_point(|(loaded, _)| *loaded <= latest)
+    });
+    if let (Some(latest), true) = (latest, stored > 0) {
+        eprintln!("Skipping {stored} snapshots, the database is already up to {latest}");
+    }
+    let mut paths = snapshots
+        .into_iter()
+        .skip(stored.saturating_sub(1))
+        .map(|(_, path)| path);
+    let Some(first) = paths.next() else {
+        return Err(anyhow::anyhow!("No snapshots in {}", dir.display()));
+    };
+    let mut old = snapshot::load(&first)?;
+    if stored == 0 {
+        db::territory::insert_territory(
+            &mut conn,
+            old.loaded,
+            &model::territory::territories(&old),
+        )
+        .with_context(|| format!("Failed to store the territory of {}", first.display()))?;
+        db::activity::update_activity(&mut conn, &old)?;
+    }
+    let mut total = 0;
+    for path in paths {
+        let new = snapshot::load(&path)?;
+        let msgs = MessageFromModelToDB::from_events(model::diff::diff(&old, &new));
+        let count: usize = msgs
+            .iter()
+            .filter_map(MessageFromModelToDB::event_count)
+            .map(|(_, count)| count)
+            .sum();
+        db::insert_events(&mut conn, &msgs)
+            .with_context(|| format!("Failed to store the events up to {}", path.display()))?;
+        db::territory::insert_territory(
+            &mut conn,
+            new.loaded,
+            &model::territory::territories(&new),
+        )
+        .with_context(|| format!("Failed to store the territory of {}", path.display()))?;
+        db::activity::update_activity(&mut conn, &new)?;
+        eprintln!("{} to {}: {count} events", old.loaded, new.loaded);
+        total += count;
+        old = new;
+    }
+    eprintln!("Imported {total} events");
+    return Ok(());
+}
+
+fn migrate() -> anyhow::Result<()> {
+    let conn = db::open_read_write().with_context(|| "Failed to open the database")?;
+    let before = db::schema_version(&conn)?;
+    if before > db::SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "The database has schema version {before}, this build only knows up to {}",
+            db::SCHEMA_VERSION
+        ));
+    }
+    db::ensure_schema(&conn);
+    eprintln!(
+        "Migrated the schema from version {before} to {}",
+        db::SCHEMA_VERSION
+    );
+    return Ok(());
+}
+
+fn vacuum() -> anyhow::Result<()> {
+    let conn = db::open_read_write().with_context(|| "Failed to open the database")?;
+    let size = |conn: &rusqlite::Connection| -> rusqlite::Result<i64> {
+        let pages: i64 = conn.pragma_query_value(None, "page_count", |r| r.get(0))?;
+        let page_size: i64 = conn.pragma_query_value(None, "page_size", |r| r.get(0))?;
+        Ok(pages * page_size)
+    };
+    let before = size(&conn)?;
+    conn.execute_batch("VACUUM")?;
+    eprintln!(
+        "Shrunk the database from {before} to {} bytes",
+        size(&conn)?
+    );
+    return Ok(());
+}
+
+fn healthcheck() -> anyhow::Result<()> {
+    let response = reqwest::blocking::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?
+        .get("http://localhost:10204/healthz")
+        .send()?;
+    let status = response.status();
+    let text = response.text()?;
+    if !status.is_success() {
+        return Err(anyhow::anyhow!("{status}: {}", text.trim()));
+    }
+    print!("{text}");
+    return Ok(());
+}
+
+/// accept role names with dashes as well, i.e. `read-only`
+fn role_name(name: &str) -> Result<Role, String> {
+    Role::from_name(&name.replace('-', "_")).ok_or_else(|| {
+        let names: Vec<_> = Role::ALL.iter().map(|r| r.name()).collect();
+        format!("expected any of {}", names.join(", "))
+    })
+}
+
+fn account(command: AccountCommand) -> anyhow::Result<()> {
+    let conn = db::open_read_write().with_context(|| "Failed to open the database")?;
+    db::ensure_schema(&conn);
+    let find = |name: &str| -> anyhow::Result<accounts::Account> {
+        accounts::list(&conn)?
+            .into_iter()
+            .find(|a| a.name == name)
+            .ok_or_else(|| anyhow::anyhow!("No account named {name}"))
+    };
+    match command {
+        AccountCommand::Add {
+            name,
+            role,
+            alliance_id,
+        } => {
+            eprint!("Password: ");
+            let mut password = String::new();
+            io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                return Err(anyhow::anyhow!("The password must not be empty"));
+            }
+            let account = accounts::create(&conn, &name, Some(password), role, alliance_id)
+                .with_context(|| format!("Failed to create {name}, is the name taken?"))?;
+            eprintln!("Created {} as {}", account.name, account.role.name());
+        }
+        AccountCommand::Token { name, label } => {
+            let account = find(&name)?;
+            let token = accounts::create_token(&conn, account.id, &label, None, Utc::now())?;
+            println!("{token}");
+        }
+        AccountCommand::List => {
+            for account in accounts::list(&conn)? {
+                let alliance = account
+                    .alliance_id
+                    .map_or_else(String::new, |id| format!(" alliance {id}"));
+                println!("{} {}{alliance}", account.name, account.role.name());
+            }
+        }
+        AccountCommand::Remove { name } => {
+            let account = find(&name)?;
+            accounts::delete(&conn, account.id)?;
+            eprintln!("Removed {name}");
+        }
+    }
+    return Ok(());
+}
+
+/// accept table names with dashes as well, they are easier to type
+fn event_table_name(name: &str) -> Result<String, String> {
+    let name = name.replace('-', "_");
+    if queries::event_table(&name).is_none() {
+        let names: Vec<_> = queries::EVENT_TABLES.iter().map(|t| t.name).collect();
+        return Err(format!("expected any of {}", names.join(", ")));
+    }
+    Ok(name)
+}
+
+fn export(args: &ExportArgs) -> anyhow::Result<()> {
+    let table = queries::event_table(&args.table).expect("checked by clap");
+    let filter = EventFilter {
+        region: Some(Region::from_filter(
+            args.ocean, args.x0, args.y0, args.x1, args.y1,
+        )),
+        alliance: args.alliance.clone(),
+        since: args.since,
+        until: args.until,
+        limit: None,
+    };
+    let conn = db::open_read_only().with_context(|| "Failed to open the database")?;
+    let count = match &args.output {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            queries::export_csv(&conn, table, &filter, io::BufWriter::new(file))?
+        }
+        None => queries::export_csv(&conn, table, &filter, io::stdout().lock())?,
+    };
+    eprintln!("Exported {count} rows from {}", table.name);
+    return Ok(());
+}
+
+fn serve() -> i32 {
     // all threads communicate via message passing
     let (tx_model_to_db, rx_db_from_model) = mpsc::channel::<MessageFromModelToDB>();
     let (tx_db_to_web, rx_web_from_db) = mpsc::channel::<MessageFromDBToWeb>();
+    let (tx_db_to_webhooks, rx_webhooks_from_db) = mpsc::channel::<MessageFromDBToWebhooks>();
 
-    // from https://stackoverflow.com/a/36031130/14053391
-    // allow the db to crash the whole application if it encounters any issue
-    let orig_hook = panic::take_hook();
-    panic::set_hook(Box::new(move |panic_info| {
-        // invoke the default handler and exit the process
-        orig_hook(panic_info);
-        process::exit(1);
-    }));
+    // restarts the model if it crashes and shuts everything down otherwise
+    let mut supervisor = Supervisor::new();
 
     // thread 1:
     // fetches a new state regularly and writes to the database what changed
@@ -49,8 +448,8 @@ fn main() {
     //  - the diff is computed,
     //  - any changes are sent to the DB Thread
     //  - optional: the new state is saved to allow a comparion immediately after reboot
-    let handle_model = thread::spawn(move || {
-        Model::new(tx_model_to_db).start();
+    supervisor.spawn_model(move |rx_shutdown| {
+        Model::new(tx_model_to_db.clone(), rx_shutdown).start();
     });
 
     // thread 2:
@@ -58,18 +457,23 @@ fn main() {
     // it responds to requests from the webserver
     // and accepts updates from the backend.
     // persisted on disk
-    let handle_db = thread::spawn(move || {
-        DB::new(rx_db_from_model, tx_db_to_web).start();
+    supervisor.spawn(Worker::DB, move || {
+        DB::new(rx_db_from_model, tx_db_to_web, tx_db_to_webhooks).start();
     });
 
     // thread 3:
     // the webserver, handles request and reports back the data from the database.
     // keeps all required data locally. This data is updated by the DB, whenever the DB receives new data from the backend
-    let handle_web = thread::spawn(move || {
+    supervisor.spawn(Worker::Web, move || {
         Web::new(rx_web_from_db).start();
     });
 
-    let _res = handle_web.join();
-    let _res = handle_db.join();
-    let _res = handle_model.join();
+    // thread 4:
+    // posts the events to the webhooks that subscribed to them. The DB queues the deliveries,
+    // this thread works through the queue and retries failed deliveries.
+    supervisor.spawn(Worker::Webhooks, move || {
+        Webhooks::new(rx_webhooks_from_db).start();
+    });
+
+    supervisor.run()
 }
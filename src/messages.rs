@@ -1,16 +1,30 @@
 //! A file to collect the messages that are sent across the channels
 
 use core::fmt;
+use std::sync::Arc;
+
+use serde_json::json;
 
 use crate::{
-    db::orm::{OrmGS, OrmPlayer},
+    db::orm::{OrmAllianceChange, OrmGS, OrmOwnerChange, OrmPlayer, OrmRename},
+    model::{database::DataTable, diff::WorldEvent, region::ocean_at},
     web::CachedDBState,
 };
 
+/// sent by the supervisor to stop the model at the next opportunity. The other threads stop once
+/// the channels they read from are closed.
+pub struct Shutdown;
+
 pub enum MessageFromModelToDB {
     GSConquered(Vec<OrmGS>),
     GSAppeared(Vec<OrmGS>),
     PlayersDisappeared(Vec<OrmPlayer>),
+    PlayersRenamed(Vec<OrmRename>),
+    AlliancesRenamed(Vec<OrmRename>),
+    PlayersChangedAlliance(Vec<OrmAllianceChange>),
+    TownsChangedOwner(Vec<OrmOwnerChange>),
+    /// the latest snapshot of the world, the DB passes it on to the webserver
+    NewSnapshot(Arc<DataTable>),
 }
 
 impl fmt::Display for MessageFromModelToDB {
@@ -25,12 +39,302 @@ impl fmt::Display for MessageFromModelToDB {
             MessageFromModelToDB::PlayersDisappeared(list) => {
                 write!(f, "PlayerDisappeared(len={})", list.len())
             }
+            MessageFromModelToDB::PlayersRenamed(list) => {
+                write!(f, "PlayersRenamed(len={})", list.len())
+            }
+            MessageFromModelToDB::AlliancesRenamed(list) => {
+                write!(f, "AlliancesRenamed(len={})", list.len())
+            }
+            MessageFromModelToDB::PlayersChangedAlliance(list) => {
+                write!(f, "PlayersChangedAlliance(len={})", list.len())
+            }
+            MessageFromModelToDB::TownsChangedOwner(list) => {
+                write!(f, "TownsChangedOwner(len={})", list.len())
+            }
+            MessageFromModelToDB::NewSnapshot(dt) => {
+                write!(f, "NewSnapshot(loaded={})", dt.loaded)
+            }
+        }
+    }
+}
+
+/// a single event as it is announced to the outside world, i.e. by webhooks or the live stream
+pub struct Event {
+    /// the name of the table the event is stored in
+    pub typ: &'static str,
+    pub position: Option<(f32, f32)>,
+    pub points: Option<u32>,
+    /// the players and alliances involved, as far as their ids are known
+    pub player_ids: Vec<u32>,
+    pub alliance_ids: Vec<u32>,
+    /// a short human readable description
+    pub summary: String,
+    pub data: serde_json::Value,
+}
+
+/// format a number with thousands separators, i.e. 8234 as "8,234"
+pub fn thousands(n: u32) -> String {
+    let digits = n.to_string();
+    let mut re = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            re.push(',');
+        }
+        re.push(c);
+    }
+    return re;
+}
+
+fn owner(player: Option<&str>, alliance: Option<&str>) -> String {
+    match (player, alliance) {
+        (Some(player), Some(alliance)) => format!("{player} [{alliance}]"),
+        (Some(player), None) => player.to_string(),
+        (None, _) => String::from("an unknown player"),
+    }
+}
+
+pub fn gs_appeared_summary(gs: &OrmGS) -> String {
+    format!(
+        "New ghost town {} {} pts at {:.0}|{:.0} (ocean {}), was owned by {}",
+        gs.name,
+        thousands(u32::from(gs.points)),
+        gs.x,
+        gs.y,
+        ocean_at(gs.x, gs.y),
+        owner(gs.player_name.as_deref(), gs.alliance_name.as_deref()),
+    )
+}
+
+pub fn gs_conquered_summary(gs: &OrmGS) -> String {
+    format!(
+        "Ghost town {} {} pts at {:.0}|{:.0} (ocean {}) conquered by {}",
+        gs.name,
+        thousands(u32::from(gs.points)),
+        gs.x,
+        gs.y,
+        ocean_at(gs.x, gs.y),
+        owner(gs.player_name.as_deref(), gs.alliance_name.as_deref()),
+    )
+}
+
+pub fn player_disappeared_summary(p: &OrmPlayer) -> String {
+    format!(
+        "{} left the game with {} pts and {} towns",
+        owner(Some(&p.name), p.alliance.as_deref()),
+        thousands(p.points),
+        p.towns
+    )
+}
+
+/// `what` is either "Player" or "Alliance"
+pub fn rename_summary(what: &str, r: &OrmRename) -> String {
+    format!("{what} {} is now called {}", r.old_name, r.new_name)
+}
+
+pub fn alliance_change_summary(c: &OrmAllianceChange) -> String {
+    match (&c.old_alliance_name, &c.new_alliance_name) {
+        (Some(old), Some(new)) => format!("{} moved from {old} to {new}", c.player_name),
+        (None, Some(new)) => format!("{} joined {new}", c.player_name),
+        (Some(old), None) => format!("{} left {old}", c.player_name),
+        (None, None) => format!("{} changed alliance", c.player_name),
+    }
+}
+
+pub fn owner_change_summary(c: &OrmOwnerChange) -> String {
+    format!(
+        "Town {} {} pts at {:.0}|{:.0} (ocean {}) conquered by {} from {}",
+        c.after.name,
+        thousands(u32::from(c.after.points)),
+        c.x,
+        c.y,
+        ocean_at(c.x, c.y),
+        owner(
+            c.after.player_name.as_deref(),
+            c.after.alliance_name.as_deref()
+        ),
+        owner(
+            c.before.player_name.as_deref(),
+            c.before.alliance_name.as_deref()
+        ),
+    )
+}
+
+impl MessageFromModelToDB {
+    /// group the events the database stores by their table. The other events are only
+    /// interesting while comparing snapshots by hand and get dropped.
+    pub fn from_events(events: Vec<WorldEvent>) -> Vec<Self> {
+        let mut gs_appeared = Vec::new();
+        let mut gs_conquered = Vec::new();
+        let mut players_disappeared = Vec::new();
+        let mut players_renamed = Vec::new();
+        let mut alliances_renamed = Vec::new();
+        let mut players_changed_alliance = Vec::new();
+        let mut towns_changed_owner = Vec::new();
+        for event in events {
+            match event {
+                WorldEvent::GSAppeared(gs) => gs_appeared.push(gs),
+                WorldEvent::GSConquered(gs) => gs_conquered.push(gs),
+                WorldEvent::PlayerDisappeared(p) => players_disappeared.push(p),
+                WorldEvent::PlayerRenamed(r) => players_renamed.push(r),
+                WorldEvent::AllianceRenamed(r) => alliances_renamed.push(r),
+                WorldEvent::PlayerChangedAlliance(c) => players_changed_alliance.push(c),
+                WorldEvent::TownChangedOwner(c) => towns_changed_owner.push(c),
+                WorldEvent::PlayerAppeared(_)
+                | WorldEvent::AllianceFounded(_)
+                | WorldEvent::AllianceDisbanded(_)
+                | WorldEvent::TownFounded(_)
+                | WorldEvent::TownRenamed(_) => {}
+            }
+        }
+        [
+            (!gs_appeared.is_empty()).then_some(Self::GSAppeared(gs_appeared)),
+            (!gs_conquered.is_empty()).then_some(Self::GSConquered(gs_conquered)),
+            (!players_disappeared.is_empty())
+                .then_some(Self::PlayersDisappeared(players_disappeared)),
+            (!players_renamed.is_empty()).then_some(Self::PlayersRenamed(players_renamed)),
+            (!alliances_renamed.is_empty()).then_some(Self::AlliancesRenamed(alliances_renamed)),
+            (!players_changed_alliance.is_empty())
+                .then_some(Self::PlayersChangedAlliance(players_changed_alliance)),
+            (!towns_changed_owner.is_empty())
+                .then_some(Self::TownsChangedOwner(towns_changed_owner)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// the type of the events in this message and their number, snapshots contain no events
+    pub fn event_count(&self) -> Option<(&'static str, usize)> {
+        match self {
+            MessageFromModelToDB::GSAppeared(list) => Some(("gs_appeared", list.len())),
+            MessageFromModelToDB::GSConquered(list) => Some(("gs_conquered", list.len())),
+            MessageFromModelToDB::PlayersDisappeared(list) => {
+                Some(("player_disappeared", list.len()))
+            }
+            MessageFromModelToDB::PlayersRenamed(list) => Some(("player_renamed", list.len())),
+            MessageFromModelToDB::AlliancesRenamed(list) => Some(("alliance_renamed", list.len())),
+            MessageFromModelToDB::PlayersChangedAlliance(list) => {
+                Some(("player_changed_alliance", list.len()))
+            }
+            MessageFromModelToDB::TownsChangedOwner(list) => {
+                Some(("town_changed_owner", list.len()))
+            }
+            MessageFromModelToDB::NewSnapshot(_) => None,
+        }
+    }
+
+    /// the individual events contained in this message, snapshots contain none
+    #[allow(clippy::too_many_lines)]
+    pub fn events(&self) -> Vec<Event> {
+        match self {
+            MessageFromModelToDB::GSAppeared(gss) => gss
+                .iter()
+                .map(|gs| Event {
+                    typ: "gs_appeared",
+                    position: Some((gs.x, gs.y)),
+                    points: Some(u32::from(gs.points)),
+                    player_ids: gs.player_id.into_iter().collect(),
+                    alliance_ids: gs.alliance_id.into_iter().collect(),
+                    summary: gs_appeared_summary(gs),
+                    data: json!(gs),
+                })
+                .collect(),
+            MessageFromModelToDB::GSConquered(gss) => gss
+                .iter()
+                .map(|gs| Event {
+                    typ: "gs_conquered",
+                    position: Some((gs.x, gs.y)),
+                    points: Some(u32::from(gs.points)),
+                    player_ids: gs.player_id.into_iter().collect(),
+                    alliance_ids: gs.alliance_id.into_iter().collect(),
+                    summary: gs_conquered_summary(gs),
+                    data: json!(gs),
+                })
+                .collect(),
+            MessageFromModelToDB::PlayersDisappeared(players) => players
+                .iter()
+                .map(|p| Event {
+                    typ: "player_disappeared",
+                    position: None,
+                    points: Some(p.points),
+                    player_ids: p.player_id.into_iter().collect(),
+                    alliance_ids: p.alliance_id.into_iter().collect(),
+                    summary: player_disappeared_summary(p),
+                    data: json!(p),
+                })
+                .collect(),
+            MessageFromModelToDB::PlayersRenamed(renames) => renames
+                .iter()
+                .map(|r| Event {
+                    typ: "player_renamed",
+                    position: None,
+                    points: None,
+                    player_ids: vec![r.id],
+                    alliance_ids: Vec::new(),
+                    summary: rename_summary("Player", r),
+                    data: json!(r),
+                })
+                .collect(),
+            MessageFromModelToDB::AlliancesRenamed(renames) => renames
+                .iter()
+                .map(|r| Event {
+                    typ: "alliance_renamed",
+                    position: None,
+                    points: None,
+                    player_ids: Vec::new(),
+                    alliance_ids: vec![r.id],
+                    summary: rename_summary("Alliance", r),
+                    data: json!(r),
+                })
+                .collect(),
+            MessageFromModelToDB::PlayersChangedAlliance(changes) => changes
+                .iter()
+                .map(|c| Event {
+                    typ: "player_changed_alliance",
+                    position: None,
+                    points: None,
+                    player_ids: vec![c.player_id],
+                    alliance_ids: c
+                        .old_alliance_id
+                        .into_iter()
+                        .chain(c.new_alliance_id)
+                        .collect(),
+                    summary: alliance_change_summary(c),
+                    data: json!(c),
+                })
+                .collect(),
+            MessageFromModelToDB::TownsChangedOwner(changes) => changes
+                .iter()
+                .map(|c| Event {
+                    typ: "town_changed_owner",
+                    position: Some((c.x, c.y)),
+                    points: Some(u32::from(c.after.points)),
+                    player_ids: c
+                        .before
+                        .player_id
+                        .into_iter()
+                        .chain(c.after.player_id)
+                        .collect(),
+                    alliance_ids: c
+                        .before
+                        .alliance_id
+                        .into_iter()
+                        .chain(c.after.alliance_id)
+                        .collect(),
+                    summary: owner_change_summary(c),
+                    data: json!(c),
+                })
+                .collect(),
+            MessageFromModelToDB::NewSnapshot(_) => Vec::new(),
         }
     }
 }
 
 pub enum MessageFromDBToWeb {
     NewData(CachedDBState),
+    NewSnapshot(Arc<DataTable>),
+    /// events that have just been committed, for the live stream
+    EventsCommitted(MessageFromModelToDB),
 }
 impl fmt::Display for MessageFromDBToWeb {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -44,6 +348,22 @@ impl fmt::Display for MessageFromDBToWeb {
                     data.players_left.len()
                 )
             }
+            MessageFromDBToWeb::NewSnapshot(dt) => {
+                write!(f, "NewSnapshot(loaded={})", dt.loaded)
+            }
+            MessageFromDBToWeb::EventsCommitted(msg) => write!(f, "EventsCommitted({msg})"),
+        }
+    }
+}
+
+pub enum MessageFromDBToWebhooks {
+    /// the schema exists and the given number of deliveries has been queued
+    NewDeliveries(usize),
+}
+impl fmt::Display for MessageFromDBToWebhooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageFromDBToWebhooks::NewDeliveries(n) => write!(f, "NewDeliveries(len={n})"),
         }
     }
 }
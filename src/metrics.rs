@@ -0,0 +1,109 @@
+//! Prometheus metrics, shared by all threads. They are registered in the default registry and
+//! served by the webserver under `/metrics`.
+
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_gauge, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge_vec, Gauge, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+};
+
+pub static FETCH_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "gregswatch_fetch_duration_seconds",
+        "time to download a data file from the grepolis api",
+        &["file"],
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap()
+});
+
+pub static FETCH_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "gregswatch_fetch_failures_total",
+        "failed downloads of a data file from the grepolis api",
+        &["file"]
+    )
+    .unwrap()
+});
+
+pub static PARSE_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "gregswatch_parse_errors_total",
+        "data files that could not be parsed",
+        &["file"]
+    )
+    .unwrap()
+});
+
+pub static INVALID_REFERENCES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "gregswatch_invalid_references_total",
+        "fetched snapshots that were rejected because the data files did not match each other"
+    )
+    .unwrap()
+});
+
+pub static EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "gregswatch_events_total",
+        "events found by comparing snapshots",
+        &["type"]
+    )
+    .unwrap()
+});
+
+pub static EVENTS_LAST_CYCLE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "gregswatch_events_last_cycle",
+        "events found in the latest comparison of snapshots",
+        &["type"]
+    )
+    .unwrap()
+});
+
+pub static DB_INSERT_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "gregswatch_db_insert_failures_total",
+        "rows that could not be inserted into the database",
+        &["table"]
+    )
+    .unwrap()
+});
+
+pub static CHANNEL_BACKLOG: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "gregswatch_channel_backlog",
+        "messages sent between the threads that have not been handled yet",
+        &["channel"]
+    )
+    .unwrap()
+});
+
+pub static SNAPSHOT_AGE: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "gregswatch_snapshot_age_seconds",
+        "time since the snapshot the webserver shows was fetched"
+    )
+    .unwrap()
+});
+
+/// labels of `CHANNEL_BACKLOG`
+pub const MODEL_TO_DB: &str = "model_to_db";
+pub const DB_TO_WEB: &str = "db_to_web";
+pub const DB_TO_WEBHOOKS: &str = "db_to_webhooks";
+
+/// the file name at the end of an url, used as label
+pub fn file_label(url: &str) -> &str {
+    url.rsplit('/').next().unwrap_or(url)
+}
+
+/// a message was sent into the channel, call it only once `send` succeeded
+pub fn sent(channel: &str) {
+    CHANNEL_BACKLOG.with_label_values(&[channel]).inc();
+}
+
+/// a message was taken out of the channel
+pub fn received(channel: &str) {
+    CHANNEL_BACKLOG.with_label_values(&[channel]).dec();
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 use chrono::{DateTime, Utc};
 
@@ -78,12 +78,23 @@ pub struct DataTable {
     pub towns: HashMap<u32, Town>,
 }
 
-impl DataTable {
-    pub fn get_ghost_town_ids(&self) -> HashSet<u32> {
-        self.towns
-            .values()
-            .filter(|t| t.player_id.is_none())
-            .map(|t| t.id)
-            .collect()
+/// the offsets of the town slots are in pixels, a field of the world has this many
+pub const PIXELS_PER_FIELD: f32 = 125.0;
+
+impl Offset {
+    /// the position of the slot on the island at `x`|`y` in world coordinates
+    pub fn position(&self, x: u16, y: u16) -> (f32, f32) {
+        (
+            f32::from(x) + f32::from(self.x) / PIXELS_PER_FIELD,
+            f32::from(y) + f32::from(self.y) / PIXELS_PER_FIELD,
+        )
+    }
+}
+
+impl Town {
+    /// the ocean the town lies in. Oceans are 100x100 fields large and numbered by the hundreds
+    /// digit of x followed by the hundreds digit of y, i.e. 512|487 lies in ocean 54.
+    pub fn ocean(&self) -> u16 {
+        (self.island_xy.0 / 100) * 10 + self.island_xy.1 / 100
     }
 }
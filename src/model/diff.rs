@@ -0,0 +1,726 @@
+//! Everything that changed between two snapshots of a world, as a list of typed events. Shared by
+//! the model, which stores the events, and the command line, which prints them.
+
+use core::fmt;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    db::orm::{OrmAllianceChange, OrmGS, OrmOwnerChange, OrmPlayer, OrmRename, TownState, Window},
+    messages::{
+        alliance_change_summary, gs_appeared_summary, gs_conquered_summary, owner_change_summary,
+        player_disappeared_summary, rename_summary,
+    },
+    model::region::ocean_at,
+};
+
+use super::database::DataTable;
+
+/// an alliance or player that showed up or vanished
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Entity {
+    pub date: DateTime<Utc>,
+    pub observed_between: Window,
+    pub id: u32,
+    pub name: String,
+}
+
+/// A single change between two snapshots. Each carries the loading times of both snapshots as
+/// `observed_between` and is dated with the time the newer one was loaded. The first seven kinds are
+/// stored in the database, in the table their `kind` names.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum WorldEvent {
+    /// a town lost its owner, with the owner it had before
+    #[serde(rename = "gs_appeared")]
+    GSAppeared(OrmGS),
+    /// a ghost town got an owner, with the new owner
+    #[serde(rename = "gs_conquered")]
+    GSConquered(OrmGS),
+    PlayerDisappeared(OrmPlayer),
+    PlayerRenamed(OrmRename),
+    AllianceRenamed(OrmRename),
+    PlayerChangedAlliance(OrmAllianceChange),
+    /// a town went from one player to another, ghost towns are `GSAppeared` and `GSConquered`
+    TownChangedOwner(OrmOwnerChange),
+    PlayerAppeared(Entity),
+    AllianceFounded(Entity),
+    AllianceDisbanded(Entity),
+    /// a town that wasn't there before, with its owner if it has one
+    TownFounded(OrmGS),
+    TownRenamed(OrmRename),
+}
+
+impl WorldEvent {
+    /// the name of the kind of event, the same as the type in its json form
+    pub fn kind(&self) -> &'static str {
+        match self {
+            WorldEvent::GSAppeared(_) => "gs_appeared",
+            WorldEvent::GSConquered(_) => "gs_conquered",
+            WorldEvent::PlayerDisappeared(_) => "player_disappeared",
+            WorldEvent::PlayerRenamed(_) => "player_renamed",
+            WorldEvent::AllianceRenamed(_) => "alliance_renamed",
+            WorldEvent::PlayerChangedAlliance(_) => "player_changed_alliance",
+            WorldEvent::TownChangedOwner(_) => "town_changed_owner",
+            WorldEvent::PlayerAppeared(_) => "player_appeared",
+            WorldEvent::AllianceFounded(_) => "alliance_founded",
+            WorldEvent::AllianceDisbanded(_) => "alliance_disbanded",
+            WorldEvent::TownFounded(_) => "town_founded",
+            WorldEvent::TownRenamed(_) => "town_renamed",
+        }
+    }
+}
+
+impl fmt::Display for WorldEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorldEvent::GSAppeared(gs) => write!(f, "{}", gs_appeared_summary(gs)),
+            WorldEvent::GSConquered(gs) => write!(f, "{}", gs_conquered_summary(gs)),
+            WorldEvent::PlayerDisappeared(p) => write!(f, "{}", player_disappeared_summary(p)),
+            WorldEvent::PlayerRenamed(r) => write!(f, "{}", rename_summary("Player", r)),
+            WorldEvent::AllianceRenamed(r) => write!(f, "{}", rename_summary("Alliance", r)),
+            WorldEvent::PlayerChangedAlliance(c) => write!(f, "{}", alliance_change_summary(c)),
+            WorldEvent::TownChangedOwner(c) => write!(f, "{}", owner_change_summary(c)),
+            WorldEvent::PlayerAppeared(p) => write!(f, "New player {}", p.name),
+            WorldEvent::AllianceFounded(a) => write!(f, "Alliance {} was founded", a.name),
+            WorldEvent::AllianceDisbanded(a) => write!(f, "Alliance {} was disbanded", a.name),
+            WorldEvent::TownFounded(t) => write!(
+                f,
+                "New town {} at {:.0}|{:.0} (ocean {}) of {}",
+                t.name,
+                t.x,
+                t.y,
+                ocean_at(t.x, t.y),
+                t.player_name.as_deref().unwrap_or("nobody"),
+            ),
+            WorldEvent::TownRenamed(r) => write!(f, "{}", rename_summary("Town", r)),
+        }
+    }
+}
+
+/// the values of the map, ordered by their id
+fn by_id<T>(map: &HashMap<u32, T>) -> impl Iterator<Item = &T> {
+    let mut ids: Vec<_> = map.keys().copied().collect();
+    ids.sort_unstable();
+    ids.into_iter().map(|id| &map[&id])
+}
+
+/// Everything that changed from `old` to `new`: first the alliances, then the players, then the
+/// towns, each ordered by id. A town that is a ghost town as soon as it shows up and a ghost town
+/// that vanishes are not reported.
+pub fn diff(old: &DataTable, new: &DataTable) -> Vec<WorldEvent> {
+    let mut events = Vec::new();
+    diff_alliances(old, new, &mut events);
+    diff_players(old, new, &mut events);
+    diff_towns(old, new, &mut events);
+    return events;
+}
+
+fn diff_alliances(old: &DataTable, new: &DataTable, events: &mut Vec<WorldEvent>) {
+    let date = new.loaded;
+    let observed_between = [old.loaded, new.loaded];
+    for alliance in by_id(&new.alliances) {
+        match old.alliances.get(&alliance.id) {
+            None => events.push(WorldEvent::AllianceFounded(Entity {
+                date,
+                observed_between,
+                id: alliance.id,
+                name: alliance.name.clone(),
+            })),
+            Some(before) if before.name != alliance.name => {
+                events.push(WorldEvent::AllianceRenamed(OrmRename {
+                    date,
+                    observed_between: Some(observed_between),
+                    id: alliance.id,
+                    old_name: before.name.clone(),
+                    new_name: alliance.name.clone(),
+                }));
+            }
+            Some(_) => {}
+        }
+    }
+    for alliance in by_id(&old.alliances) {
+        if !new.alliances.contains_key(&alliance.id) {
+            events.push(WorldEvent::AllianceDisbanded(Entity {
+                date,
+                observed_between,
+                id: alliance.id,
+                name: alliance.name.clone(),
+            }));
+        }
+    }
+}
+
+fn diff_players(old: &DataTable, new: &DataTable, events: &mut Vec<WorldEvent>) {
+    let date = new.loaded;
+    let observed_between = [old.loaded, new.loaded];
+    for player in by_id(&new.players) {
+        let Some(before) = old.players.get(&player.id) else {
+            events.push(WorldEvent::PlayerAppeared(Entity {
+                date,
+                observed_between,
+                id: player.id,
+                name: player.name.clone(),
+            }));
+            continue;
+        };
+        if before.name != player.name {
+            events.push(WorldEvent::PlayerRenamed(OrmRename {
+                date,
+                observed_between: Some(observed_between),
+                id: player.id,
+                old_name: before.name.clone(),
+                new_name: player.name.clone(),
+            }));
+        }
+        if before.alliance_id != player.alliance_id {
+            events.push(WorldEvent::PlayerChangedAlliance(OrmAllianceChange::from(
+                (
+                    observed_between,
+                    before,
+                    &old.alliances,
+                    player,
+                    &new.alliances,
+                ),
+            )));
+        }
+    }
+    for player in by_id(&old.players) {
+        if !new.players.contains_key(&player.id) {
+            events.push(WorldEvent::PlayerDisappeared(OrmPlayer::from((
+                observed_between,
+                player,
+                &old.alliances,
+            ))));
+        }
+    }
+}
+
+fn diff_towns(old: &DataTable, new: &DataTable, events: &mut Vec<WorldEvent>) {
+    let date = new.loaded;
+    let observed_between = [old.loaded, new.loaded];
+    for town in by_id(&new.towns) {
+        let Some(before) = old.towns.get(&town.id) else {
+            if town.player_id.is_some() {
+                events.extend(OrmGS::as_after(old, new, town.id).map(WorldEvent::TownFounded));
+            }
+            continue;
+        };
+        if before.name != town.name {
+            events.push(WorldEvent::TownRenamed(OrmRename {
+                date,
+                observed_between: Some(observed_between),
+                id: town.id,
+                old_name: before.name.clone(),
+                new_name: town.name.clone(),
+            }));
+        }
+        match (before.player_id, town.player_id) {
+            // the ghost town as it was before, with the player that owned it
+            (Some(_), None) => {
+                events.extend(OrmGS::as_before(old, new, town.id).map(WorldEvent::GSAppeared));
+            }
+            // and the conquered one with the conqueror
+            (None, Some(_)) => {
+                events.extend(OrmGS::as_after(old, new, town.id).map(WorldEvent::GSConquered));
+            }
+            (Some(old_player_id), Some(new_player_id)) if old_player_id != new_player_id => {
+                events.push(WorldEvent::TownChangedOwner(OrmOwnerChange {
+                    date,
+                    observed_between,
+                    town_id: town.id,
+                    x: town.actual_x,
+                    y: town.actual_y,
+                    before: TownState::of(old, before),
+                    after: TownState::of(new, town),
+                }));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::{diff, Entity, WorldEvent};
+    use crate::{
+        db::orm::{
+            OrmAllianceChange, OrmGS, OrmOwnerChange, OrmPlayer, OrmRename, TownState, Window,
+        },
+        messages::MessageFromModelToDB,
+        model::{
+            database::{Alliance, Player, Town},
+            fixtures::{alliance, player, town, world},
+        },
+    };
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, hour, 0, 0).unwrap()
+    }
+
+    /// the window of the snapshots at hour 1 and 2 that all tests compare
+    fn window() -> Window {
+        [at(1), at(2)]
+    }
+
+    fn state(town: &Town, owner: Option<(&Player, Option<&Alliance>)>) -> TownState {
+        TownState {
+            name: town.name.clone(),
+            points: town.points,
+            player_id: owner.map(|(p, _)| p.id),
+            player_name: owner.map(|(p, _)| p.name.clone()),
+            alliance_id: owner.and_then(|(_, a)| a).map(|a| a.id),
+            alliance_name: owner.and_then(|(_, a)| a).map(|a| a.name.clone()),
+        }
+    }
+
+    /// the event of the town with `main` at the top level
+    fn gs(
+        town: &Town,
+        main: &TownState,
+        before: Option<TownState>,
+        after: Option<TownState>,
+    ) -> OrmGS {
+        OrmGS {
+            date: at(2),
+            observed_between: Some(window()),
+            name: main.name.clone(),
+            points: main.points,
+            x: town.actual_x,
+            y: town.actual_y,
+            player_name: main.player_name.clone(),
+            alliance_name: main.alliance_name.clone(),
+            town_id: Some(town.id),
+            player_id: main.player_id,
+            alliance_id: main.alliance_id,
+            before,
+            after,
+        }
+    }
+
+    #[test]
+    fn identical_snapshots_have_no_events() {
+        let sparta = alliance(1, "Sparta");
+        let achilles = player(7, "Achilles", Some(1));
+        let old = world(
+            at(1),
+            vec![sparta.clone()],
+            vec![achilles.clone()],
+            vec![town(100, "Troy", Some(7)), town(101, "Ruins", None)],
+        );
+        let new = world(
+            at(2),
+            vec![sparta],
+            vec![achilles],
+            vec![town(100, "Troy", Some(7)), town(101, "Ruins", None)],
+        );
+        assert_eq!(diff(&old, &new), Vec::new());
+    }
+
+    #[test]
+    fn changed_stats_are_no_events() {
+        let mut sparta = alliance(1, "Sparta");
+        let mut achilles = player(7, "Achilles", Some(1));
+        let mut troy = town(100, "Troy", Some(7));
+        let old = world(
+            at(1),
+            vec![sparta.clone()],
+            vec![achilles.clone()],
+            vec![troy.clone()],
+        );
+        sparta.points += 1000;
+        sparta.rank = 2;
+        achilles.points += 500;
+        achilles.towns += 1;
+        troy.points += 200;
+        let new = world(at(2), vec![sparta], vec![achilles], vec![troy]);
+        assert_eq!(diff(&old, &new), Vec::new());
+    }
+
+    #[test]
+    fn everything_is_new_in_an_empty_world() {
+        let old = world(at(1), vec![], vec![], vec![]);
+        let sparta = alliance(1, "Sparta");
+        let achilles = player(7, "Achilles", Some(1));
+        let troy = town(100, "Troy", Some(7));
+        let troy_state = state(&troy, Some((&achilles, Some(&sparta))));
+        let new = world(
+            at(2),
+            vec![sparta.clone()],
+            vec![achilles.clone()],
+            vec![troy.clone(), town(101, "Ruins", None)],
+        );
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                WorldEvent::AllianceFounded(Entity {
+                    date: at(2),
+                    observed_between: window(),
+                    id: 1,
+                    name: String::from("Sparta"),
+                }),
+                WorldEvent::PlayerAppeared(Entity {
+                    date: at(2),
+                    observed_between: window(),
+                    id: 7,
+                    name: String::from("Achilles"),
+                }),
+                WorldEvent::TownFounded(gs(&troy, &troy_state, None, Some(troy_state.clone()))),
+            ]
+        );
+    }
+
+    #[test]
+    fn everything_is_gone_in_an_empty_world() {
+        let sparta = alliance(1, "Sparta");
+        let achilles = player(7, "Achilles", Some(1));
+        let old = world(
+            at(1),
+            vec![sparta],
+            vec![achilles],
+            vec![town(100, "Troy", Some(7)), town(101, "Ruins", None)],
+        );
+        let new = world(at(2), vec![], vec![], vec![]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                WorldEvent::AllianceDisbanded(Entity {
+                    date: at(2),
+                    observed_between: window(),
+                    id: 1,
+                    name: String::from("Sparta"),
+                }),
+                WorldEvent::PlayerDisappeared(OrmPlayer {
+                    date: at(2),
+                    observed_between: Some(window()),
+                    player_id: Some(7),
+                    alliance_id: Some(1),
+                    name: String::from("Achilles"),
+                    towns: 2,
+                    points: 5000,
+                    rank: 3,
+                    alliance: Some(String::from("Sparta")),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn ghost_town_of_a_departed_player() {
+        let sparta = alliance(1, "Sparta");
+        let achilles = player(7, "Achilles", Some(1));
+        let troy = town(100, "Troy", Some(7));
+        let old = world(
+            at(1),
+            vec![sparta.clone()],
+            vec![achilles.clone()],
+            vec![troy.clone()],
+        );
+        // ghost towns lose some points
+        let ghost = Town {
+            points: 1200,
+            ..town(100, "Troy", None)
+        };
+        let new = world(at(2), vec![sparta.clone()], vec![], vec![ghost.clone()]);
+        let before = state(&troy, Some((&achilles, Some(&sparta))));
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                WorldEvent::PlayerDisappeared(OrmPlayer {
+                    date: at(2),
+                    observed_between: Some(window()),
+                    player_id: Some(7),
+                    alliance_id: Some(1),
+                    name: String::from("Achilles"),
+                    towns: 2,
+                    points: 5000,
+                    rank: 3,
+                    alliance: Some(String::from("Sparta")),
+                }),
+                // with the owner from before, the new snapshot doesn't know them anymore
+                WorldEvent::GSAppeared(gs(
+                    &troy,
+                    &before,
+                    Some(before.clone()),
+                    Some(state(&ghost, None))
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn ghost_town_of_a_remaining_player() {
+        let achilles = player(7, "Achilles", None);
+        let troy = town(100, "Troy", Some(7));
+        let old = world(at(1), vec![], vec![achilles.clone()], vec![troy.clone()]);
+        let new = world(
+            at(2),
+            vec![],
+            vec![achilles.clone()],
+            vec![town(100, "Troy", None)],
+        );
+        assert_eq!(
+            diff(&old, &new),
+            vec![WorldEvent::GSAppeared(gs(
+                &troy,
+                &state(&troy, Some((&achilles, None))),
+                Some(state(&troy, Some((&achilles, None)))),
+                Some(state(&troy, None)),
+            ))]
+        );
+    }
+
+    #[test]
+    fn ghost_town_conquered() {
+        let sparta = alliance(1, "Sparta");
+        let achilles = player(7, "Achilles", Some(1));
+        let old = world(
+            at(1),
+            vec![sparta.clone()],
+            vec![achilles.clone()],
+            vec![town(101, "Ruins", None)],
+        );
+        let ruins = town(101, "Ruins", Some(7));
+        let new = world(
+            at(2),
+            vec![sparta.clone()],
+            vec![achilles.clone()],
+            vec![ruins.clone()],
+        );
+        assert_eq!(
+            diff(&old, &new),
+            vec![WorldEvent::GSConquered(gs(
+                &ruins,
+                &state(&ruins, Some((&achilles, Some(&sparta)))),
+                Some(state(&ruins, None)),
+                Some(state(&ruins, Some((&achilles, Some(&sparta))))),
+            ))]
+        );
+    }
+
+    #[test]
+    fn ghost_towns_that_appear_or_vanish_are_ignored() {
+        let old = world(at(1), vec![], vec![], vec![town(101, "Ruins", None)]);
+        let new = world(at(2), vec![], vec![], vec![town(102, "Rubble", None)]);
+        assert_eq!(diff(&old, &new), Vec::new());
+    }
+
+    #[test]
+    fn town_taken_by_another_player() {
+        let troy = town(100, "Troy", None);
+        let achilles = player(7, "Achilles", None);
+        let hector = player(8, "Hector", None);
+        let old = world(
+            at(1),
+            vec![],
+            vec![achilles.clone(), hector.clone()],
+            vec![town(100, "Troy", Some(8))],
+        );
+        let new = world(
+            at(2),
+            vec![],
+            vec![achilles.clone(), hector.clone()],
+            vec![town(100, "Troy", Some(7))],
+        );
+        assert_eq!(
+            diff(&old, &new),
+            vec![WorldEvent::TownChangedOwner(OrmOwnerChange {
+                date: at(2),
+                observed_between: window(),
+                town_id: 100,
+                x: 512.5,
+                y: 487.25,
+                before: state(&troy, Some((&hector, None))),
+                after: state(&troy, Some((&achilles, None))),
+            })]
+        );
+    }
+
+    #[test]
+    fn renames() {
+        let old = world(
+            at(1),
+            vec![alliance(1, "Sparta")],
+            vec![player(7, "Achilles", Some(1))],
+            vec![town(100, "Troy", Some(7))],
+        );
+        let new = world(
+            at(2),
+            vec![alliance(1, "Athens")],
+            vec![player(7, "Odysseus", Some(1))],
+            vec![town(100, "Ilion", Some(7))],
+        );
+        let rename = |id, old_name: &str, new_name: &str| OrmRename {
+            date: at(2),
+            observed_between: Some(window()),
+            id,
+            old_name: old_name.to_string(),
+            new_name: new_name.to_string(),
+        };
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                WorldEvent::AllianceRenamed(rename(1, "Sparta", "Athens")),
+                WorldEvent::PlayerRenamed(rename(7, "Achilles", "Odysseus")),
+                WorldEvent::TownRenamed(rename(100, "Troy", "Ilion")),
+            ]
+        );
+    }
+
+    #[test]
+    fn alliance_changes() {
+        let sparta = alliance(1, "Sparta");
+        let athens = alliance(2, "Athens");
+        let old = world(
+            at(1),
+            vec![sparta.clone(), athens.clone()],
+            vec![
+                player(7, "Achilles", None),
+                player(8, "Hector", Some(1)),
+                player(9, "Ajax", Some(1)),
+            ],
+            vec![],
+        );
+        let new = world(
+            at(2),
+            vec![sparta, athens],
+            vec![
+                player(7, "Achilles", Some(2)),
+                player(8, "Hector", None),
+                player(9, "Ajax", Some(2)),
+            ],
+            vec![],
+        );
+        let change = |id, name: &str, old: Option<(u32, &str)>, new: Option<(u32, &str)>| {
+            WorldEvent::PlayerChangedAlliance(OrmAllianceChange {
+                date: at(2),
+                observed_between: Some(window()),
+                player_id: id,
+                player_name: name.to_string(),
+                old_alliance_id: old.map(|a| a.0),
+                old_alliance_name: old.map(|a| a.1.to_string()),
+                new_alliance_id: new.map(|a| a.0),
+                new_alliance_name: new.map(|a| a.1.to_string()),
+            })
+        };
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                change(7, "Achilles", None, Some((2, "Athens"))),
+                change(8, "Hector", Some((1, "Sparta")), None),
+                change(9, "Ajax", Some((1, "Sparta")), Some((2, "Athens"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn members_leave_a_disbanded_alliance() {
+        let old = world(
+            at(1),
+            vec![alliance(1, "Sparta")],
+            vec![player(7, "Achilles", Some(1))],
+            vec![],
+        );
+        let new = world(at(2), vec![], vec![player(7, "Achilles", None)], vec![]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                WorldEvent::AllianceDisbanded(Entity {
+                    date: at(2),
+                    observed_between: window(),
+                    id: 1,
+                    name: String::from("Sparta"),
+                }),
+                WorldEvent::PlayerChangedAlliance(OrmAllianceChange {
+                    date: at(2),
+                    observed_between: Some(window()),
+                    player_id: 7,
+                    player_name: String::from("Achilles"),
+                    old_alliance_id: Some(1),
+                    old_alliance_name: Some(String::from("Sparta")),
+                    new_alliance_id: None,
+                    new_alliance_name: None,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_are_ordered_by_id() {
+        let ids = [5, 3, 9, 1, 7, 2, 8];
+        let old = world(
+            at(1),
+            vec![],
+            vec![],
+            ids.map(|id| town(id, &format!("Town {id}"), Some(1)))
+                .to_vec(),
+        );
+        let new = world(
+            at(2),
+            vec![],
+            vec![],
+            ids.map(|id| town(id, &format!("Town {id}"), None)).to_vec(),
+        );
+        let events: Vec<_> = diff(&old, &new)
+            .into_iter()
+            .map(|e| match e {
+                WorldEvent::GSAppeared(gs) => gs.town_id.unwrap(),
+                _ => panic!("unexpected event {e:?}"),
+            })
+            .collect();
+        assert_eq!(events, vec![1, 2, 3, 5, 7, 8, 9]);
+    }
+
+    #[test]
+    fn only_stored_events_are_sent_to_the_database() {
+        let old = world(
+            at(1),
+            vec![alliance(1, "Sparta")],
+            vec![player(7, "Achilles", Some(1)), player(8, "Hector", None)],
+            vec![town(100, "Troy", Some(7)), town(101, "Ruins", None)],
+        );
+        let new = world(
+            at(2),
+            vec![alliance(2, "Athens")],
+            vec![player(8, "Paris", None), player(9, "Ajax", None)],
+            vec![
+                town(100, "Troy", None),
+                town(101, "Ruins", Some(8)),
+                town(102, "Sparta", Some(9)),
+            ],
+        );
+        let counts: Vec<_> = MessageFromModelToDB::from_events(diff(&old, &new))
+            .iter()
+            .filter_map(MessageFromModelToDB::event_count)
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("gs_appeared", 1),
+                ("gs_conquered", 1),
+                ("player_disappeared", 1),
+                ("player_renamed", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_uses_the_kind_as_type() {
+        let troy = town(100, "Troy", None);
+        let event = WorldEvent::GSAppeared(gs(&troy, &state(&troy, None), None, None));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], event.kind());
+        assert_eq!(json["data"]["name"], "Troy");
+        let event = WorldEvent::PlayerAppeared(Entity {
+            date: at(2),
+            observed_between: window(),
+            id: 7,
+            name: String::from("Achilles"),
+        });
+        assert_eq!(serde_json::to_value(&event).unwrap()["type"], event.kind());
+    }
+}
@@ -1,5 +1,6 @@
 use super::database::{Alliance, DataTable, Island, Offset, Player, Town};
 use super::offset_data;
+use crate::metrics;
 use anyhow::{anyhow, Context};
 use chrono::Utc;
 
@@ -9,11 +10,21 @@ use tracing::info;
 
 fn download_generic(
     client: &reqwest::blocking::Client,
-    url: String,
+    url: &str,
 ) -> std::result::Result<String, reqwest::Error> {
-    let result = client.get(url.clone()).send()?;
-    info!("Got status {} for url {url}", result.status());
-    let text = result.text()?;
+    let file = metrics::file_label(url);
+    let timer = metrics::FETCH_DURATION
+        .with_label_values(&[file])
+        .start_timer();
+    let res = client.get(url).send().and_then(|result| {
+        info!("Got status {} for url {url}", result.status());
+        result.text()
+    });
+    timer.observe_duration();
+    if res.is_err() {
+        metrics::FETCH_FAILURES.with_label_values(&[file]).inc();
+    }
+    let text = res?;
 
     // // for debugging
     // let now = chrono::Utc::now();
@@ -47,7 +58,7 @@ impl DataTable {
         let handle_data_players = std::thread::spawn(move || {
             download_generic(
                 &thread_client,
-                format!("https://{thread_server_id}.grepolis.com/data/players.txt"),
+                &format!("https://{thread_server_id}.grepolis.com/data/players.txt"),
             )
         });
         let thread_client = reqwest_client.clone();
@@ -55,7 +66,7 @@ impl DataTable {
         let handle_data_alliances = std::thread::spawn(move || {
             download_generic(
                 &thread_client,
-                format!("https://{thread_server_id}.grepolis.com/data/alliances.txt"),
+                &format!("https://{thread_server_id}.grepolis.com/data/alliances.txt"),
             )
         });
         let thread_client = reqwest_client.clone();
@@ -63,7 +74,7 @@ impl DataTable {
         let handle_data_towns = std::thread::spawn(move || {
             download_generic(
                 &thread_client,
-                format!("https://{thread_server_id}.grepolis.com/data/towns.txt"),
+                &format!("https://{thread_server_id}.grepolis.com/data/towns.txt"),
             )
         });
         let thread_client = reqwest_client;
@@ -71,7 +82,7 @@ impl DataTable {
         let handle_data_islands = std::thread::spawn(move || {
             download_generic(
                 &thread_client,
-                format!("https://{thread_server_id}.grepolis.com/data/islands.txt"),
+                &format!("https://{thread_server_id}.grepolis.com/data/islands.txt"),
             )
         });
 
@@ -81,25 +92,41 @@ impl DataTable {
             .join()
             .expect("Failed to join AllianceData fetching thread")
             .context("Failed to download alliance data")?;
-        let alliances = Self::parse_alliances(&data_alliances)?;
+        let alliances = Self::parse_alliances(&data_alliances).inspect_err(|_| {
+            metrics::PARSE_ERRORS
+                .with_label_values(&["alliances.txt"])
+                .inc();
+        })?;
 
         let data_islands = handle_data_islands
             .join()
             .expect("Failed to join islandData fetching thread")
             .context("Failed to download island data")?;
-        let islands = Self::parse_islands(&data_islands)?;
+        let islands = Self::parse_islands(&data_islands).inspect_err(|_| {
+            metrics::PARSE_ERRORS
+                .with_label_values(&["islands.txt"])
+                .inc();
+        })?;
 
         let data_players = handle_data_players
             .join()
             .expect("Failed to join PlayerData fetching thread")
             .context("Failed to download player data")?;
-        let players = Self::parse_players(&data_players)?;
+        let players = Self::parse_players(&data_players).inspect_err(|_| {
+            metrics::PARSE_ERRORS
+                .with_label_values(&["players.txt"])
+                .inc();
+        })?;
 
         let data_towns = handle_data_towns
             .join()
             .expect("Failed to join TownData fetching thread")
             .context("Failed to download town data")?;
-        let towns = Self::parse_towns(&data_towns, &offsets)?;
+        let towns = Self::parse_towns(&data_towns, &offsets).inspect_err(|_| {
+            metrics::PARSE_ERRORS
+                .with_label_values(&["towns.txt"])
+                .inc();
+        })?;
 
         let re = Self {
             loaded: Utc::now(),
@@ -112,6 +139,7 @@ impl DataTable {
 
         // abort if not all references are valid
         if !re.all_references_valid() {
+            metrics::INVALID_REFERENCES.inc();
             return Err(anyhow!("Invalid references in API response"));
         }
 
@@ -120,7 +148,7 @@ impl DataTable {
 
     /// the response from the server is basically a db dump. We parse the response as is and
     /// just store the references in the Town/Player/etc structs. But the API sometimes returns
-    /// mismatched tables (mismatched in time). So it may be that the references that TownA has into
+    /// mismatched tables (mismatched in time). So it may be that the references that `TownA` has into
     /// the Player Table is no longer valid.  Therefore this function exists. it checks if such a
     /// case exists (returns false) or if all references are valid (returns true)
     fn all_references_valid(&self) -> bool {
@@ -153,24 +181,43 @@ impl DataTable {
         return true;
     }
 
-    fn make_offsets() -> HashMap<u8, Offset> {
-        let lines: Vec<&str> = offset_data::OFFSET_DATA.lines().collect();
-        let mut re = HashMap::with_capacity(lines.len());
-        for line in lines {
-            let mut values = line.split(',');
-            let typ: u8 = values.next().unwrap().parse().unwrap();
-            let x: u16 = values.next().unwrap().parse().unwrap();
-            let y: u16 = values.next().unwrap().parse().unwrap();
-            let slot_number: u8 = values.next().unwrap().parse().unwrap();
-            re.insert(
-                slot_number,
+    fn parse_offset_data() -> Vec<Offset> {
+        offset_data::OFFSET_DATA
+            .lines()
+            .map(|line| {
+                let mut values = line.split(',');
+                let typ: u8 = values.next().unwrap().parse().unwrap();
+                let x: u16 = values.next().unwrap().parse().unwrap();
+                let y: u16 = values.next().unwrap().parse().unwrap();
+                let slot_number: u8 = values.next().unwrap().parse().unwrap();
                 Offset {
                     typ,
                     x,
                     y,
                     slot_number,
-                },
-            );
+                }
+            })
+            .collect()
+    }
+
+    fn make_offsets() -> HashMap<u8, Offset> {
+        let offsets = Self::parse_offset_data();
+        let mut re = HashMap::with_capacity(offsets.len());
+        for offset in offsets {
+            re.insert(offset.slot_number, offset);
+        }
+        return re;
+    }
+
+    /// all town slots of every island type, ordered by slot number. Unlike `self.offsets` this
+    /// keeps the slots of different island types apart.
+    pub fn offsets_by_island_type() -> HashMap<u8, Vec<Offset>> {
+        let mut re: HashMap<u8, Vec<Offset>> = HashMap::new();
+        for offset in Self::parse_offset_data() {
+            re.entry(offset.typ).or_default().push(offset);
+        }
+        for slots in re.values_mut() {
+            slots.sort_by_key(|o| o.slot_number);
         }
         return re;
     }
@@ -409,9 +456,7 @@ impl DataTable {
             // get the offset from the offset list from slot_number
             let offset = offsets.get(&slot_number).unwrap();
 
-            // compute actual x
-            let actual_x = x as f32 + offset.x as f32 / 125f32;
-            let actual_y = y as f32 + offset.y as f32 / 125f32;
+            let (actual_x, actual_y) = offset.position(x, y);
 
             re.insert(
                 id,
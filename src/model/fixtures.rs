@@ -0,0 +1,74 @@
+//! Small worlds for the tests, shared by the tests of the model, the database and the web pages.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use super::database::{Alliance, DataTable, Player, Town};
+
+pub fn alliance(id: u32, name: &str) -> Alliance {
+    Alliance {
+        id,
+        name: name.to_string(),
+        points: 10_000,
+        towns: 5,
+        members: 2,
+        rank: 1,
+    }
+}
+
+pub fn player(id: u32, name: &str, alliance_id: Option<u32>) -> Player {
+    Player {
+        id,
+        name: name.to_string(),
+        alliance_id,
+        points: 5000,
+        rank: 3,
+        towns: 2,
+    }
+}
+
+/// a town on the island at 512|487
+pub fn town(id: u32, name: &str, player_id: Option<u32>) -> Town {
+    Town {
+        id,
+        name: name.to_string(),
+        points: 1500,
+        player_id,
+        island_xy: (512, 487),
+        offset_slotnumber: 3,
+        actual_x: 512.5,
+        actual_y: 487.25,
+    }
+}
+
+/// a town named after its id with the given points and position
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn town_at(id: u32, player_id: Option<u32>, points: u16, x: f32, y: f32) -> Town {
+    Town {
+        id,
+        name: format!("Town {id}"),
+        points,
+        player_id,
+        island_xy: (x as u16, y as u16),
+        offset_slotnumber: 0,
+        actual_x: x,
+        actual_y: y,
+    }
+}
+
+pub fn world(
+    loaded: DateTime<Utc>,
+    alliances: Vec<Alliance>,
+    players: Vec<Player>,
+    towns: Vec<Town>,
+) -> DataTable {
+    DataTable {
+        loaded,
+        offsets: HashMap::new(),
+        islands: HashMap::new(),
+        alliances: alliances.into_iter().map(|a| (a.id, a)).collect(),
+        players: players.into_iter().map(|p| (p.id, p)).collect(),
+        towns: towns.into_iter().map(|t| (t.id, t)).collect(),
+    }
+}
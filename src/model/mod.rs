@@ -1,157 +1,158 @@
-use anyhow::Context;
 use chrono::Utc;
-use std::{collections::HashSet, sync::mpsc::Sender, thread, time};
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, SendError, Sender},
+        Arc,
+    },
+    time,
+};
 use tracing::{error, info, warn};
 
-use postcard;
-
 use crate::{
-    db::orm::{OrmGS, OrmPlayer},
-    messages::MessageFromModelToDB,
+    messages::{MessageFromModelToDB, Shutdown},
+    metrics, status,
 };
 
 use self::database::DataTable;
 
 pub mod database;
+pub mod diff;
 mod download;
+#[cfg(test)]
+pub mod fixtures;
 mod offset_data;
+pub mod region;
+pub mod snapshot;
+pub mod targets;
+pub mod territory;
+pub mod travel;
+
+/// the world that is tracked
+pub const WORLD: &str = "de99";
+
+/// the latest snapshot, to compare against right after a restart
+const STATE_PATH: &str = "./state_old.bin";
+/// the least time between two fetches
+const FETCH_INTERVAL: time::Duration = time::Duration::from_secs(60 * 60);
+/// the time to wait after a failed fetch
+const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(60);
 
 pub struct Model {
     tx: Sender<MessageFromModelToDB>,
+    rx_shutdown: Receiver<Shutdown>,
 }
 
 impl Model {
-    pub fn new(tx: Sender<MessageFromModelToDB>) -> Self {
-        Self { tx }
+    pub fn new(tx: Sender<MessageFromModelToDB>, rx_shutdown: Receiver<Shutdown>) -> Self {
+        Self { tx, rx_shutdown }
+    }
+
+    /// sleep for the given duration, unless we are asked to shut down. Returns whether to stop.
+    fn wait(&self, duration: time::Duration) -> bool {
+        match self.rx_shutdown.recv_timeout(duration) {
+            Err(RecvTimeoutError::Timeout) => false,
+            Ok(Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                info!("Shutting down the model");
+                true
+            }
+        }
     }
 
     /// load the file `old_state.bin` from disk into a `DataTable`
     fn load_state() -> anyhow::Result<DataTable> {
-        let bytes = std::fs::read("./state_old.bin")
-            .with_context(|| "Failed to read the old state from disk!")?;
-        let dt = postcard::from_bytes(&bytes).with_context(|| "Failed to parse the old state!")?;
-        return Ok(dt);
+        snapshot::load(Path::new(STATE_PATH))
     }
 
     /// save the given `DataTable` to the file `old_state.bin` on disk
     fn save_state(dt: &DataTable) -> anyhow::Result<()> {
-        let bytes = postcard::to_allocvec(dt)
-            .with_context(|| "failed to convert the Datatable to postcard format")?;
-        std::fs::write("./state_old.bin", bytes)
-            .with_context(|| "Failed to write the old state to disk")?;
-        return Ok(());
+        snapshot::save(Path::new(STATE_PATH), dt)
     }
 
-    fn get_datatable_for_sure() -> DataTable {
+    /// fetch the world until it succeeds. None if we have to shut down before that.
+    fn get_datatable_for_sure(&self) -> Option<DataTable> {
         loop {
             // TODO do this for more servers
-            let res = DataTable::create_for_world("de99");
+            let res = DataTable::create_for_world(WORLD);
             match res {
                 Ok(dt) => {
                     info!("Successfully loaded a new DataTable");
-                    break dt;
+                    status::record_fetch(WORLD);
+                    break Some(dt);
                 }
                 Err(err) => {
                     warn!("Failed to load DB: {:?}", err);
-                    thread::sleep(time::Duration::from_secs(60));
+                    if self.wait(RETRY_INTERVAL) {
+                        break None;
+                    }
                 }
             }
         }
     }
 
+    /// send the message to the DB and count the events in it once it was sent
+    fn send(&self, msg: MessageFromModelToDB) -> Result<(), SendError<MessageFromModelToDB>> {
+        let events = msg.event_count();
+        self.tx.send(msg).inspect(|()| {
+            metrics::sent(metrics::MODEL_TO_DB);
+            if let Some((typ, count)) = events {
+                metrics::EVENTS
+                    .with_label_values(&[typ])
+                    .inc_by(u64::try_from(count).unwrap_or(u64::MAX));
+                metrics::EVENTS_LAST_CYCLE
+                    .with_label_values(&[typ])
+                    .set(i64::try_from(count).unwrap_or(i64::MAX));
+            }
+        })
+    }
+
+    /// hand the given snapshot to the DB, which passes it on to the webserver
+    fn send_snapshot(&self, dt: &Arc<DataTable>) {
+        let res = self.send(MessageFromModelToDB::NewSnapshot(Arc::clone(dt)));
+        if let Err(err) = res {
+            error!("Failed to send snapshot to Database: {}", err);
+        }
+    }
+
     pub fn start(self) {
-        let mut state_old = Self::load_state().unwrap_or_else(|err| {
-            error!("{:?}", err);
-            Self::get_datatable_for_sure()
-        });
+        let state_old = match Self::load_state() {
+            Ok(dt) => Some(dt),
+            Err(err) => {
+                error!("{:?}", err);
+                self.get_datatable_for_sure()
+            }
+        };
+        let Some(state_old) = state_old else {
+            return;
+        };
+        let mut state_old = Arc::new(state_old);
+        self.send_snapshot(&state_old);
         loop {
             // ensure we do not compare datatables that were fetched less than one hour apart from each other.
-            let now = Utc::now();
-            let delta = now - state_old.loaded;
-            let min_sleep = chrono::Duration::try_hours(1).unwrap();
-            thread::sleep(
-                delta
-                    .min(min_sleep)
-                    .to_std()
-                    .unwrap_or(time::Duration::from_secs(1 * 60 * 60)),
-            );
-
-            let state_new = Self::get_datatable_for_sure();
-            let mut tracked_any_updates = false;
-
-            let gs_ids_new = state_new.get_ghost_town_ids();
-            let gs_ids_old = state_old.get_ghost_town_ids();
-
-            let diff_appeared = gs_ids_new.difference(&gs_ids_old); // basically new - old
-            let diff_conquered = gs_ids_old.difference(&gs_ids_new); // basically old - new
-
-            let gs_appeared: Vec<_> = diff_appeared
-                .filter_map(|id| state_old.towns.get(id))
-                .map(|town| {
-                    OrmGS::from((
-                        state_new.loaded,
-                        town,
-                        &state_old.players,
-                        &state_old.alliances,
-                    ))
-                })
-                .collect();
-            if !gs_appeared.is_empty() {
-                tracked_any_updates = true;
-                let res = self.tx.send(MessageFromModelToDB::GSAppeared(gs_appeared));
-                if let Err(err) = res {
-                    error!("Failed to send list of appeared GS to Database: {}", err);
-                }
+            let delta = (Utc::now() - state_old.loaded).to_std().unwrap_or_default();
+            if self.wait(FETCH_INTERVAL.saturating_sub(delta)) {
+                return;
             }
 
-            let gs_conquered: Vec<_> = diff_conquered
-                .filter_map(|id| state_new.towns.get(id))
-                .map(|town| {
-                    OrmGS::from((
-                        state_new.loaded,
-                        town,
-                        &state_new.players,
-                        &state_new.alliances,
-                    ))
-                })
-                .collect();
-            if !gs_conquered.is_empty() {
-                tracked_any_updates = true;
-                let res = self
-                    .tx
-                    .send(MessageFromModelToDB::GSConquered(gs_conquered));
-                if let Err(err) = res {
-                    error!("Failed to send list of conquered GS to Database: {}", err);
-                }
-            }
+            let Some(state_new) = self.get_datatable_for_sure() else {
+                return;
+            };
+            let state_new = Arc::new(state_new);
+            metrics::EVENTS_LAST_CYCLE.reset();
 
-            // Determine which player no longer exists
-            let player_ids_old: HashSet<_> = state_old.players.keys().collect();
-            let player_ids_new: HashSet<_> = state_new.players.keys().collect();
-
-            let players_disappeared: Vec<_> = player_ids_old
-                .difference(&player_ids_new)
-                .filter_map(|id| state_old.players.get(id))
-                .map(|player| OrmPlayer::from((state_new.loaded, player, &state_old.alliances)))
-                .collect();
-            if !players_disappeared.is_empty() {
-                tracked_any_updates = true;
-                let res = self.tx.send(MessageFromModelToDB::PlayersDisappeared(
-                    players_disappeared,
-                ));
+            let msgs = MessageFromModelToDB::from_events(diff::diff(&state_old, &state_new));
+            if msgs.is_empty() {
+                info!("No changes this time");
+            }
+            for msg in msgs {
+                let res = self.send(msg);
                 if let Err(err) = res {
-                    error!(
-                        "Failed to send list of disappeared Players to Database: {}",
-                        err
-                    );
+                    error!("Failed to send {} to Database: {err}", err.0);
                 }
             }
 
-            if !tracked_any_updates {
-                info!("No changes this time");
-            }
-
             state_old = state_new;
+            self.send_snapshot(&state_old);
             let res = Self::save_state(&state_old);
             if let Err(err) = res {
                 error!("{:?}", err);
@@ -0,0 +1,86 @@
+//! Rectangular parts of the world, used to filter towns, islands and events by their position.
+
+use serde::{Deserialize, Serialize};
+
+/// the size of the world in fields, both in x and y direction
+pub const WORLD_SIZE: f32 = 1000.0;
+
+/// the ocean at the given position, see `Town::ocean`
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn ocean_at(x: f32, y: f32) -> u16 {
+    let x = x.clamp(0.0, WORLD_SIZE - 1.0) as u16;
+    let y = y.clamp(0.0, WORLD_SIZE - 1.0) as u16;
+    (x / 100) * 10 + y / 100
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub x0: f32,
+    pub y0: f32,
+    pub x1: f32,
+    pub y1: f32,
+}
+
+impl Region {
+    pub const WORLD: Region = Region {
+        x0: 0.0,
+        y0: 0.0,
+        x1: WORLD_SIZE,
+        y1: WORLD_SIZE,
+    };
+
+    /// the region covered by the given ocean, see `Town::ocean`
+    pub fn ocean(ocean: u16) -> Self {
+        let x0 = f32::from(ocean / 10) * 100.0;
+        let y0 = f32::from(ocean % 10) * 100.0;
+        Self {
+            x0,
+            y0,
+            x1: x0 + 100.0,
+            y1: y0 + 100.0,
+        }
+    }
+
+    /// the smallest region containing both given corners
+    pub fn from_corners(x0: f32, y0: f32, x1: f32, y1: f32) -> Self {
+        Self {
+            x0: x0.min(x1),
+            y0: y0.min(y1),
+            x1: x0.max(x1),
+            y1: y0.max(y1),
+        }
+    }
+
+    /// either the given ocean or the rectangle between the given corners, where missing corners
+    /// default to the edges of the world
+    pub fn from_filter(
+        ocean: Option<u16>,
+        x0: Option<f32>,
+        y0: Option<f32>,
+        x1: Option<f32>,
+        y1: Option<f32>,
+    ) -> Self {
+        if let Some(ocean) = ocean {
+            return Self::ocean(ocean);
+        }
+        let world = Self::WORLD;
+        Self::from_corners(
+            x0.unwrap_or(world.x0),
+            y0.unwrap_or(world.y0),
+            x1.unwrap_or(world.x1),
+            y1.unwrap_or(world.y1),
+        )
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        self.x0 <= x && x < self.x1 && self.y0 <= y && y < self.y1
+    }
+
+    pub fn width(&self) -> f32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> f32 {
+        self.y1 - self.y0
+    }
+}
@@ -0,0 +1,152 @@
+//! The file format of saved snapshots. A header in front of the postcard encoded `DataTable`
+//! identifies the file, the version of the format and carries a checksum of the data:
+//!
+//! | bytes | content                              |
+//! |-------|--------------------------------------|
+//! | 8     | `MAGIC`                              |
+//! | 2     | format version, little endian        |
+//! | 4     | crc32 of the payload, little endian  |
+//! | 8     | length of the payload, little endian |
+//! | ...   | payload                              |
+//!
+//! Files without the header were written before it existed, they count as version 0.
+//! Whenever the layout of `DataTable` changes, bump `FORMAT_VERSION`, keep a copy of the old
+//! structs and convert them in `decode`.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+
+use super::database::DataTable;
+
+const MAGIC: &[u8; 8] = b"GSWSNAP\n";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LENGTH: usize = 8 + 2 + 4 + 8;
+
+/// the snapshot as it is written to disk
+pub fn encode(dt: &DataTable) -> anyhow::Result<Vec<u8>> {
+    let payload = postcard::to_allocvec(dt)
+        .with_context(|| "failed to convert the Datatable to postcard format")?;
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    return Ok(bytes);
+}
+
+/// read a snapshot of any known version
+pub fn decode(bytes: &[u8]) -> anyhow::Result<DataTable> {
+    let Some(header) = bytes.strip_prefix(MAGIC) else {
+        // version 0, the payload had the same layout as in version 1
+        return postcard::from_bytes(bytes)
+            .with_context(|| "Failed to parse the snapshot without header");
+    };
+    if header.len() < HEADER_LENGTH - MAGIC.len() {
+        return Err(anyhow!("The snapshot header is truncated"));
+    }
+    let version = u16::from_le_bytes([header[0], header[1]]);
+    let checksum = u32::from_le_bytes(header[2..6].try_into().unwrap());
+    let length = u64::from_le_bytes(header[6..14].try_into().unwrap());
+    let payload = &header[14..];
+    if payload.len() as u64 != length {
+        return Err(anyhow!(
+            "The snapshot is truncated, expected {length} bytes but found {}",
+            payload.len()
+        ));
+    }
+    if crc32fast::hash(payload) != checksum {
+        return Err(anyhow!("The checksum of the snapshot does not match"));
+    }
+
+    match version {
+        1 => postcard::from_bytes(payload).with_context(|| "Failed to parse the snapshot"),
+        _ => Err(anyhow!(
+            "The snapshot has format version {version}, this build only knows up to {FORMAT_VERSION}"
+        )),
+    }
+}
+
+pub fn load(path: &Path) -> anyhow::Result<DataTable> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed to read the snapshot {}", path.display()))?;
+    decode(&bytes).with_context(|| format!("Failed to load the snapshot {}", path.display()))
+}
+
+/// write the snapshot next to `path` first and rename it afterwards, so that `path` is never left
+/// half written
+pub fn save(path: &Path, dt: &DataTable) -> anyhow::Result<()> {
+    let bytes = encode(dt)?;
+    let mut tmp = PathBuf::from(path);
+    tmp.as_mut_os_string().push(".tmp");
+    let mut file =
+        fs::File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(&bytes)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{decode, encode, HEADER_LENGTH};
+    use crate::model::database::{DataTable, Player};
+
+    fn datatable() -> DataTable {
+        DataTable {
+            loaded: "2024-03-01T12:00:00Z".parse().unwrap(),
+            offsets: HashMap::new(),
+            islands: HashMap::new(),
+            alliances: HashMap::new(),
+            players: HashMap::from([(
+                7,
+                Player {
+                    id: 7,
+                    name: String::from("Achilles"),
+                    alliance_id: None,
+                    points: 1234,
+                    rank: 1,
+                    towns: 2,
+                },
+            )]),
+            towns: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dt = datatable();
+        assert!(decode(&encode(&dt).unwrap()).unwrap() == dt);
+    }
+
+    #[test]
+    fn reads_snapshots_without_header() {
+        let dt = datatable();
+        let legacy = postcard::to_allocvec(&dt).unwrap();
+        assert!(decode(&legacy).unwrap() == dt);
+    }
+
+    #[test]
+    fn rejects_damaged_snapshots() {
+        let bytes = encode(&datatable()).unwrap();
+
+        let mut flipped = bytes.clone();
+        flipped[HEADER_LENGTH + 3] ^= 1;
+        assert!(decode(&flipped).is_err());
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&bytes[..HEADER_LENGTH - 1]).is_err());
+
+        let mut newer = bytes;
+        newer[8] = 2;
+        assert!(decode(&newer).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Ranks possible targets around an origin: the open ghost towns and optionally the towns of
+//! inactive players. Every target gets a score from its points, its distance, whether it lies in
+//! the ocean of the origin, how many enemy towns surround it and how long it has been open. Each
+//! part is scaled to 0 to 1 and weighted, the weights can be negative to prefer the opposite.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{database::DataTable, region::ocean_at, travel::Travel};
+
+/// towns open for this long or longer get the full score for being open
+const OPEN_HOURS_FULL: f64 = 7.0 * 24.0;
+/// this many enemy towns around a target give the full penalty
+const ENEMIES_FULL: f64 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Weights {
+    pub points: f64,
+    pub distance: f64,
+    pub same_ocean: f64,
+    pub enemies: f64,
+    pub open: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            points: 1.0,
+            distance: 1.0,
+            same_ocean: 0.5,
+            enemies: 1.0,
+            open: 0.5,
+        }
+    }
+}
+
+/// where the attack starts and who the attacker is
+#[derive(Debug, Clone, Copy)]
+pub struct Origin {
+    pub x: f32,
+    pub y: f32,
+    /// the towns of this player are never targets
+    pub player_id: Option<u32>,
+    /// towns of this alliance are neither targets nor enemies
+    pub alliance_id: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TargetQuery {
+    pub origin: Origin,
+    pub weights: Weights,
+    /// targets farther away than this many fields are left out
+    pub max_distance: f32,
+    /// enemy towns within this many fields of a target count towards its density
+    pub radius: f32,
+    /// the unit and speeds used for the travel time
+    pub travel: Travel,
+    pub now: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Target {
+    pub town_id: u32,
+    pub name: String,
+    pub points: u16,
+    pub x: f32,
+    pub y: f32,
+    pub ocean: u16,
+    /// the inactive owner, None for ghost towns
+    pub player_id: Option<u32>,
+    pub player: Option<String>,
+    /// since when the town is a ghost town or its owner inactive, if known
+    pub open_since: Option<DateTime<Utc>>,
+    /// the straight distance from the origin in fields
+    pub distance: f32,
+    /// the seconds the unit of the query needs from the origin
+    pub travel_seconds: u32,
+    /// the number of enemy towns within the radius
+    pub enemies: u32,
+    pub score: f64,
+}
+
+/// counts towns around a position quickly by sorting them into square cells of the radius
+struct Density {
+    radius: f32,
+    cells: HashMap<(i32, i32), Vec<(f32, f32)>>,
+}
+
+impl Density {
+    #[allow(clippy::cast_possible_truncation)]
+    fn cell(&self, x: f32, y: f32) -> (i32, i32) {
+        (
+            (x / self.radius).floor() as i32,
+            (y / self.radius).floor() as i32,
+        )
+    }
+
+    fn new(radius: f32, positions: impl Iterator<Item = (f32, f32)>) -> Self {
+        let mut re = Self {
+            radius: radius.max(0.1),
+            cells: HashMap::new(),
+        };
+        for (x, y) in positions {
+            let cell = re.cell(x, y);
+            re.cells.entry(cell).or_default().push((x, y));
+        }
+        return re;
+    }
+
+    fn around(&self, x: f32, y: f32) -> u32 {
+        let (cx, cy) = self.cell(x, y);
+        let mut count = 0;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let Some(cell) = self.cells.get(&(cx + dx, cy + dy)) else {
+                    continue;
+                };
+                count += cell
+                    .iter()
+                    .filter(|(tx, ty)| (tx - x).hypot(ty - y) <= self.radius)
+                    .count();
+            }
+        }
+        u32::try_from(count).unwrap_or(u32::MAX)
+    }
+}
+
+/// The targets within reach of the origin, the best first. `ghost_towns_since` holds since when
+/// towns are ghost towns, `inactive` since when players are inactive. Only the towns of the
+/// players in `inactive` are considered besides the ghost towns.
+pub fn find_targets(
+    dt: &DataTable,
+    query: &TargetQuery,
+    ghost_towns_since: &HashMap<u32, DateTime<Utc>>,
+    inactive: &HashMap<u32, DateTime<Utc>>,
+) -> Vec<Target> {
+    let origin = query.origin;
+    let alliance_of = |player_id: u32| dt.players.get(&player_id).and_then(|p| p.alliance_id);
+    let is_friendly = |player_id: u32| {
+        Some(player_id) == origin.player_id
+            || (origin.alliance_id.is_some() && alliance_of(player_id) == origin.alliance_id)
+    };
+    let enemies = Density::new(
+        query.radius,
+        dt.towns
+            .values()
+            .filter(|t| t.player_id.is_some_and(|p| !is_friendly(p)))
+            .map(|t| (t.actual_x, t.actual_y)),
+    );
+
+    let mut targets: Vec<Target> = dt
+        .towns
+        .values()
+        .filter_map(|town| {
+            let open_since = match town.player_id {
+                None => ghost_towns_since.get(&town.id).copied(),
+                Some(player_id) if !is_friendly(player_id) => Some(*inactive.get(&player_id)?),
+                Some(_) => return None,
+            };
+            let distance = (town.actual_x - origin.x).hypot(town.actual_y - origin.y);
+            if distance > query.max_distance {
+                return None;
+            }
+            let player = town.player_id.and_then(|id| dt.players.get(&id));
+            Some(Target {
+                town_id: town.id,
+                name: town.name.clone(),
+                points: town.points,
+                x: town.actual_x,
+                y: town.actual_y,
+                ocean: ocean_at(town.actual_x, town.actual_y),
+                player_id: player.map(|p| p.id),
+                player: player.map(|p| p.name.clone()),
+                open_since,
+                distance,
+                travel_seconds: query.travel.seconds(distance),
+                enemies: enemies.around(town.actual_x, town.actual_y),
+                score: 0.0,
+            })
+        })
+        .collect();
+
+    let max_points = targets.iter().map(|t| t.points).max().unwrap_or(1).max(1);
+    let origin_ocean = ocean_at(origin.x, origin.y);
+    let w = query.weights;
+    for target in &mut targets {
+        let open_hours = target.open_since.map_or(0.0, |since| {
+            #[allow(clippy::cast_precision_loss)]
+            let hours = (query.now - since).num_minutes() as f64 / 60.0;
+            hours
+        });
+        target.score = w.points * f64::from(target.points) / f64::from(max_points)
+            + w.distance
+                * (1.0 - f64::from(target.distance) / f64::from(query.max_distance.max(1.0)))
+            + w.same_ocean * f64::from(u8::from(target.ocean == origin_ocean))
+            - w.enemies * (f64::from(target.enemies) / ENEMIES_FULL).min(1.0)
+            + w.open * (open_hours / OPEN_HOURS_FULL).clamp(0.0, 1.0);
+    }
+    targets.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(a.distance.total_cmp(&b.distance))
+    });
+    return targets;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::{DateTime, Duration, Utc};
+
+    use super::{find_targets, Origin, TargetQuery, Weights};
+    use crate::model::{
+        database::DataTable,
+        fixtures::{player, town_at, world as world_of},
+        travel::Travel,
+    };
+
+    fn world() -> DataTable {
+        world_of(
+            Utc::now(),
+            vec![],
+            // 1 is the attacker, 2 in the same alliance, 3 an enemy, 4 an inactive enemy
+            vec![
+                player(1, "Attacker", Some(10)),
+                player(2, "Ally", Some(10)),
+                player(3, "Enemy", Some(20)),
+                player(4, "Inactive", None),
+            ],
+            vec![
+                town_at(1, Some(1), 5000, 500.0, 500.0),
+                // a large ghost town surrounded by enemies
+                town_at(2, None, 8000, 520.0, 500.0),
+                town_at(3, Some(3), 5000, 521.0, 501.0),
+                town_at(4, Some(3), 5000, 519.0, 499.0),
+                // a smaller one next to the origin
+                town_at(5, None, 6000, 503.0, 500.0),
+                // too far away
+                town_at(6, None, 9000, 600.0, 600.0),
+                // of an inactive player and of the own alliance
+                town_at(7, Some(4), 4000, 505.0, 505.0),
+                town_at(8, Some(2), 4000, 501.0, 501.0),
+            ],
+        )
+    }
+
+    fn query(weights: Weights) -> TargetQuery {
+        TargetQuery {
+            origin: Origin {
+                x: 500.0,
+                y: 500.0,
+                player_id: Some(1),
+                alliance_id: Some(10),
+            },
+            weights,
+            max_distance: 50.0,
+            radius: 5.0,
+            travel: Travel::default(),
+            now: Utc::now(),
+        }
+    }
+
+    fn ids(
+        dt: &DataTable,
+        query: &TargetQuery,
+        inactive: &HashMap<u32, DateTime<Utc>>,
+    ) -> Vec<u32> {
+        find_targets(dt, query, &HashMap::new(), inactive)
+            .iter()
+            .map(|t| t.town_id)
+            .collect()
+    }
+
+    #[test]
+    fn enemies_and_distance_outweigh_points() {
+        let dt = world();
+        let targets = find_targets(
+            &dt,
+            &query(Weights::default()),
+            &HashMap::new(),
+            &HashMap::new(),
+        );
+        let summary: Vec<_> = targets.iter().map(|t| (t.town_id, t.enemies)).collect();
+        assert_eq!(summary, vec![(5, 0), (2, 2)]);
+        assert!((targets[0].distance - 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weights_change_the_order() {
+        let dt = world();
+        let greedy = Weights {
+            points: 10.0,
+            ..Weights::default()
+        };
+        assert_eq!(ids(&dt, &query(greedy), &HashMap::new()), vec![2, 5]);
+    }
+
+    #[test]
+    fn inactive_players_are_included_on_request() {
+        let dt = world();
+        let since = Utc::now() - Duration::try_days(3).unwrap();
+        // the own alliance is never a target, even if inactive
+        let inactive = HashMap::from([(4, since), (2, since)]);
+        let mut found = ids(&dt, &query(Weights::default()), &inactive);
+        found.sort_unstable();
+        assert_eq!(found, vec![2, 5, 7]);
+    }
+}
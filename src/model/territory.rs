@@ -0,0 +1,173 @@
+//! The territory of the alliances: how many towns and which share of the points each alliance holds
+//! per ocean and where its towns are centred. Oceans where more than one alliance holds a large
+//! share are contested, that is where the frontlines are.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::{database::DataTable, region::ocean_at};
+
+/// the share of the points in an ocean from which on an alliance counts as present there
+pub const CONTESTED_SHARE: f64 = 0.2;
+
+/// the towns of one alliance in one ocean
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Territory {
+    pub ocean: u16,
+    pub alliance_id: u32,
+    pub alliance: String,
+    pub towns: u32,
+    pub points: u32,
+    /// the share of the towns with an owner in the ocean, between 0 and 1
+    pub town_share: f64,
+    /// the share of the points of the towns with an owner in the ocean
+    pub points_share: f64,
+    /// the average position of the towns
+    pub centroid: (f32, f32),
+}
+
+/// an ocean where at least two alliances hold `CONTESTED_SHARE` of the points
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Contested {
+    pub ocean: u16,
+    /// the territories of the alliances present, the strongest first
+    pub alliances: Vec<Territory>,
+}
+
+/// the territory of every alliance in every ocean it has towns in, ordered by ocean and the
+/// strongest alliance first. Towns of players without alliance count towards the totals of the
+/// ocean, ghost towns don't.
+#[allow(clippy::cast_precision_loss)]
+pub fn territories(dt: &DataTable) -> Vec<Territory> {
+    // towns and points of every ocean, and the towns, points and summed positions per alliance
+    let mut totals: BTreeMap<u16, (u32, u32)> = BTreeMap::new();
+    let mut per_alliance: BTreeMap<(u16, u32), (u32, u32, f64, f64)> = BTreeMap::new();
+    for town in dt.towns.values() {
+        let Some(player) = town.player_id.and_then(|id| dt.players.get(&id)) else {
+            continue;
+        };
+        let ocean = ocean_at(town.actual_x, town.actual_y);
+        let total = totals.entry(ocean).or_default();
+        total.0 += 1;
+        total.1 += u32::from(town.points);
+        let Some(alliance_id) = player.alliance_id else {
+            continue;
+        };
+        let entry = per_alliance.entry((ocean, alliance_id)).or_default();
+        entry.0 += 1;
+        entry.1 += u32::from(town.points);
+        entry.2 += f64::from(town.actual_x);
+        entry.3 += f64::from(town.actual_y);
+    }
+
+    let mut re: Vec<_> = per_alliance
+        .into_iter()
+        .filter_map(|((ocean, alliance_id), (towns, points, x, y))| {
+            let alliance = dt.alliances.get(&alliance_id)?;
+            let (total_towns, total_points) = totals[&ocean];
+            #[allow(clippy::cast_possible_truncation)]
+            Some(Territory {
+                ocean,
+                alliance_id,
+                alliance: alliance.name.clone(),
+                towns,
+                points,
+                town_share: f64::from(towns) / f64::from(total_towns),
+                points_share: f64::from(points) / f64::from(total_points.max(1)),
+                centroid: ((x / f64::from(towns)) as f32, (y / f64::from(towns)) as f32),
+            })
+        })
+        .collect();
+    re.sort_by(|a, b| {
+        a.ocean
+            .cmp(&b.ocean)
+            .then(b.points.cmp(&a.points))
+            .then(a.alliance_id.cmp(&b.alliance_id))
+    });
+    return re;
+}
+
+/// the oceans where at least two alliances hold `CONTESTED_SHARE` of the points, given the
+/// territories as returned by `territories`
+pub fn contested(territories: &[Territory]) -> Vec<Contested> {
+    let mut re: Vec<Contested> = Vec::new();
+    for territory in territories
+        .iter()
+        .filter(|t| t.points_share >= CONTESTED_SHARE)
+    {
+        match re.last_mut() {
+            Some(c) if c.ocean == territory.ocean => c.alliances.push(territory.clone()),
+            _ => re.push(Contested {
+                ocean: territory.ocean,
+                alliances: vec![territory.clone()],
+            }),
+        }
+    }
+    re.retain(|c| c.alliances.len() >= 2);
+    return re;
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{contested, territories};
+    use crate::model::{
+        database::DataTable,
+        fixtures::{alliance, player, town_at, world as world_of},
+    };
+
+    fn world() -> DataTable {
+        world_of(
+            Utc::now(),
+            vec![alliance(1, "Alliance 1"), alliance(2, "Alliance 2")],
+            vec![
+                player(10, "Player 10", Some(1)),
+                player(20, "Player 20", Some(2)),
+                player(30, "Player 30", None),
+            ],
+            vec![
+                // ocean 55: contested between both alliances
+                town_at(1, Some(10), 3000, 510.0, 520.0),
+                town_at(2, Some(10), 3000, 530.0, 540.0),
+                town_at(3, Some(20), 3000, 550.0, 550.0),
+                town_at(4, Some(30), 1000, 560.0, 560.0),
+                // ghost towns don't count
+                town_at(5, None, 9000, 570.0, 570.0),
+                // ocean 44: alliance 2 alone
+                town_at(6, Some(20), 500, 450.0, 450.0),
+            ],
+        )
+    }
+
+    #[test]
+    fn shares_and_centroids_per_ocean() {
+        let territories = territories(&world());
+        let summary: Vec<_> = territories
+            .iter()
+            .map(|t| (t.ocean, t.alliance_id, t.towns, t.points))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(44, 2, 1, 500), (55, 1, 2, 6000), (55, 2, 1, 3000)]
+        );
+        assert!((territories[0].points_share - 1.0).abs() < 1e-9);
+        assert!((territories[1].town_share - 0.5).abs() < 1e-9);
+        assert!((territories[1].points_share - 0.6).abs() < 1e-9);
+        assert_eq!(territories[1].centroid, (520.0, 530.0));
+    }
+
+    #[test]
+    fn contested_oceans_need_two_strong_alliances() {
+        let contested = contested(&territories(&world()));
+        assert_eq!(contested.len(), 1);
+        assert_eq!(contested[0].ocean, 55);
+        let ids: Vec<_> = contested[0]
+            .alliances
+            .iter()
+            .map(|t| t.alliance_id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+}
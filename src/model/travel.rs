@@ -0,0 +1,120 @@
+//! How long units sail between two positions. The game measures distances in pixels, a field of
+//! the world is 125 of them like in the town offsets, and a unit with speed 1 needs 50 seconds per
+//! pixel on a world with speed 1. Land units travel at the speed of the transport boats that
+//! carry them.
+
+use serde::{Deserialize, Serialize};
+
+use super::database::PIXELS_PER_FIELD;
+
+/// the speed of the tracked world, see `WORLD`
+pub const WORLD_SPEED: f32 = 1.0;
+
+const SECONDS_PER_PIXEL: f32 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    #[default]
+    ColonyShip,
+    TransportBoat,
+    FastTransport,
+    Bireme,
+    LightShip,
+    FireShip,
+    Trireme,
+}
+
+impl Unit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::ColonyShip => "colony ship",
+            Unit::TransportBoat => "transport boat",
+            Unit::FastTransport => "fast transport",
+            Unit::Bireme => "bireme",
+            Unit::LightShip => "light ship",
+            Unit::FireShip => "fire ship",
+            Unit::Trireme => "trireme",
+        }
+    }
+
+    /// the base speed of the unit, without research or hero bonuses
+    pub fn speed(self) -> f32 {
+        match self {
+            Unit::ColonyShip => 3.0,
+            Unit::FireShip => 5.0,
+            Unit::TransportBoat => 8.0,
+            Unit::LightShip => 13.0,
+            Unit::Bireme | Unit::FastTransport | Unit::Trireme => 15.0,
+        }
+    }
+}
+
+/// the speeds that determine a travel time
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Travel {
+    pub unit: Unit,
+    /// the speed of the unit including bonuses, the base speed of the unit by default
+    pub unit_speed: f32,
+    pub world_speed: f32,
+}
+
+impl Default for Travel {
+    fn default() -> Self {
+        Self::new(Unit::default(), None, None)
+    }
+}
+
+impl Travel {
+    pub fn new(unit: Unit, unit_speed: Option<f32>, world_speed: Option<f32>) -> Self {
+        Self {
+            unit,
+            unit_speed: unit_speed.unwrap_or_else(|| unit.speed()),
+            world_speed: world_speed.unwrap_or(WORLD_SPEED),
+        }
+    }
+
+    /// the seconds needed for `distance` fields
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn seconds(&self, distance: f32) -> u32 {
+        let speed = (self.unit_speed * self.world_speed).max(0.01);
+        (distance * PIXELS_PER_FIELD * SECONDS_PER_PIXEL / speed).round() as u32
+    }
+}
+
+/// a travel time like "3h 12m", or "2d 4h 5m" for more than a day
+pub fn format_seconds(seconds: u32) -> String {
+    let minutes = (seconds + 30) / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else {
+        format!("{hours}h {minutes}m")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_seconds, Travel, Unit};
+
+    #[test]
+    fn faster_units_and_worlds_arrive_earlier() {
+        let colony = Travel::default();
+        // 10 fields are 1250 pixels, 62500 seconds at speed 1
+        assert_eq!(colony.seconds(10.0), 20833);
+        let fast_world = Travel::new(Unit::ColonyShip, None, Some(2.0));
+        assert_eq!(fast_world.seconds(10.0), 10417);
+        let bireme = Travel::new(Unit::Bireme, None, None);
+        assert!(bireme.seconds(10.0) < colony.seconds(10.0));
+        let boosted = Travel::new(Unit::ColonyShip, Some(6.0), None);
+        assert_eq!(boosted.seconds(10.0), fast_world.seconds(10.0));
+    }
+
+    #[test]
+    fn formats_hours_and_days() {
+        assert_eq!(format_seconds(3 * 3600 + 12 * 60), "3h 12m");
+        assert_eq!(format_seconds(20833), "5h 47m");
+        assert_eq!(format_seconds(2 * 86400 + 4 * 3600 + 300), "2d 4h 5m");
+        assert_eq!(format_seconds(0), "0h 0m");
+    }
+}
@@ -0,0 +1,92 @@
+//! What the threads did last, for `/status` and `/healthz`. Like the metrics, this is shared by
+//! all threads. Warnings and errors are collected by a tracing layer.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Write},
+    sync::{LazyLock, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// the number of warnings and errors that are kept
+const RECENT_ERRORS: usize = 20;
+
+#[derive(Clone, Serialize)]
+pub struct LogEntry {
+    pub date: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct Status {
+    /// the last time a complete and valid snapshot was fetched, per world
+    pub last_fetch: BTreeMap<String, DateTime<Utc>>,
+    /// the last time the DB committed new events
+    pub last_commit: Option<DateTime<Utc>>,
+    /// the latest warnings and errors, newest first
+    pub recent_errors: VecDeque<LogEntry>,
+}
+
+static STATUS: LazyLock<Mutex<Status>> = LazyLock::new(Mutex::default);
+
+/// a copy of the current status
+pub fn current() -> Status {
+    STATUS.lock().unwrap().clone()
+}
+
+pub fn record_fetch(world: &str) {
+    STATUS
+        .lock()
+        .unwrap()
+        .last_fetch
+        .insert(world.to_string(), Utc::now());
+}
+
+pub fn record_commit() {
+    STATUS.lock().unwrap().last_commit = Some(Utc::now());
+}
+
+/// collects the fields of an event into a single line
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{value:?}");
+        } else {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+}
+
+/// remembers the latest warnings and errors in the status
+pub struct RecentErrors;
+
+impl<S: Subscriber> Layer<S> for RecentErrors {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        let entry = LogEntry {
+            date: Utc::now(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.0,
+        };
+        let mut status = STATUS.lock().unwrap();
+        status.recent_errors.push_front(entry);
+        status.recent_errors.truncate(RECENT_ERRORS);
+    }
+}
@@ -0,0 +1,172 @@
+//! Runs the threads of the server. A crashed model is restarted while everything else keeps
+//! running, any other crash or a SIGTERM/SIGINT shuts the server down. Shutting down only stops
+//! the model, the other threads follow once the channels they read from are closed. That way the
+//! DB still commits everything the model has sent.
+
+use std::{
+    fmt, panic,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tracing::{error, info, warn};
+
+use crate::messages::Shutdown;
+
+/// the time to wait before a crashed model is started again
+const RESTART_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Worker {
+    Model,
+    DB,
+    Web,
+    Webhooks,
+}
+
+impl fmt::Display for Worker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Worker::Model => write!(f, "model"),
+            Worker::DB => write!(f, "db"),
+            Worker::Web => write!(f, "web"),
+            Worker::Webhooks => write!(f, "webhooks"),
+        }
+    }
+}
+
+enum Event {
+    Signal(i32),
+    /// the thread has ended, the flag tells whether it panicked
+    Exited(Worker, bool),
+}
+
+type ModelFn = Arc<dyn Fn(Receiver<Shutdown>) + Send + Sync>;
+
+pub struct Supervisor {
+    tx_events: Sender<Event>,
+    rx_events: Receiver<Event>,
+    running: usize,
+    /// starts the model. Dropped on shutdown, together with the sender to the DB it holds.
+    model: Option<ModelFn>,
+    /// stops the running model
+    tx_shutdown: Option<Sender<Shutdown>>,
+}
+
+impl Supervisor {
+    /// also handles SIGTERM and SIGINT from here on
+    pub fn new() -> Self {
+        let (tx_events, rx_events) = mpsc::channel();
+
+        let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Failed to handle signals");
+        let tx_signals = tx_events.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if tx_signals.send(Event::Signal(signal)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            tx_events,
+            rx_events,
+            running: 0,
+            model: None,
+            tx_shutdown: None,
+        }
+    }
+
+    /// run `f` on its own thread and get notified when it ends
+    pub fn spawn(&mut self, worker: Worker, f: impl FnOnce() + Send + 'static) {
+        let tx_events = self.tx_events.clone();
+        thread::Builder::new()
+            .name(worker.to_string())
+            .spawn(move || {
+                let res = panic::catch_unwind(panic::AssertUnwindSafe(f));
+                let _ = tx_events.send(Event::Exited(worker, res.is_err()));
+            })
+            .expect("Failed to spawn thread");
+        self.running += 1;
+    }
+
+    /// run the model on its own thread. `f` is called again whenever the model crashed.
+    pub fn spawn_model(&mut self, f: impl Fn(Receiver<Shutdown>) + Send + Sync + 'static) {
+        self.model = Some(Arc::new(f));
+        self.restart_model();
+    }
+
+    fn restart_model(&mut self) {
+        let Some(model) = self.model.clone() else {
+            return;
+        };
+        let (tx_shutdown, rx_shutdown) = mpsc::channel();
+        self.tx_shutdown = Some(tx_shutdown);
+        self.spawn(Worker::Model, move || model(rx_shutdown));
+    }
+
+    /// stop the model and with it everything else
+    fn shutdown(&mut self) {
+        if let Some(tx_shutdown) = self.tx_shutdown.take() {
+            let _ = tx_shutdown.send(Shutdown);
+        }
+        self.model = None;
+    }
+
+    /// wait until all threads have ended. Returns the exit code for the process.
+    pub fn run(mut self) -> i32 {
+        let mut exit_code = 0;
+        let mut restart_at: Option<Instant> = None;
+        while self.running > 0 || restart_at.is_some() {
+            let event = match restart_at {
+                Some(at) => self
+                    .rx_events
+                    .recv_timeout(at.saturating_duration_since(Instant::now())),
+                None => self
+                    .rx_events
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match event {
+                Ok(Event::Signal(signal)) => {
+                    info!("Received signal {signal}, shutting down");
+                    restart_at = None;
+                    self.shutdown();
+                }
+                Ok(Event::Exited(worker, panicked)) => {
+                    self.running -= 1;
+                    if !panicked {
+                        info!("The {worker} thread has ended");
+                    } else if worker == Worker::Model && self.model.is_some() {
+                        warn!(
+                            "The model crashed, restarting it in {}s",
+                            RESTART_DELAY.as_secs()
+                        );
+                        restart_at = Some(Instant::now() + RESTART_DELAY);
+                        continue;
+                    } else {
+                        error!("The {worker} thread crashed, shutting down");
+                        exit_code = 1;
+                    }
+                    // without the model there is nothing left to do, without any other thread
+                    // the data goes nowhere
+                    self.shutdown();
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    restart_at = None;
+                    self.restart_model();
+                }
+                Err(RecvTimeoutError::Disconnected) => unreachable!("we hold a sender ourselves"),
+            }
+        }
+        return exit_code;
+    }
+}
@@ -0,0 +1,263 @@
+//! Who is asking. Private views take an `Auth`, which finds the account by its API token in the
+//! `Authorization: Bearer` header, the login cookie or a `token` query parameter for feed
+//! readers. Logging in on `/login` creates a token that expires after `LOGIN_DAYS` and stores it
+//! in the cookie. Public views don't ask.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect},
+    Form, Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::db::accounts::{self, Account, Role, TokenInfo};
+
+use super::{html, query_db, write_db};
+
+const COOKIE: &str = "gregswatch_token";
+const LOGIN_DAYS: i64 = 30;
+
+/// the account of the request, rejects the request with a 401 if there is none
+pub struct Auth(pub Account);
+
+impl Auth {
+    /// a 403 unless the account has at least the given role
+    pub fn require(&self, role: Role) -> Result<(), StatusCode> {
+        if self.0.role >= role {
+            return Ok(());
+        }
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+fn cookie_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .find_map(|c| c.trim().strip_prefix(COOKIE)?.strip_prefix('='))
+        .map(String::from)
+}
+
+fn token(parts: &Parts) -> Option<String> {
+    let bearer = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string());
+    let query = || {
+        form_urlencoded::parse(parts.uri.query().unwrap_or_default().as_bytes())
+            .find(|(key, _)| key == "token")
+            .map(|(_, value)| value.into_owned())
+    };
+    bearer
+        .or_else(|| cookie_token(&parts.headers))
+        .or_else(query)
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Auth {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = token(parts).ok_or(StatusCode::UNAUTHORIZED)?;
+        query_db(move |conn| accounts::account_for_token(conn, &token, Utc::now()))
+            .await?
+            .map(Auth)
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+fn login_form(message: &str) -> Html<String> {
+    let body = format!(
+        r#"<h1>Log in</h1>
+<p>{}</p>
+<form method="post" action="/login">
+  <p><label>Name <input name="name" autocomplete="username" required></label></p>
+  <p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+  <p><button>Log in</button></p>
+</form>"#,
+        html::escape(message)
+    );
+    html::page("Log in", &body)
+}
+
+#[allow(clippy::unused_async)]
+pub async fn login_page() -> Html<String> {
+    login_form("Log in to see the private views of your alliance.")
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    name: String,
+    password: String,
+}
+
+pub async fn login(Form(form): Form<LoginForm>) -> Result<impl IntoResponse, StatusCode> {
+    let token = write_db(move |conn| {
+        let Some(account) = accounts::check_password(conn, &form.name, &form.password)? else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        let expires = now + Duration::try_days(LOGIN_DAYS).unwrap_or_default();
+        accounts::create_token(conn, account.id, "login", Some(expires), now).map(Some)
+    })
+    .await?;
+    let Some(token) = token else {
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            login_form("Unknown name or wrong password."),
+        )
+            .into_response());
+    };
+    let cookie = format!(
+        "{COOKIE}={token}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        LOGIN_DAYS * 24 * 60 * 60
+    );
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response())
+}
+
+/// revokes the token of the cookie and removes the cookie
+pub async fn logout(headers: HeaderMap) -> Result<impl IntoResponse, StatusCode> {
+    if let Some(token) = cookie_token(&headers) {
+        write_db(move |conn| accounts::revoke_by_token(conn, &token)).await?;
+    }
+    let cookie = format!("{COOKIE}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0");
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to("/")))
+}
+
+#[allow(clippy::unused_async)]
+pub async fn me(Auth(account): Auth) -> Json<Account> {
+    Json(account)
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    label: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    /// shown only once
+    token: String,
+    label: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+pub async fn list_tokens(Auth(account): Auth) -> Result<Json<Vec<TokenInfo>>, StatusCode> {
+    Ok(Json(
+        query_db(move |conn| accounts::tokens(conn, account.id)).await?,
+    ))
+}
+
+pub async fn create_token(
+    Auth(account): Auth,
+    Json(new): Json<NewToken>,
+) -> Result<(StatusCode, Json<CreatedToken>), StatusCode> {
+    let label = new.label.clone();
+    let token = write_db(move |conn| {
+        accounts::create_token(conn, account.id, &label, new.expires, Utc::now())
+    })
+    .await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedToken {
+            token,
+            label: new.label,
+            expires: new.expires,
+        }),
+    ))
+}
+
+pub async fn revoke_token(Auth(account): Auth, Path(id): Path<i64>) -> StatusCode {
+    match write_db(move |conn| accounts::revoke_token(conn, account.id, id)).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(status) => status,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AccountChange {
+    /// required when creating an account, ignored when changing one
+    #[serde(default)]
+    name: String,
+    /// a new password, accounts without one can only use tokens
+    password: Option<String>,
+    role: Role,
+    alliance_id: Option<u32>,
+}
+
+pub async fn list_accounts(auth: Auth) -> Result<Json<Vec<Account>>, StatusCode> {
+    auth.require(Role::Admin)?;
+    Ok(Json(query_db(accounts::list).await?))
+}
+
+pub async fn create_account(
+    auth: Auth,
+    Json(new): Json<AccountChange>,
+) -> Result<(StatusCode, Json<Account>), (StatusCode, String)> {
+    auth.require(Role::Admin)
+        .map_err(|status| (status, String::from("Only admins manage accounts")))?;
+    if new.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, String::from("No name given")));
+    }
+    let name = new.name.trim().to_string();
+    let account = write_db(move |conn| {
+        accounts::create(
+            conn,
+            &name,
+            new.password.as_deref(),
+            new.role,
+            new.alliance_id,
+        )
+    })
+    .await
+    .map_err(|status| {
+        (
+            status,
+            String::from("Failed to create the account, is the name taken?"),
+        )
+    })?;
+    Ok((StatusCode::CREATED, Json(account)))
+}
+
+pub async fn update_account(
+    auth: Auth,
+    Path(id): Path<i64>,
+    Json(change): Json<AccountChange>,
+) -> StatusCode {
+    if let Err(status) = auth.require(Role::Admin) {
+        return status;
+    }
+    let res = write_db(move |conn| {
+        let existed = accounts::update(conn, id, change.role, change.alliance_id)?;
+        if let (true, Some(password)) = (existed, &change.password) {
+            accounts::set_password(conn, id, password)?;
+        }
+        Ok(existed)
+    })
+    .await;
+    match res {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(status) => status,
+    }
+}
+
+pub async fn remove_account(auth: Auth, Path(id): Path<i64>) -> StatusCode {
+    if let Err(status) = auth.require(Role::Admin) {
+        return status;
+    }
+    match write_db(move |conn| accounts::delete(conn, id)).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(status) => status,
+    }
+}
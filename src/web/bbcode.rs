@@ -0,0 +1,354 @@
+//! `BBCode` versions of the event lists and of the current ghost towns, to paste into the forum
+//! of an alliance. Towns are grouped by ocean and sorted by points, the forum turns the tags into
+//! links to the towns, players and alliances.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::{
+    db::{
+        orm::{OrmGS, OrmPlayer},
+        queries::{self, EventFilter},
+    },
+    messages::thousands,
+    model::{
+        region::ocean_at,
+        travel::{format_seconds, Travel},
+    },
+};
+
+use super::{query_db, RegionParams, TravelParams, WebState};
+
+const CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const DEFAULT_HOURS: i64 = 24;
+
+/// a town in a `BBCode` list, independent of where it comes from
+pub struct TownLine {
+    pub id: Option<u32>,
+    pub name: String,
+    pub points: u16,
+    pub x: f32,
+    pub y: f32,
+    pub player: Option<String>,
+    pub alliance: Option<String>,
+}
+
+impl From<OrmGS> for TownLine {
+    fn from(gs: OrmGS) -> Self {
+        Self {
+            id: gs.town_id,
+            name: gs.name,
+            points: gs.points,
+            x: gs.x,
+            y: gs.y,
+            player: gs.player_name,
+            alliance: gs.alliance_name,
+        }
+    }
+}
+
+/// the town that travel times in a list are measured from
+pub struct Arrival {
+    pub x: f32,
+    pub y: f32,
+    pub travel: Travel,
+}
+
+impl Arrival {
+    /// from the town with the id `from` in the snapshot, None if no town is given. Unknown towns
+    /// are a 404.
+    fn from_town(
+        state: &WebState,
+        from: Option<u32>,
+        travel: &TravelParams,
+    ) -> Result<Option<Self>, StatusCode> {
+        let Some(from) = from else {
+            return Ok(None);
+        };
+        let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        let town = snapshot.towns.get(&from).ok_or(StatusCode::NOT_FOUND)?;
+        Ok(Some(Self {
+            x: town.actual_x,
+            y: town.actual_y,
+            travel: travel.travel(),
+        }))
+    }
+
+    fn seconds_to(&self, x: f32, y: f32) -> u32 {
+        self.travel.seconds((x - self.x).hypot(y - self.y))
+    }
+}
+
+fn player_tag(name: &str) -> String {
+    format!("[player]{name}[/player]")
+}
+
+fn alliance_tag(name: &str) -> String {
+    format!("[ally]{name}[/ally]")
+}
+
+/// the towns grouped by ocean and sorted by points within each ocean. `owner` is written in front
+/// of the player, i.e. "was owned by" for towns that have just been abandoned. With `arrival` every
+/// town shows how long the unit needs to get there.
+pub fn render_towns(
+    title: &str,
+    owner: &str,
+    towns: Vec<TownLine>,
+    arrival: Option<&Arrival>,
+) -> String {
+    let mut oceans: BTreeMap<u16, Vec<TownLine>> = BTreeMap::new();
+    for town in towns {
+        oceans
+            .entry(ocean_at(town.x, town.y))
+            .or_default()
+            .push(town);
+    }
+
+    let mut re = format!("[b]{title}[/b]\n");
+    if oceans.is_empty() {
+        re.push_str("Nothing recorded.\n");
+    }
+    for (ocean, mut towns) in oceans {
+        towns.sort_by_key(|t| std::cmp::Reverse(t.points));
+        let _ = write!(re, "\n[b]Ocean {ocean}[/b]\n");
+        for town in towns {
+            match town.id {
+                Some(id) => {
+                    let _ = write!(re, "[town]{id}[/town]");
+                }
+                None => re.push_str(&town.name),
+            }
+            let _ = write!(
+                re,
+                " {} pts {:.0}|{:.0}",
+                thousands(u32::from(town.points)),
+                town.x,
+                town.y
+            );
+            if let Some(player) = &town.player {
+                let _ = write!(re, ", {owner} {}", player_tag(player));
+                if let Some(alliance) = &town.alliance {
+                    let _ = write!(re, " ({})", alliance_tag(alliance));
+                }
+            }
+            if let Some(arrival) = arrival {
+                let _ = write!(
+                    re,
+                    ", arrival in {}",
+                    format_seconds(arrival.seconds_to(town.x, town.y))
+                );
+            }
+            re.push('\n');
+        }
+    }
+    return re;
+}
+
+/// departed players sorted by points. They have no position, so there are no oceans.
+pub fn render_players(title: &str, mut players: Vec<OrmPlayer>) -> String {
+    players.sort_by_key(|p| std::cmp::Reverse(p.points));
+    let mut re = format!("[b]{title}[/b]\n");
+    if players.is_empty() {
+        re.push_str("Nothing recorded.\n");
+    }
+    for player in players {
+        let _ = write!(
+            re,
+            "{} {} pts, {} towns",
+            player_tag(&player.name),
+            thousands(player.points),
+            player.towns
+        );
+        if let Some(alliance) = &player.alliance {
+            let _ = write!(re, " ({})", alliance_tag(alliance));
+        }
+        re.push('\n');
+    }
+    return re;
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    /// events recorded at or after this time, defaults to `hours` ago
+    since: Option<DateTime<Utc>>,
+    /// events recorded before this time
+    until: Option<DateTime<Utc>>,
+    /// how far back events are listed if `since` is missing
+    hours: Option<i64>,
+    /// only events involving the alliance with this name
+    alliance: Option<String>,
+    /// only towns with at least this many points
+    min_points: Option<u16>,
+    /// show the arrival from the town with this id
+    from: Option<u32>,
+}
+
+pub async fn events(
+    Path(file): Path<String>,
+    State(state): State<WebState>,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<ListParams>,
+    Query(travel): Query<TravelParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let table = file.strip_suffix(".txt").unwrap_or(&file).to_string();
+    let since = params.since.unwrap_or_else(|| {
+        Utc::now() - Duration::try_hours(params.hours.unwrap_or(DEFAULT_HOURS)).unwrap_or_default()
+    });
+    let filter = EventFilter {
+        region: Some(region.region()),
+        alliance: params.alliance,
+        since: Some(since),
+        until: params.until,
+        limit: None,
+    };
+    let min_points = params.min_points.unwrap_or(0);
+
+    let text = match table.as_str() {
+        "gs_appeared" | "gs_conquered" => {
+            let (title, owner) = if table == "gs_appeared" {
+                ("New ghost towns", "was owned by")
+            } else {
+                ("Conquered ghost towns", "conquered by")
+            };
+            let arrival = Arrival::from_town(&state, params.from, &travel)?;
+            let gss = query_db(move |conn| queries::gs_filtered(conn, &table, &filter)).await?;
+            let towns = gss
+                .into_iter()
+                .filter(|gs| gs.points >= min_points)
+                .map(TownLine::from)
+                .collect();
+            render_towns(title, owner, towns, arrival.as_ref())
+        }
+        "player_disappeared" => {
+            let players =
+                query_db(move |conn| queries::players_disappeared_filtered(conn, &filter)).await?;
+            render_players("Players that left", players)
+        }
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+    Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], text))
+}
+
+#[derive(Deserialize)]
+pub struct GhostTownParams {
+    /// only ghost towns with at least this many points
+    min_points: Option<u16>,
+    /// show the arrival from the town with this id
+    from: Option<u32>,
+}
+
+/// all ghost towns that currently exist
+#[allow(clippy::unused_async)]
+pub async fn ghost_towns(
+    State(state): State<WebState>,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<GhostTownParams>,
+    Query(travel): Query<TravelParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let arrival = Arrival::from_town(&state, params.from, &travel)?;
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let region = region.region();
+    let min_points = params.min_points.unwrap_or(0);
+    let towns = snapshot
+        .towns
+        .values()
+        .filter(|t| t.player_id.is_none())
+        .filter(|t| t.points >= min_points && region.contains(t.actual_x, t.actual_y))
+        .map(|t| TownLine {
+            id: Some(t.id),
+            name: t.name.clone(),
+            points: t.points,
+            x: t.actual_x,
+            y: t.actual_y,
+            player: None,
+            alliance: None,
+        })
+        .collect();
+    let text = render_towns("Ghost towns", "", towns, arrival.as_ref());
+    Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::travel::Unit;
+
+    fn line(id: Option<u32>, name: &str, points: u16, x: f32, y: f32) -> TownLine {
+        TownLine {
+            id,
+            name: name.to_string(),
+            points,
+            x,
+            y,
+            player: Some(String::from("Achilles")),
+            alliance: Some(String::from("Myrmidons")),
+        }
+    }
+
+    #[test]
+    fn towns_are_grouped_by_ocean_and_sorted_by_points() {
+        let mut ghost = line(Some(3), "Ruins", 900, 455.0, 460.0);
+        ghost.player = None;
+        let towns = vec![
+            line(Some(1), "Troy", 1200, 512.0, 487.0),
+            ghost,
+            // recorded before the ids were stored
+            line(None, "Sparta", 8234, 515.0, 491.0),
+        ];
+        // 50 seconds per field
+        let arrival = Arrival {
+            x: 512.0,
+            y: 491.0,
+            travel: Travel::new(Unit::ColonyShip, Some(125.0), Some(1.0)),
+        };
+        assert_eq!(
+            render_towns("Ghost towns", "was owned by", towns, Some(&arrival)),
+            "[b]Ghost towns[/b]
+
+[b]Ocean 44[/b]
+[town]3[/town] 900 pts 455|460, arrival in 0h 54m
+
+[b]Ocean 54[/b]
+Sparta 8,234 pts 515|491, was owned by [player]Achilles[/player] ([ally]Myrmidons[/ally]), arrival in 0h 3m
+[town]1[/town] 1,200 pts 512|487, was owned by [player]Achilles[/player] ([ally]Myrmidons[/ally]), arrival in 0h 3m
+"
+        );
+        assert_eq!(
+            render_towns("Ghost towns", "owned by", Vec::new(), None),
+            "[b]Ghost towns[/b]\nNothing recorded.\n"
+        );
+    }
+
+    #[test]
+    fn players_are_sorted_by_points() {
+        let player = |name: &str, points, alliance: Option<&str>| OrmPlayer {
+            date: Utc::now(),
+            observed_between: None,
+            player_id: None,
+            alliance_id: None,
+            name: name.to_string(),
+            towns: 3,
+            points,
+            rank: 17,
+            alliance: alliance.map(String::from),
+        };
+        let players = vec![
+            player("Paris", 900, None),
+            player("Hector", 12000, Some("Trojans")),
+        ];
+        assert_eq!(
+            render_players("Players that left", players),
+            "[b]Players that left[/b]
+[player]Hector[/player] 12,000 pts, 3 towns ([ally]Trojans[/ally])
+[player]Paris[/player] 900 pts, 3 towns
+"
+        );
+    }
+}
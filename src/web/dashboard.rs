@@ -0,0 +1,128 @@
+//! The start page, showing the latest events. New events are streamed in from `/events/stream`
+//! and highlighted.
+
+use std::fmt::Write;
+
+use axum::{extract::State, response::Html};
+
+use crate::db::orm::{OrmGS, OrmPlayer};
+
+use super::{html, WebState};
+
+const GS_HEADER: [&str; 6] = ["Date", "Town", "Points", "Position", "Player", "Alliance"];
+const PLAYER_HEADER: [&str; 6] = ["Date", "Player", "Towns", "Points", "Rank", "Alliance"];
+
+/// like `html::table`, but always renders the table, so that the script can add rows to it
+fn live_table(id: &str, header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut re = String::from("<table><thead><tr>");
+    for h in header {
+        let _ = write!(re, "<th>{}</th>", html::escape(h));
+    }
+    let _ = write!(re, r#"</tr></thead><tbody id="{id}">"#);
+    for row in rows {
+        re.push_str("<tr>");
+        for cell in row {
+            let _ = write!(re, "<td>{cell}</td>");
+        }
+        re.push_str("</tr>");
+    }
+    re.push_str("</tbody></table>");
+    return re;
+}
+
+fn gs_rows(gss: &[OrmGS]) -> Vec<Vec<String>> {
+    gss.iter()
+        .map(|gs| {
+            vec![
+                gs.date.format("%Y-%m-%d %H:%M").to_string(),
+                html::escape(&gs.name),
+                gs.points.to_string(),
+                format!("{:.0}|{:.0}", gs.x, gs.y),
+                html::escape(gs.player_name.as_deref().unwrap_or_default()),
+                html::escape(gs.alliance_name.as_deref().unwrap_or_default()),
+            ]
+        })
+        .collect()
+}
+
+fn player_rows(players: &[OrmPlayer]) -> Vec<Vec<String>> {
+    players
+        .iter()
+        .map(|p| {
+            vec![
+                p.date.format("%Y-%m-%d %H:%M").to_string(),
+                html::escape(&p.name),
+                p.towns.to_string(),
+                p.points.to_string(),
+                p.rank.to_string(),
+                html::escape(p.alliance.as_deref().unwrap_or_default()),
+            ]
+        })
+        .collect()
+}
+
+/// prepends streamed events to their table and marks them until they are hovered
+const SCRIPT: &str = r"<script>
+function cell(text) {
+  const td = document.createElement('td');
+  td.textContent = text === null || text === undefined ? '' : text;
+  return td;
+}
+function date(d) {
+  return d.slice(0, 16).replace('T', ' ');
+}
+function row(type, d) {
+  if (type === 'player_disappeared') {
+    return [date(d.date), d.name, d.towns, d.points, d.rank, d.alliance];
+  }
+  return [date(d.date), d.name, d.points, Math.round(d.x) + '|' + Math.round(d.y), d.player_name, d.alliance_name];
+}
+const source = new EventSource('/events/stream?types=gs_appeared,gs_conquered,player_disappeared');
+for (const type of ['gs_appeared', 'gs_conquered', 'player_disappeared']) {
+  source.addEventListener(type, (msg) => {
+    const event = JSON.parse(msg.data);
+    const tr = document.createElement('tr');
+    tr.className = 'new';
+    tr.title = event.summary;
+    tr.addEventListener('mouseenter', () => tr.classList.remove('new'), { once: true });
+    for (const text of row(type, event.data)) {
+      tr.appendChild(cell(text));
+    }
+    document.getElementById(type).prepend(tr);
+  });
+}
+</script>";
+
+#[allow(clippy::unused_async)]
+pub async fn dashboard(State(state): State<WebState>) -> Html<String> {
+    let mut body = String::from(
+        r#"<style>tr.new { background: #fff3b0; }</style>
+<h1>Gregswatch</h1>
+<p><a href="/search">Search</a> · <a href="/map">Map</a> · <a href="/worldmap">World map</a> · <a href="/islands">Islands</a></p>"#,
+    );
+    {
+        let cache = state.cache.lock().unwrap();
+        body.push_str(
+            r#"<h2>New ghost towns <small><a href="/bbcode/gs_appeared">BBCode</a></small></h2>"#,
+        );
+        body.push_str(&live_table(
+            "gs_appeared",
+            &GS_HEADER,
+            &gs_rows(&cache.gs_appeared),
+        ));
+        body.push_str(r#"<h2>Conquered ghost towns <small><a href="/bbcode/gs_conquered">BBCode</a></small></h2>"#);
+        body.push_str(&live_table(
+            "gs_conquered",
+            &GS_HEADER,
+            &gs_rows(&cache.gs_conquered),
+        ));
+        body.push_str(r#"<h2>Players that left <small><a href="/bbcode/player_disappeared">BBCode</a></small></h2>"#);
+        body.push_str(&live_table(
+            "player_disappeared",
+            &PLAYER_HEADER,
+            &player_rows(&cache.players_left),
+        ));
+    }
+    body.push_str(SCRIPT);
+    html::page("Gregswatch", &body)
+}
@@ -0,0 +1,388 @@
+//! Detail pages and json endpoints for single players and alliances. The current stats come from
+//! the latest snapshot, the history from the event tables in the database.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    db::{
+        orm::{OrmAllianceChange, OrmGS, OrmPlayer, OrmRename},
+        queries,
+    },
+    model::database::{DataTable, Town},
+};
+
+use super::{html, query_db, WebState};
+
+#[derive(Serialize)]
+pub struct TownInfo {
+    pub id: u32,
+    pub name: String,
+    pub points: u16,
+    pub x: f32,
+    pub y: f32,
+    pub ocean: u16,
+}
+
+impl From<&Town> for TownInfo {
+    fn from(town: &Town) -> Self {
+        Self {
+            id: town.id,
+            name: town.name.clone(),
+            points: town.points,
+            x: town.actual_x,
+            y: town.actual_y,
+            ocean: town.ocean(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MemberInfo {
+    pub id: u32,
+    pub name: String,
+    pub points: u32,
+    pub rank: u16,
+    pub towns: u16,
+}
+
+#[derive(Serialize)]
+pub struct PlayerDetails {
+    pub id: u32,
+    pub name: String,
+    pub alliance_id: Option<u32>,
+    pub alliance_name: Option<String>,
+    pub points: u32,
+    pub rank: u16,
+    pub towns_count: u16,
+    /// time at which the snapshot with the current stats was loaded
+    pub loaded: DateTime<Utc>,
+    pub towns: Vec<TownInfo>,
+    pub name_history: Vec<OrmRename>,
+    /// towns of this player that turned into ghost towns
+    pub ghost_towns: Vec<OrmGS>,
+    /// ghost towns this player conquered
+    pub conquered_ghost_towns: Vec<OrmGS>,
+    pub alliance_changes: Vec<OrmAllianceChange>,
+}
+
+#[derive(Serialize)]
+pub struct AllianceDetails {
+    pub id: u32,
+    pub name: String,
+    pub points: u32,
+    pub towns_count: u32,
+    pub members_count: u16,
+    pub rank: u16,
+    /// time at which the snapshot with the current stats was loaded
+    pub loaded: DateTime<Utc>,
+    pub members: Vec<MemberInfo>,
+    pub towns: Vec<TownInfo>,
+    pub name_history: Vec<OrmRename>,
+    /// towns of members that turned into ghost towns
+    pub ghost_towns: Vec<OrmGS>,
+    /// ghost towns conquered by members
+    pub conquered_ghost_towns: Vec<OrmGS>,
+    /// players that joined or left the alliance
+    pub member_changes: Vec<OrmAllianceChange>,
+    /// members that left the game
+    pub players_disappeared: Vec<OrmPlayer>,
+}
+
+/// the current name followed by all previous names, without duplicates
+fn all_names(current: &str, history: &[OrmRename]) -> Vec<String> {
+    let mut names = vec![current.to_string()];
+    for rename in history {
+        for name in [&rename.old_name, &rename.new_name] {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    return names;
+}
+
+fn sorted_towns<'a>(towns: impl Iterator<Item = &'a Town>) -> Vec<TownInfo> {
+    let mut re: Vec<TownInfo> = towns.map(TownInfo::from).collect();
+    re.sort_by(|a, b| b.points.cmp(&a.points).then(a.id.cmp(&b.id)));
+    return re;
+}
+
+async fn player_details(state: &WebState, id: u32) -> Result<PlayerDetails, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let player = snapshot.players.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+    let current_name = player.name.clone();
+
+    let (name_history, ghost_towns, conquered_ghost_towns, alliance_changes) =
+        query_db(move |conn| {
+            let name_history = queries::renames(conn, "player_renamed", id)?;
+            let names = all_names(&current_name, &name_history);
+            let ghost_towns = queries::gs_involving(conn, "gs_appeared", "player", &names)?;
+            let conquered = queries::gs_involving(conn, "gs_conquered", "player", &names)?;
+            let alliance_changes = queries::alliance_changes_of_player(conn, id)?;
+            Ok((name_history, ghost_towns, conquered, alliance_changes))
+        })
+        .await?;
+
+    return Ok(PlayerDetails {
+        id,
+        name: player.name.clone(),
+        alliance_id: player.alliance_id,
+        alliance_name: alliance_name(&snapshot, player.alliance_id),
+        points: player.points,
+        rank: player.rank,
+        towns_count: player.towns,
+        loaded: snapshot.loaded,
+        towns: sorted_towns(snapshot.towns.values().filter(|t| t.player_id == Some(id))),
+        name_history,
+        ghost_towns,
+        conquered_ghost_towns,
+        alliance_changes,
+    });
+}
+
+async fn alliance_details(state: &WebState, id: u32) -> Result<AllianceDetails, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let alliance = snapshot.alliances.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+    let current_name = alliance.name.clone();
+
+    let (name_history, ghost_towns, conquered_ghost_towns, member_changes, players_disappeared) =
+        query_db(move |conn| {
+            let name_history = queries::renames(conn, "alliance_renamed", id)?;
+            let names = all_names(&current_name, &name_history);
+            let ghost_towns = queries::gs_involving(conn, "gs_appeared", "alliance", &names)?;
+            let conquered = queries::gs_involving(conn, "gs_conquered", "alliance", &names)?;
+            let member_changes = queries::alliance_changes_of_alliance(conn, id)?;
+            let disappeared = queries::players_disappeared_from_alliance(conn, &names)?;
+            Ok((
+                name_history,
+                ghost_towns,
+                conquered,
+                member_changes,
+                disappeared,
+            ))
+        })
+        .await?;
+
+    let mut members: Vec<MemberInfo> = snapshot
+        .players
+        .values()
+        .filter(|p| p.alliance_id == Some(id))
+        .map(|p| MemberInfo {
+            id: p.id,
+            name: p.name.clone(),
+            points: p.points,
+            rank: p.rank,
+            towns: p.towns,
+        })
+        .collect();
+    members.sort_by_key(|m| m.rank);
+
+    let towns = sorted_towns(snapshot.towns.values().filter(|t| {
+        t.player_id
+            .and_then(|pid| snapshot.players.get(&pid))
+            .is_some_and(|p| p.alliance_id == Some(id))
+    }));
+
+    return Ok(AllianceDetails {
+        id,
+        name: alliance.name.clone(),
+        points: alliance.points,
+        towns_count: alliance.towns,
+        members_count: alliance.members,
+        rank: alliance.rank,
+        loaded: snapshot.loaded,
+        members,
+        towns,
+        name_history,
+        ghost_towns,
+        conquered_ghost_towns,
+        member_changes,
+        players_disappeared,
+    });
+}
+
+fn alliance_name(snapshot: &DataTable, alliance_id: Option<u32>) -> Option<String> {
+    alliance_id
+        .and_then(|id| snapshot.alliances.get(&id))
+        .map(|a| a.name.clone())
+}
+
+pub async fn api_player(
+    Path(id): Path<u32>,
+    State(state): State<WebState>,
+) -> Result<Json<PlayerDetails>, StatusCode> {
+    player_details(&state, id).await.map(Json)
+}
+
+pub async fn api_alliance(
+    Path(id): Path<u32>,
+    State(state): State<WebState>,
+) -> Result<Json<AllianceDetails>, StatusCode> {
+    alliance_details(&state, id).await.map(Json)
+}
+
+pub async fn player_page(
+    Path(id): Path<u32>,
+    State(state): State<WebState>,
+) -> Result<Html<String>, StatusCode> {
+    let details = player_details(&state, id).await?;
+
+    let alliance = match (details.alliance_id, &details.alliance_name) {
+        (Some(id), Some(name)) => html::alliance_link(id, name),
+        _ => String::from("-"),
+    };
+    let body = format!(
+        r#"<h1>{name}</h1>
+<p>Alliance: {alliance} / Points: {points} / Rank: {rank} / Towns: {towns_count} (as of {loaded})</p>
+<p><a href="/api/player/{id}">json</a></p>
+<h2>Towns</h2>{towns}
+<h2>Name history</h2>{name_history}
+<h2>Alliance changes</h2>{alliance_changes}
+<h2>Towns that turned into ghost towns</h2>{ghost_towns}
+<h2>Conquered ghost towns</h2>{conquered}"#,
+        name = html::escape(&details.name),
+        points = details.points,
+        rank = details.rank,
+        towns_count = details.towns_count,
+        loaded = details.loaded,
+        towns = towns_table(&details.towns),
+        name_history = renames_table(&details.name_history),
+        alliance_changes = alliance_changes_table(&details.alliance_changes),
+        ghost_towns = gs_table(&details.ghost_towns),
+        conquered = gs_table(&details.conquered_ghost_towns),
+    );
+    Ok(html::page(&details.name, &body))
+}
+
+pub async fn alliance_page(
+    Path(id): Path<u32>,
+    State(state): State<WebState>,
+) -> Result<Html<String>, StatusCode> {
+    let details = alliance_details(&state, id).await?;
+
+    let members: Vec<_> = details
+        .members
+        .iter()
+        .map(|m| {
+            vec![
+                html::player_link(m.id, &m.name),
+                m.points.to_string(),
+                m.rank.to_string(),
+                m.towns.to_string(),
+            ]
+        })
+        .collect();
+    let disappeared: Vec<_> = details
+        .players_disappeared
+        .iter()
+        .map(|p| {
+            vec![
+                p.date.to_string(),
+                html::escape(&p.name),
+                p.points.to_string(),
+                p.towns.to_string(),
+            ]
+        })
+        .collect();
+    let body = format!(
+        r#"<h1>{name}</h1>
+<p>Points: {points} / Rank: {rank} / Members: {members_count} / Towns: {towns_count} (as of {loaded})</p>
+<p><a href="/api/alliance/{id}">json</a></p>
+<h2>Members</h2>{members}
+<h2>Name history</h2>{name_history}
+<h2>Member changes</h2>{member_changes}
+<h2>Members that left the game</h2>{disappeared}
+<h2>Towns of members that turned into ghost towns</h2>{ghost_towns}
+<h2>Ghost towns conquered by members</h2>{conquered}
+<h2>Towns</h2>{towns}"#,
+        name = html::escape(&details.name),
+        points = details.points,
+        rank = details.rank,
+        members_count = details.members_count,
+        towns_count = details.towns_count,
+        loaded = details.loaded,
+        members = html::table(&["Name", "Points", "Rank", "Towns"], &members),
+        name_history = renames_table(&details.name_history),
+        member_changes = alliance_changes_table(&details.member_changes),
+        disappeared = html::table(&["Date", "Name", "Points", "Towns"], &disappeared),
+        ghost_towns = gs_table(&details.ghost_towns),
+        conquered = gs_table(&details.conquered_ghost_towns),
+        towns = towns_table(&details.towns),
+    );
+    Ok(html::page(&details.name, &body))
+}
+
+fn towns_table(towns: &[TownInfo]) -> String {
+    let rows: Vec<_> = towns
+        .iter()
+        .map(|t| {
+            vec![
+                html::escape(&t.name),
+                t.points.to_string(),
+                format!("{:.0}|{:.0}", t.x, t.y),
+                t.ocean.to_string(),
+            ]
+        })
+        .collect();
+    html::table(&["Name", "Points", "Position", "Ocean"], &rows)
+}
+
+fn renames_table(renames: &[OrmRename]) -> String {
+    let rows: Vec<_> = renames
+        .iter()
+        .map(|r| {
+            vec![
+                r.date.to_string(),
+                html::escape(&r.old_name),
+                html::escape(&r.new_name),
+            ]
+        })
+        .collect();
+    html::table(&["Date", "Old name", "New name"], &rows)
+}
+
+fn alliance_changes_table(changes: &[OrmAllianceChange]) -> String {
+    let link = |id: Option<u32>, name: &Option<String>| match (id, name) {
+        (Some(id), Some(name)) => html::alliance_link(id, name),
+        _ => String::from("-"),
+    };
+    let rows: Vec<_> = changes
+        .iter()
+        .map(|c| {
+            vec![
+                c.date.to_string(),
+                html::player_link(c.player_id, &c.player_name),
+                link(c.old_alliance_id, &c.old_alliance_name),
+                link(c.new_alliance_id, &c.new_alliance_name),
+            ]
+        })
+        .collect();
+    html::table(&["Date", "Player", "Old alliance", "New alliance"], &rows)
+}
+
+fn gs_table(gss: &[OrmGS]) -> String {
+    let rows: Vec<_> = gss
+        .iter()
+        .map(|gs| {
+            vec![
+                gs.date.to_string(),
+                html::escape(&gs.name),
+                gs.points.to_string(),
+                format!("{:.0}|{:.0}", gs.x, gs.y),
+                html::escape(gs.player_name.as_deref().unwrap_or("-")),
+                html::escape(gs.alliance_name.as_deref().unwrap_or("-")),
+            ]
+        })
+        .collect();
+    html::table(
+        &["Date", "Name", "Points", "Position", "Player", "Alliance"],
+        &rows,
+    )
+}
@@ -0,0 +1,91 @@
+//! Csv exports of the event tables, streamed straight from the database.
+
+use std::io;
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::error;
+
+use crate::db::{
+    self,
+    queries::{self, EventFilter},
+};
+
+use super::RegionParams;
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    /// only events recorded at or after this time
+    since: Option<DateTime<Utc>>,
+    /// only events recorded before this time
+    until: Option<DateTime<Utc>>,
+    /// only events involving the alliance with this name
+    alliance: Option<String>,
+}
+
+/// hands everything written to it to the response body. Fails once the client went away, which
+/// ends the export.
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub async fn export(
+    Path(file): Path<String>,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let name = file.strip_suffix(".csv").ok_or(StatusCode::NOT_FOUND)?;
+    let table = queries::event_table(name).ok_or(StatusCode::NOT_FOUND)?;
+    let filter = EventFilter {
+        region: Some(region.region()),
+        alliance: params.alliance,
+        since: params.since,
+        until: params.until,
+        limit: None,
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let res = db::open_read_only()
+            .map_err(anyhow::Error::from)
+            .and_then(|conn| queries::export_csv(&conn, table, &filter, ChannelWriter(tx.clone())));
+        if let Err(err) = res {
+            error!("Failed to export {}: {err:?}", table.name);
+            // cut the response short, so that the client notices the export is incomplete
+            let _ = tx.blocking_send(Err(io::Error::other(err.to_string())));
+        }
+    });
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                String::from("text/csv; charset=utf-8"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(r#"attachment; filename="{}.csv""#, table.name),
+            ),
+        ],
+        StreamBody::new(ReceiverStream::new(rx)),
+    ))
+}
@@ -0,0 +1,357 @@
+//! Atom feeds of the ghost town and player events, so that members can subscribe with their feed
+//! reader. Every feed accepts the region filter of the map and an `alliance` name.
+
+use std::fmt::Write;
+
+use axum::{
+    extract::{Path, Query, RawQuery},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+
+use crate::{
+    db::{
+        orm::{OrmGS, OrmPlayer},
+        queries::{self, EventFilter},
+        watchlist,
+    },
+    model::region::ocean_at,
+};
+
+use super::{auth::Auth, html::escape, query_db, RegionParams};
+
+const CONTENT_TYPE: &str = "application/atom+xml; charset=utf-8";
+/// number of entries in a feed
+const FEED_LENGTH: usize = 50;
+
+#[derive(Deserialize)]
+pub struct FeedParams {
+    /// only events involving an alliance with this name
+    alliance: Option<String>,
+}
+
+struct Entry {
+    id: String,
+    title: String,
+    updated: DateTime<Utc>,
+    content: String,
+}
+
+fn timestamp(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// `<owner> [<alliance>]`, or the given fallback if there is no owner
+fn owner(player: Option<&str>, alliance: Option<&str>, fallback: &str) -> String {
+    match (player, alliance) {
+        (Some(player), Some(alliance)) => format!("{player} [{alliance}]"),
+        (Some(player), None) => player.to_string(),
+        (None, _) => fallback.to_string(),
+    }
+}
+
+fn render(
+    feed: &str,
+    title: &str,
+    headers: &HeaderMap,
+    query: Option<&str>,
+    entries: &[Entry],
+) -> impl IntoResponse {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost:10204");
+    let xml = atom(feed, title, host, query, entries);
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], xml)
+}
+
+fn atom(feed: &str, title: &str, host: &str, query: Option<&str>, entries: &[Entry]) -> String {
+    let query = query.map(|q| format!("?{q}")).unwrap_or_default();
+    // the feed is identified by its filters, the entries by their event
+    let feed_id = format!("tag:gregswatch,2024:feeds/{feed}{query}");
+    let updated = entries
+        .iter()
+        .map(|e| e.updated)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>{title}</title>
+  <id>{feed_id}</id>
+  <updated>{updated}</updated>
+  <link rel="self" href="http://{host}/feeds/{feed}.atom{query}"/>
+  <author><name>gregswatch</name></author>
+"#,
+        title = escape(title),
+        feed_id = escape(&feed_id),
+        updated = timestamp(updated),
+        host = escape(host),
+        query = escape(&query),
+    );
+    for entry in entries {
+        let _ = write!(
+            xml,
+            r#"  <entry>
+    <title>{}</title>
+    <id>{}</id>
+    <updated>{}</updated>
+    <content type="text">{}</content>
+  </entry>
+"#,
+            escape(&entry.title),
+            escape(&entry.id),
+            timestamp(entry.updated),
+            escape(&entry.content),
+        );
+    }
+    xml.push_str("</feed>\n");
+    return xml;
+}
+
+fn gs_appeared_entry(gs: &OrmGS) -> Entry {
+    Entry {
+        id: format!(
+            "tag:gregswatch,2024:gs_appeared/{}/{}/{}",
+            timestamp(gs.date),
+            gs.x,
+            gs.y
+        ),
+        title: format!(
+            "New ghost town {} ({} points) at {:.0}|{:.0}",
+            gs.name, gs.points, gs.x, gs.y
+        ),
+        updated: gs.date,
+        content: format!(
+            "{} of {} turned into a ghost town. It has {} points and lies in ocean {} at {:.0}|{:.0}.",
+            gs.name,
+            owner(gs.player_name.as_deref(), gs.alliance_name.as_deref(), "an unknown player"),
+            gs.points,
+            ocean_at(gs.x, gs.y),
+            gs.x,
+            gs.y
+        ),
+    }
+}
+
+fn gs_conquered_entry(gs: &OrmGS) -> Entry {
+    let conqueror = owner(
+        gs.player_name.as_deref(),
+        gs.alliance_name.as_deref(),
+        "an unknown player",
+    );
+    Entry {
+        id: format!(
+            "tag:gregswatch,2024:gs_conquered/{}/{}/{}",
+            timestamp(gs.date),
+            gs.x,
+            gs.y
+        ),
+        title: format!(
+            "Ghost town {} at {:.0}|{:.0} conquered by {conqueror}",
+            gs.name, gs.x, gs.y
+        ),
+        updated: gs.date,
+        content: format!(
+            "{conqueror} conquered the ghost town {} ({} points) in ocean {} at {:.0}|{:.0}.",
+            gs.name,
+            gs.points,
+            ocean_at(gs.x, gs.y),
+            gs.x,
+            gs.y
+        ),
+    }
+}
+
+/// identified by the id of the player, or by its name for players recorded before the ids were
+/// stored. The name is percent encoded, it may contain anything.
+fn player_entry(p: &OrmPlayer) -> Entry {
+    let player = owner(Some(&p.name), p.alliance.as_deref(), "");
+    let who = p.player_id.map_or_else(
+        || form_urlencoded::byte_serialize(p.name.as_bytes()).collect(),
+        |id| id.to_string(),
+    );
+    Entry {
+        id: format!(
+            "tag:gregswatch,2024:player_disappeared/{}/{who}",
+            timestamp(p.date),
+        ),
+        title: format!("{player} left the game"),
+        updated: p.date,
+        content: format!(
+            "{player} left the game with {} points and {} towns, rank {}.",
+            p.points, p.towns, p.rank
+        ),
+    }
+}
+
+fn filter(region: &RegionParams, params: FeedParams) -> EventFilter {
+    EventFilter {
+        region: Some(region.region()),
+        alliance: params.alliance,
+        limit: Some(FEED_LENGTH),
+        ..Default::default()
+    }
+}
+
+pub async fn gs_appeared(
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<FeedParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let filter = filter(&region, params);
+    let events = query_db(move |conn| queries::gs_filtered(conn, "gs_appeared", &filter)).await?;
+    let entries: Vec<_> = events
+        .into_iter()
+        .map(|gs| gs_appeared_entry(&gs))
+        .collect();
+    Ok(render(
+        "gs_appeared",
+        "New ghost towns",
+        &headers,
+        query.as_deref(),
+        &entries,
+    ))
+}
+
+pub async fn gs_conquered(
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<FeedParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let filter = filter(&region, params);
+    let events = query_db(move |conn| queries::gs_filtered(conn, "gs_conquered", &filter)).await?;
+    let entries: Vec<_> = events
+        .into_iter()
+        .map(|gs| gs_conquered_entry(&gs))
+        .collect();
+    Ok(render(
+        "gs_conquered",
+        "Conquered ghost towns",
+        &headers,
+        query.as_deref(),
+        &entries,
+    ))
+}
+
+/// departed players have no position, so only the alliance filter applies to this feed
+pub async fn players_disappeared(
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<FeedParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let filter = filter(&region, params);
+    let events = query_db(move |conn| queries::players_disappeared_filtered(conn, &filter)).await?;
+    let entries: Vec<_> = events.into_iter().map(|p| player_entry(&p)).collect();
+    Ok(render(
+        "player_disappeared",
+        "Players that left the game",
+        &headers,
+        query.as_deref(),
+        &entries,
+    ))
+}
+
+/// the personal feed of a watchlist, every event that involves one of its players, alliances or
+/// regions. Feed readers pass their token as `?token=`.
+pub async fn watchlist(
+    auth: Auth,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    let id: i64 = file
+        .strip_suffix(".atom")
+        .unwrap_or(&file)
+        .parse()
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let (watchlist, events) = query_db(move |conn| {
+        Ok((
+            watchlist::get(conn, id)?,
+            watchlist::events(conn, id, FEED_LENGTH)?,
+        ))
+    })
+    .await?;
+    let watchlist = watchlist
+        .filter(|w| auth.0.can_see(w.alliance_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let entries: Vec<_> = events
+        .into_iter()
+        .map(|e| Entry {
+            id: format!("tag:gregswatch,2024:watchlist/{id}/{}", e.id),
+            title: e.summary.clone(),
+            updated: e.date,
+            content: e.summary,
+        })
+        .collect();
+    Ok(render(
+        &format!("watchlist/{id}"),
+        &watchlist.name,
+        &headers,
+        None,
+        &entries,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date() -> DateTime<Utc> {
+        "2024-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn departed(player_id: Option<u32>, name: &str) -> OrmPlayer {
+        OrmPlayer {
+            date: date(),
+            observed_between: None,
+            player_id,
+            alliance_id: None,
+            name: name.to_string(),
+            towns: 3,
+            points: 12000,
+            rank: 17,
+            alliance: Some(String::from("Troy & Co")),
+        }
+    }
+
+    #[test]
+    fn entry_ids_are_valid_tags() {
+        let entry = player_entry(&departed(Some(4), "Hector of Troy"));
+        assert_eq!(
+            entry.id,
+            "tag:gregswatch,2024:player_disappeared/2024-03-01T12:00:00Z/4"
+        );
+        assert_eq!(entry.title, "Hector of Troy [Troy & Co] left the game");
+        // players recorded before the ids have only their name
+        let entry = player_entry(&departed(None, "Hector <of> Troy/ä"));
+        assert_eq!(
+            entry.id,
+            "tag:gregswatch,2024:player_disappeared/2024-03-01T12:00:00Z/Hector+%3Cof%3E+Troy%2F%C3%A4"
+        );
+    }
+
+    #[test]
+    fn feeds_are_escaped() {
+        let entries = [player_entry(&departed(Some(4), "<Hector>"))];
+        let xml = atom(
+            "player_disappeared",
+            "Players & towns",
+            "example.com",
+            Some("alliance=Troy&ocean=55"),
+            &entries,
+        );
+        assert!(xml.contains("<title>Players &amp; towns</title>"));
+        assert!(xml.contains(
+            r#"href="http://example.com/feeds/player_disappeared.atom?alliance=Troy&amp;ocean=55""#
+        ));
+        assert!(xml.contains("<title>&lt;Hector&gt; [Troy &amp; Co] left the game</title>"));
+        assert!(xml.contains("<updated>2024-03-01T12:00:00Z</updated>"));
+        assert_eq!(xml.matches("<entry>").count(), 1);
+    }
+}
@@ -0,0 +1,190 @@
+//! `GeoJSON` exports of the current towns and islands and of the ghost town events, for use in
+//! QGIS and the like. Coordinates are the in-game coordinates, x to the east and y to the south,
+//! towns use the same position that is shown everywhere else (`Town::actual_x`/`actual_y`).
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{orm::OrmGS, queries};
+
+use super::{query_db, RegionParams, WebState};
+
+const CONTENT_TYPE: &str = "application/geo+json";
+
+#[derive(Serialize)]
+pub struct FeatureCollection<P> {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    features: Vec<Feature<P>>,
+}
+
+impl<P> FromIterator<Feature<P>> for FeatureCollection<P> {
+    fn from_iter<T: IntoIterator<Item = Feature<P>>>(iter: T) -> Self {
+        Self {
+            typ: "FeatureCollection",
+            features: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Feature<P> {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    geometry: Point,
+    properties: P,
+}
+
+impl<P> Feature<P> {
+    fn point(x: f32, y: f32, properties: P) -> Self {
+        Self {
+            typ: "Feature",
+            geometry: Point {
+                typ: "Point",
+                coordinates: [x, y],
+            },
+            properties,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Point {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    coordinates: [f32; 2],
+}
+
+#[derive(Serialize)]
+pub struct TownProperties {
+    id: u32,
+    name: String,
+    points: u16,
+    ocean: u16,
+    island_x: u16,
+    island_y: u16,
+    slot_number: u8,
+    ghost_town: bool,
+    player_id: Option<u32>,
+    player: Option<String>,
+    alliance_id: Option<u32>,
+    alliance: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IslandProperties {
+    id: u32,
+    typ: u8,
+    towns: u8,
+    ressource_plus: String,
+    ressource_minus: String,
+}
+
+#[derive(Deserialize)]
+pub struct EventParams {
+    /// only events recorded at or after this time
+    since: Option<DateTime<Utc>>,
+}
+
+#[allow(clippy::unused_async)]
+pub async fn towns(
+    State(state): State<WebState>,
+    Query(region): Query<RegionParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let region = region.region();
+    let collection: FeatureCollection<_> = snapshot
+        .towns
+        .values()
+        .filter(|t| region.contains(t.actual_x, t.actual_y))
+        .map(|town| {
+            let player = town.player_id.and_then(|id| snapshot.players.get(&id));
+            let alliance = player
+                .and_then(|p| p.alliance_id)
+                .and_then(|id| snapshot.alliances.get(&id));
+            Feature::point(
+                town.actual_x,
+                town.actual_y,
+                TownProperties {
+                    id: town.id,
+                    name: town.name.clone(),
+                    points: town.points,
+                    ocean: town.ocean(),
+                    island_x: town.island_xy.0,
+                    island_y: town.island_xy.1,
+                    slot_number: town.offset_slotnumber,
+                    ghost_town: town.player_id.is_none(),
+                    player_id: player.map(|p| p.id),
+                    player: player.map(|p| p.name.clone()),
+                    alliance_id: alliance.map(|a| a.id),
+                    alliance: alliance.map(|a| a.name.clone()),
+                },
+            )
+        })
+        .collect();
+    Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], Json(collection)))
+}
+
+#[allow(clippy::unused_async)]
+pub async fn islands(
+    State(state): State<WebState>,
+    Query(region): Query<RegionParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let region = region.region();
+    let collection: FeatureCollection<_> = snapshot
+        .islands
+        .values()
+        .filter(|i| region.contains(f32::from(i.x), f32::from(i.y)))
+        .map(|island| {
+            Feature::point(
+                f32::from(island.x),
+                f32::from(island.y),
+                IslandProperties {
+                    id: island.id,
+                    typ: island.typ,
+                    towns: island.towns,
+                    ressource_plus: island.ressource_plus.clone(),
+                    ressource_minus: island.ressource_minus.clone(),
+                },
+            )
+        })
+        .collect();
+    Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], Json(collection)))
+}
+
+async fn gs_events(
+    table: &'static str,
+    region: &RegionParams,
+    params: &EventParams,
+) -> Result<impl IntoResponse, StatusCode> {
+    let region = region.region();
+    let since = params.since.unwrap_or_default();
+    let events = query_db(move |conn| queries::gs_since(conn, table, since)).await?;
+    let collection: FeatureCollection<OrmGS> = events
+        .into_iter()
+        .filter(|gs| region.contains(gs.x, gs.y))
+        .map(|gs| Feature::point(gs.x, gs.y, gs))
+        .collect();
+    Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], Json(collection)))
+}
+
+pub async fn gs_appeared(
+    Query(region): Query<RegionParams>,
+    Query(params): Query<EventParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    gs_events("gs_appeared", &region, &params).await
+}
+
+pub async fn gs_conquered(
+    Query(region): Query<RegionParams>,
+    Query(params): Query<EventParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    gs_events("gs_conquered", &region, &params).await
+}
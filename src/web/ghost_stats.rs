@@ -0,0 +1,123 @@
+//! How long ghost towns last before they are conquered and who conquers them.
+
+use std::fmt::Write;
+
+use axum::{extract::Query, http::StatusCode, response::Html, Json};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::db::ghost_stats::{self, Conqueror, GhostTownStats, Lifetimes, BUCKET_HOURS};
+
+use super::{html, query_db};
+
+const DEFAULT_LIMIT: usize = 20;
+
+#[derive(Deserialize)]
+pub struct StatsParams {
+    /// only conquests recorded at or after this time
+    since: Option<DateTime<Utc>>,
+    /// the number of players and alliances in the rankings
+    limit: Option<usize>,
+}
+
+async fn stats(params: StatsParams) -> Result<GhostTownStats, StatusCode> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    query_db(move |conn| ghost_stats::ghost_town_stats(conn, params.since, limit)).await
+}
+
+pub async fn api_ghost_towns(
+    Query(params): Query<StatsParams>,
+) -> Result<Json<GhostTownStats>, StatusCode> {
+    Ok(Json(stats(params).await?))
+}
+
+fn hours(hours: Option<f64>) -> String {
+    hours.map_or_else(String::new, |h| format!("{h:.1}"))
+}
+
+fn lifetime_row(label: String, lifetimes: &Lifetimes) -> Vec<String> {
+    let mut row = vec![
+        label,
+        lifetimes.count.to_string(),
+        hours(lifetimes.average_hours),
+        hours(lifetimes.median_hours),
+    ];
+    row.extend(lifetimes.buckets.iter().map(ToString::to_string));
+    return row;
+}
+
+fn conqueror_rows(conquerors: &[Conqueror], link: fn(u32, &str) -> String) -> Vec<Vec<String>> {
+    conquerors
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            vec![
+                (i + 1).to_string(),
+                c.id.map_or_else(|| html::escape(&c.name), |id| link(id, &c.name)),
+                c.conquests.to_string(),
+                c.points.to_string(),
+                hours(c.average_ghost_hours),
+            ]
+        })
+        .collect()
+}
+
+pub async fn ghost_towns_page(
+    Query(params): Query<StatsParams>,
+) -> Result<Html<String>, StatusCode> {
+    let stats = stats(params).await?;
+
+    let mut body = String::from("<h1>Ghost towns</h1>");
+    if let Some(since) = stats.since {
+        let _ = write!(
+            body,
+            "<p>Conquests since {}.</p>",
+            since.format("%Y-%m-%d %H:%M")
+        );
+    }
+    let _ = write!(
+        body,
+        "<p>{} conquered ghost towns could not be linked to their appearance and are not part of the lifetimes.</p>",
+        stats.unlinked
+    );
+
+    // the buckets are named by their upper bound
+    let mut bounds = String::from("0h");
+    let mut bucket_names = Vec::new();
+    for h in BUCKET_HOURS {
+        let bound = if h % 24 == 0 {
+            format!("{}d", h / 24)
+        } else {
+            format!("{h}h")
+        };
+        bucket_names.push(format!("{bounds}-{bound}"));
+        bounds = bound;
+    }
+    bucket_names.push(format!("> {bounds}"));
+    let mut header = vec!["Ocean", "Conquered", "Average hours", "Median hours"];
+    header.extend(bucket_names.iter().map(String::as_str));
+
+    body.push_str("<h2>Time until conquest</h2>");
+    let mut rows = vec![lifetime_row(String::from("all"), &stats.overall)];
+    rows.extend(
+        stats
+            .oceans
+            .iter()
+            .map(|(ocean, lifetimes)| lifetime_row(ocean.to_string(), lifetimes)),
+    );
+    body.push_str(&html::table(&header, &rows));
+
+    let header = ["#", "Name", "Conquered", "Points", "Average ghost hours"];
+    body.push_str("<h2>Top players</h2>");
+    body.push_str(&html::table(
+        &header,
+        &conqueror_rows(&stats.top_players, html::player_link),
+    ));
+    body.push_str("<h2>Top alliances</h2>");
+    body.push_str(&html::table(
+        &header,
+        &conqueror_rows(&stats.top_alliances, html::alliance_link),
+    ));
+
+    Ok(html::page("Ghost towns", &body))
+}
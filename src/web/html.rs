@@ -0,0 +1,79 @@
+//! Small helpers to render the html pages. The pages are deliberately plain, they are built with
+//! `format!` and contain no scripts unless a page needs one.
+
+use std::fmt::Write;
+
+use axum::response::Html;
+
+/// escape text for use inside html elements and attribute values
+pub fn escape(text: &str) -> String {
+    let mut re = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => re.push_str("&amp;"),
+            '<' => re.push_str("&lt;"),
+            '>' => re.push_str("&gt;"),
+            '"' => re.push_str("&quot;"),
+            '\'' => re.push_str("&#39;"),
+            _ => re.push(c),
+        }
+    }
+    return re;
+}
+
+/// wrap the given body into a full html page
+pub fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        r#"<!doctype html>
+<html>
+<head>
+  <meta charset="UTF-8" />
+  <title>{title}</title>
+  <style>
+    body {{
+      max-width: 60rem;
+      margin: auto;
+      font-family: 'Lucida Sans', 'Lucida Sans Regular', 'Lucida Grande', 'Lucida Sans Unicode', Geneva, Verdana, sans-serif;
+    }}
+    table {{ width: 100%; border-collapse: collapse; }}
+    td, th {{ padding: 0.25rem 0.75rem; text-align: left; border-bottom: 1px solid #ddd; }}
+  </style>
+</head>
+<body>
+{body}
+</body>
+</html>"#,
+        title = escape(title),
+    ))
+}
+
+/// render a table with the given header and rows. The cells are expected to be escaped already.
+pub fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    if rows.is_empty() {
+        return String::from("<p>Nothing recorded.</p>");
+    }
+    let mut re = String::from("<table><tr>");
+    for h in header {
+        let _ = write!(re, "<th>{}</th>", escape(h));
+    }
+    re.push_str("</tr>");
+    for row in rows {
+        re.push_str("<tr>");
+        for cell in row {
+            let _ = write!(re, "<td>{cell}</td>");
+        }
+        re.push_str("</tr>");
+    }
+    re.push_str("</table>");
+    return re;
+}
+
+/// a link to the page of the player with the given id
+pub fn player_link(id: u32, name: &str) -> String {
+    format!(r#"<a href="/player/{id}">{}</a>"#, escape(name))
+}
+
+/// a link to the page of the alliance with the given id
+pub fn alliance_link(id: u32, name: &str) -> String {
+    format!(r#"<a href="/alliance/{id}">{}</a>"#, escape(name))
+}
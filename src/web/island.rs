@@ -0,0 +1,395 @@
+//! Islands with their resources and town slots: which slots are taken by whom and which are free.
+//! The slots come from `OFFSET_DATA`, so islands without town slots like rocks have none.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::model::{
+    database::{DataTable, Island, Offset, Town},
+    region::ocean_at,
+};
+
+use super::{html, RegionParams, WebState};
+
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Serialize)]
+pub struct SlotTown {
+    pub id: u32,
+    pub name: String,
+    pub points: u16,
+    /// None for ghost towns
+    pub player_id: Option<u32>,
+    pub player: Option<String>,
+    pub alliance_id: Option<u32>,
+    pub alliance: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SlotInfo {
+    pub slot_number: u8,
+    pub x: f32,
+    pub y: f32,
+    /// the town in this slot, None if the slot is free
+    pub town: Option<SlotTown>,
+}
+
+#[derive(Serialize)]
+pub struct IslandDetails {
+    pub id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub ocean: u16,
+    pub typ: u8,
+    pub ressource_plus: String,
+    pub ressource_minus: String,
+    /// the number of towns that can still be founded, as reported by the game
+    pub available_towns: u8,
+    pub free_slots: usize,
+    pub ghost_towns: usize,
+    /// time at which the snapshot was loaded
+    pub loaded: DateTime<Utc>,
+    pub slots: Vec<SlotInfo>,
+}
+
+#[derive(Serialize)]
+pub struct IslandSummary {
+    pub id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub ocean: u16,
+    pub typ: u8,
+    pub ressource_plus: String,
+    pub ressource_minus: String,
+    pub available_towns: u8,
+    pub slots: usize,
+    pub free_slots: usize,
+    pub ghost_towns: usize,
+    pub ghost_town_points: u32,
+}
+
+#[derive(Deserialize)]
+pub struct IslandListParams {
+    /// only islands with at least this many free slots, 1 by default
+    min_free: Option<usize>,
+    /// only islands with a ghost town on them
+    #[serde(default)]
+    ghost_towns: bool,
+    limit: Option<usize>,
+}
+
+fn slot_town(snapshot: &DataTable, town: &Town) -> SlotTown {
+    let player = town.player_id.and_then(|id| snapshot.players.get(&id));
+    let alliance = player
+        .and_then(|p| p.alliance_id)
+        .and_then(|id| snapshot.alliances.get(&id));
+    SlotTown {
+        id: town.id,
+        name: town.name.clone(),
+        points: town.points,
+        player_id: player.map(|p| p.id),
+        player: player.map(|p| p.name.clone()),
+        alliance_id: alliance.map(|a| a.id),
+        alliance: alliance.map(|a| a.name.clone()),
+    }
+}
+
+/// every slot of the island with the town in it, given the towns on the island
+fn slots(
+    snapshot: &DataTable,
+    island: &Island,
+    offsets: Option<&Vec<Offset>>,
+    towns: &[&Town],
+) -> Vec<SlotInfo> {
+    let by_slot: HashMap<u8, &Town> = towns.iter().map(|t| (t.offset_slotnumber, *t)).collect();
+    offsets
+        .map(|offsets| {
+            offsets
+                .iter()
+                .map(|o| {
+                    let (x, y) = o.position(island.x, island.y);
+                    SlotInfo {
+                        slot_number: o.slot_number,
+                        x,
+                        y,
+                        town: by_slot.get(&o.slot_number).map(|t| slot_town(snapshot, t)),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn towns_by_island(snapshot: &DataTable) -> HashMap<(u16, u16), Vec<&Town>> {
+    let mut re: HashMap<(u16, u16), Vec<&Town>> = HashMap::new();
+    for town in snapshot.towns.values() {
+        re.entry(town.island_xy).or_default().push(town);
+    }
+    return re;
+}
+
+fn island_details(state: &WebState, x: u16, y: u16) -> Result<IslandDetails, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let island = snapshot.islands.get(&(x, y)).ok_or(StatusCode::NOT_FOUND)?;
+    let towns: Vec<&Town> = snapshot
+        .towns
+        .values()
+        .filter(|t| t.island_xy == (x, y))
+        .collect();
+    let offsets = DataTable::offsets_by_island_type();
+    let slots = slots(&snapshot, island, offsets.get(&island.typ), &towns);
+    Ok(IslandDetails {
+        id: island.id,
+        x,
+        y,
+        ocean: ocean_at(f32::from(x), f32::from(y)),
+        typ: island.typ,
+        ressource_plus: island.ressource_plus.clone(),
+        ressource_minus: island.ressource_minus.clone(),
+        available_towns: island.towns,
+        free_slots: slots.iter().filter(|s| s.town.is_none()).count(),
+        ghost_towns: towns.iter().filter(|t| t.player_id.is_none()).count(),
+        loaded: snapshot.loaded,
+        slots,
+    })
+}
+
+/// the islands in the region with free slots, those with the most ghost towns and free slots first
+fn island_list(
+    state: &WebState,
+    region: &RegionParams,
+    params: &IslandListParams,
+) -> Result<Vec<IslandSummary>, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let region = region.region();
+    let offsets = DataTable::offsets_by_island_type();
+    let towns = towns_by_island(&snapshot);
+    let min_free = params.min_free.unwrap_or(1);
+    let mut re: Vec<IslandSummary> = snapshot
+        .islands
+        .values()
+        .filter(|i| region.contains(f32::from(i.x), f32::from(i.y)))
+        .filter_map(|island| {
+            let slots = offsets.get(&island.typ).map_or(0, Vec::len);
+            let towns = towns.get(&(island.x, island.y));
+            let towns = towns.map_or(&[][..], Vec::as_slice);
+            let ghosts: Vec<_> = towns.iter().filter(|t| t.player_id.is_none()).collect();
+            let summary = IslandSummary {
+                id: island.id,
+                x: island.x,
+                y: island.y,
+                ocean: ocean_at(f32::from(island.x), f32::from(island.y)),
+                typ: island.typ,
+                ressource_plus: island.ressource_plus.clone(),
+                ressource_minus: island.ressource_minus.clone(),
+                available_towns: island.towns,
+                slots,
+                free_slots: slots.saturating_sub(towns.len()),
+                ghost_towns: ghosts.len(),
+                ghost_town_points: ghosts.iter().map(|t| u32::from(t.points)).sum(),
+            };
+            let wanted =
+                summary.free_slots >= min_free && (!params.ghost_towns || summary.ghost_towns > 0);
+            wanted.then_some(summary)
+        })
+        .collect();
+    re.sort_by(|a, b| {
+        b.ghost_towns
+            .cmp(&a.ghost_towns)
+            .then(b.free_slots.cmp(&a.free_slots))
+            .then(a.id.cmp(&b.id))
+    });
+    re.truncate(params.limit.unwrap_or(DEFAULT_LIMIT));
+    return Ok(re);
+}
+
+#[allow(clippy::unused_async)]
+pub async fn api_island(
+    Path((x, y)): Path<(u16, u16)>,
+    State(state): State<WebState>,
+) -> Result<Json<IslandDetails>, StatusCode> {
+    island_details(&state, x, y).map(Json)
+}
+
+#[allow(clippy::unused_async)]
+pub async fn api_islands(
+    State(state): State<WebState>,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<IslandListParams>,
+) -> Result<Json<Vec<IslandSummary>>, StatusCode> {
+    island_list(&state, &region, &params).map(Json)
+}
+
+fn island_link(x: u16, y: u16) -> String {
+    format!(r#"<a href="/island/{x}/{y}">{x}|{y}</a>"#)
+}
+
+#[allow(clippy::unused_async)]
+pub async fn island_page(
+    Path((x, y)): Path<(u16, u16)>,
+    State(state): State<WebState>,
+) -> Result<Html<String>, StatusCode> {
+    let details = island_details(&state, x, y)?;
+    let rows: Vec<_> = details
+        .slots
+        .iter()
+        .map(|slot| {
+            let position = format!("{:.1}|{:.1}", slot.x, slot.y);
+            let Some(town) = &slot.town else {
+                return vec![slot.slot_number.to_string(), position, String::from("free")];
+            };
+            let owner = match (town.player_id, &town.player) {
+                (Some(id), Some(name)) => html::player_link(id, name),
+                _ => String::from("ghost town"),
+            };
+            let alliance = match (town.alliance_id, &town.alliance) {
+                (Some(id), Some(name)) => format!(" [{}]", html::alliance_link(id, name)),
+                _ => String::new(),
+            };
+            vec![
+                slot.slot_number.to_string(),
+                position,
+                format!(
+                    "{} ({} points) - {owner}{alliance}",
+                    html::escape(&town.name),
+                    town.points
+                ),
+            ]
+        })
+        .collect();
+    let body = format!(
+        r#"<h1>Island {x}|{y}</h1>
+<p>Ocean: {ocean} / Type: {typ} / More {plus}, less {minus} / Free slots: {free} of {slots} / Ghost towns: {ghosts} / Available according to the game: {available} (as of {loaded})</p>
+<p><a href="/api/island/{x}/{y}">json</a> / <a href="/map?x0={x0}&amp;y0={y0}&amp;x1={x1}&amp;y1={y1}">map</a></p>
+<h2>Slots</h2>{slots_table}"#,
+        ocean = details.ocean,
+        typ = details.typ,
+        plus = html::escape(&details.ressource_plus),
+        minus = html::escape(&details.ressource_minus),
+        free = details.free_slots,
+        slots = details.slots.len(),
+        ghosts = details.ghost_towns,
+        available = details.available_towns,
+        loaded = details.loaded,
+        x0 = f32::from(x) - 5.0,
+        y0 = f32::from(y) - 5.0,
+        x1 = f32::from(x) + 15.0,
+        y1 = f32::from(y) + 15.0,
+        slots_table = html::table(&["Slot", "Position", "Town"], &rows),
+    );
+    Ok(html::page(&format!("Island {x}|{y}"), &body))
+}
+
+#[allow(clippy::unused_async)]
+pub async fn islands_page(
+    State(state): State<WebState>,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<IslandListParams>,
+) -> Result<Html<String>, StatusCode> {
+    let islands = island_list(&state, &region, &params)?;
+    let rows: Vec<_> = islands
+        .iter()
+        .map(|i| {
+            vec![
+                island_link(i.x, i.y),
+                i.ocean.to_string(),
+                html::escape(&i.ressource_plus),
+                html::escape(&i.ressource_minus),
+                format!("{} of {}", i.free_slots, i.slots),
+                i.ghost_towns.to_string(),
+                i.ghost_town_points.to_string(),
+            ]
+        })
+        .collect();
+    let body = format!(
+        "<h1>Islands with free slots</h1>
+<p>Restrict the list with <code>ocean=54</code> or <code>x0</code>, <code>y0</code>, <code>x1</code>, <code>y1</code>, only show islands with ghost towns with <code>ghost_towns=true</code>. Islands with the most ghost towns come first.</p>
+{}",
+        html::table(
+            &[
+                "Island",
+                "Ocean",
+                "More",
+                "Less",
+                "Free slots",
+                "Ghost towns",
+                "Ghost town points"
+            ],
+            &rows
+        )
+    );
+    Ok(html::page("Islands", &body))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::model::fixtures::{alliance, player, town, world};
+
+    fn island() -> Island {
+        Island {
+            id: 1,
+            x: 512,
+            y: 487,
+            typ: 3,
+            towns: 1,
+            ressource_plus: String::from("wood"),
+            ressource_minus: String::from("iron"),
+        }
+    }
+
+    fn offset(slot_number: u8, x: u16, y: u16) -> Offset {
+        Offset {
+            typ: 3,
+            x,
+            y,
+            slot_number,
+        }
+    }
+
+    #[test]
+    fn slots_are_free_taken_or_ghost_towns() {
+        let mut troy = town(1, "Troy", Some(7));
+        troy.offset_slotnumber = 0;
+        let mut ruins = town(2, "Ruins", None);
+        ruins.offset_slotnumber = 2;
+        let dt = world(
+            Utc::now(),
+            vec![alliance(1, "Sparta")],
+            vec![player(7, "Achilles", Some(1))],
+            vec![troy.clone(), ruins.clone()],
+        );
+        let offsets = vec![offset(0, 0, 0), offset(1, 125, 250), offset(2, 250, 62)];
+        let slots = slots(&dt, &island(), Some(&offsets), &[&troy, &ruins]);
+
+        let summary: Vec<_> = slots
+            .iter()
+            .map(|s| (s.slot_number, s.town.as_ref().map(|t| t.id)))
+            .collect();
+        assert_eq!(summary, vec![(0, Some(1)), (1, None), (2, Some(2))]);
+        assert_eq!((slots[1].x, slots[1].y), (513.0, 489.0));
+        assert_eq!((slots[2].x, slots[2].y), (514.0, 487.496));
+
+        let taken = slots[0].town.as_ref().unwrap();
+        assert_eq!(taken.player.as_deref(), Some("Achilles"));
+        assert_eq!(taken.alliance.as_deref(), Some("Sparta"));
+        let ghost = slots[2].town.as_ref().unwrap();
+        assert_eq!((ghost.player_id, ghost.alliance_id), (None, None));
+    }
+
+    #[test]
+    fn islands_without_offsets_have_no_slots() {
+        let dt = world(Utc::now(), vec![], vec![], vec![]);
+        assert!(slots(&dt, &island(), None, &[]).is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+//! The players and alliances with the most conquests, ghost towns taken and towns lost in the
+//! last day, week, month or a custom range.
+
+use std::fmt::Write;
+
+use axum::{extract::Query, http::StatusCode, response::Html, Json};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::db::leaderboard::{self, Entry, Ranking};
+
+use super::{html, query_db};
+
+const DEFAULT_LIMIT: usize = 20;
+
+/// the predefined windows, ending now
+const PERIODS: [(&str, i64); 3] = [("24h", 24), ("7d", 7 * 24), ("30d", 30 * 24)];
+
+#[derive(Deserialize)]
+pub struct LeaderboardParams {
+    /// `24h`, `7d` or `30d`, ignored if `since` is given
+    period: Option<String>,
+    /// the start of a custom range
+    since: Option<DateTime<Utc>>,
+    /// the end of a custom range, now by default
+    until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    by: Ranking,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Leaderboard {
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    by: Ranking,
+    players: Vec<Entry>,
+    alliances: Vec<Entry>,
+}
+
+impl LeaderboardParams {
+    fn window(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), StatusCode> {
+        let until = self.until.unwrap_or_else(Utc::now);
+        if let Some(since) = self.since {
+            return Ok((since, until));
+        }
+        let period = self.period.as_deref().unwrap_or(PERIODS[0].0);
+        let (_, hours) = PERIODS
+            .iter()
+            .find(|(name, _)| *name == period)
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        Ok((
+            until - Duration::try_hours(*hours).unwrap_or_default(),
+            until,
+        ))
+    }
+}
+
+async fn leaderboard(params: &LeaderboardParams) -> Result<Leaderboard, StatusCode> {
+    let (since, until) = params.window()?;
+    let by = params.by;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let (players, alliances) = query_db(move |conn| {
+        Ok((
+            leaderboard::leaderboard(conn, "player", by, since, until, limit)?,
+            leaderboard::leaderboard(conn, "alliance", by, since, until, limit)?,
+        ))
+    })
+    .await?;
+    Ok(Leaderboard {
+        since,
+        until,
+        by,
+        players,
+        alliances,
+    })
+}
+
+pub async fn api_leaderboard(
+    Query(params): Query<LeaderboardParams>,
+) -> Result<Json<Leaderboard>, StatusCode> {
+    Ok(Json(leaderboard(&params).await?))
+}
+
+fn rows(entries: &[Entry], link: fn(u32, &str) -> String) -> Vec<Vec<String>> {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| {
+            vec![
+                (i + 1).to_string(),
+                link(e.id, &e.name),
+                e.conquests.to_string(),
+                e.ghost_towns.to_string(),
+                e.lost.to_string(),
+            ]
+        })
+        .collect()
+}
+
+fn ranking_name(ranking: Ranking) -> &'static str {
+    match ranking {
+        Ranking::Conquests => "conquests",
+        Ranking::GhostTowns => "ghost towns taken",
+        Ranking::Lost => "towns lost",
+    }
+}
+
+pub async fn leaderboard_page(
+    Query(params): Query<LeaderboardParams>,
+) -> Result<Html<String>, StatusCode> {
+    let board = leaderboard(&params).await?;
+    let by = board.by.column();
+
+    let mut body = String::from("<h1>Leaderboard</h1><p>");
+    for (name, _) in PERIODS {
+        let _ = write!(
+            body,
+            r#"<a href="/leaderboard?period={name}&amp;by={by}">{name}</a> "#
+        );
+    }
+    body.push_str("| ");
+    for ranking in Ranking::ALL {
+        let _ = write!(
+            body,
+            r#"<a href="/leaderboard?since={}&amp;until={}&amp;by={}">{}</a> "#,
+            board.since.to_rfc3339_opts(SecondsFormat::Secs, true),
+            board.until.to_rfc3339_opts(SecondsFormat::Secs, true),
+            ranking.column(),
+            ranking_name(ranking),
+        );
+    }
+    let _ = write!(
+        body,
+        "</p><p>Most {} from {} to {}.</p>",
+        ranking_name(board.by),
+        board.since.format("%Y-%m-%d %H:%M"),
+        board.until.format("%Y-%m-%d %H:%M"),
+    );
+
+    let header = ["#", "Name", "Conquests", "Ghost towns", "Lost"];
+    body.push_str("<h2>Players</h2>");
+    body.push_str(&html::table(
+        &header,
+        &rows(&board.players, html::player_link),
+    ));
+    body.push_str("<h2>Alliances</h2>");
+    body.push_str(&html::table(
+        &header,
+        &rows(&board.alliances, html::alliance_link),
+    ));
+
+    Ok(html::page("Leaderboard", &body))
+}
@@ -0,0 +1,70 @@
+//! Server sent events of everything the DB commits, so that open pages can show new events
+//! without reloading.
+
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::messages::MessageFromModelToDB;
+
+use super::WebState;
+
+/// the number of events a slow client may fall behind before it misses some
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// an event ready to be sent to the browsers
+pub struct LiveEvent {
+    typ: &'static str,
+    json: String,
+}
+
+/// turn a committed message into the events that are streamed
+pub fn live_events(msg: &MessageFromModelToDB) -> Vec<Arc<LiveEvent>> {
+    msg.events()
+        .into_iter()
+        .map(|event| {
+            let json = json!({
+                "type": event.typ,
+                "summary": event.summary,
+                "data": event.data,
+            });
+            Arc::new(LiveEvent {
+                typ: event.typ,
+                json: json.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct StreamParams {
+    /// comma separated event types, all types if missing
+    types: Option<String>,
+}
+
+#[allow(clippy::unused_async)]
+pub async fn stream(
+    State(state): State<WebState>,
+    Query(params): Query<StreamParams>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let types: Option<Vec<String>> = params
+        .types
+        .map(|types| types.split(',').map(|t| t.trim().to_string()).collect());
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(move |res| {
+        // a lagging client simply misses the events it could not keep up with
+        let event = res.ok()?;
+        if let Some(types) = &types {
+            if !types.iter().any(|t| t == event.typ) {
+                return None;
+            }
+        }
+        Some(Ok(Event::default().event(event.typ).data(&event.json)))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
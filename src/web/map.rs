@@ -0,0 +1,422 @@
+//! Server side rendering of a region of the world as svg. Islands are drawn as the outline of their
+//! town slots, towns as dots coloured by alliance. Ghost towns are highlighted and the ghost towns
+//! that appeared or were conquered recently get a ring around them. Optionally the territory of the
+//! alliances is drawn on top, see `model::territory`.
+
+use std::{collections::HashMap, fmt::Write};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse},
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+
+use crate::{
+    db::{orm::OrmGS, queries},
+    model::{
+        database::{DataTable, Island, Offset},
+        region::Region,
+        territory::{contested, territories, CONTESTED_SHARE},
+    },
+};
+
+use super::{html, query_db, RegionParams, WebState};
+
+const DEFAULT_WIDTH: u32 = 1000;
+const MAX_WIDTH: u32 = 4000;
+const DEFAULT_HOURS: i64 = 24;
+
+const COLOR_SEA: &str = "#1e3a5f";
+const COLOR_ISLAND: &str = "#d6c08a";
+const COLOR_GHOST: &str = "#ffffff";
+const COLOR_GHOST_STROKE: &str = "#dc2626";
+const COLOR_APPEARED: &str = "#f97316";
+const COLOR_CONQUERED: &str = "#22c55e";
+const COLOR_CONTESTED: &str = "#ef4444";
+
+#[derive(Deserialize)]
+pub struct MapParams {
+    /// width of the rendered image in pixels
+    width: Option<u32>,
+    /// ghost towns that appeared or were conquered within this many hours are marked
+    hours: Option<i64>,
+    /// mark the centroids of the alliances in every ocean and outline contested oceans
+    #[serde(default)]
+    territory: bool,
+}
+
+/// fill colour for the towns of the given alliance. Towns without an alliance are grey.
+pub fn alliance_color(alliance_id: Option<u32>) -> (u8, u8, u8) {
+    let Some(id) = alliance_id else {
+        return (0x9c, 0xa3, 0xaf);
+    };
+    // step through the hues by the golden angle, so that neighbouring ids look different
+    let hue = (f64::from(id) * 137.508) % 360.0;
+    hsl_to_rgb(hue, 0.75, 0.55)
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::many_single_char_names
+)]
+fn hsl_to_rgb(h: f64, s: f64, l: f64) -> (u8, u8, u8) {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+    let (r, g, b) = match h {
+        h if h < 60.0 => (c, x, 0.0),
+        h if h < 120.0 => (x, c, 0.0),
+        h if h < 180.0 => (0.0, c, x),
+        h if h < 240.0 => (0.0, x, c),
+        h if h < 300.0 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let to_u8 = |v: f64| ((v + m) * 255.0).round() as u8;
+    (to_u8(r), to_u8(g), to_u8(b))
+}
+
+pub fn hex((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// the outline of an island in world coordinates: its town slots ordered by their angle around
+/// the centre of the island and pushed outwards a bit, so that the towns lie inside. Islands
+/// without town slots are drawn as a small square.
+pub fn island_outline(island: &Island, slots: Option<&Vec<Offset>>) -> Vec<(f32, f32)> {
+    let x = f32::from(island.x);
+    let y = f32::from(island.y);
+    let mut points: Vec<(f32, f32)> = slots
+        .map(|slots| {
+            slots
+                .iter()
+                .map(|o| o.position(island.x, island.y))
+                .collect()
+        })
+        .unwrap_or_default();
+    if points.len() < 3 {
+        return vec![(x, y), (x + 1.0, y), (x + 1.0, y + 1.0), (x, y + 1.0)];
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    let n = points.len() as f32;
+    let cx = points.iter().map(|p| p.0).sum::<f32>() / n;
+    let cy = points.iter().map(|p| p.1).sum::<f32>() / n;
+    points.sort_by(|a, b| {
+        let angle_a = (a.1 - cy).atan2(a.0 - cx);
+        let angle_b = (b.1 - cy).atan2(b.0 - cx);
+        angle_a.total_cmp(&angle_b)
+    });
+    points
+        .into_iter()
+        .map(|(px, py)| (cx + (px - cx) * 1.3, cy + (py - cy) * 1.3))
+        .collect()
+}
+
+/// whether the island may reach into the region. Islands are at most 8 fields large.
+pub fn island_near_region(island: &Island, region: &Region) -> bool {
+    let x = f32::from(island.x);
+    let y = f32::from(island.y);
+    x + 8.0 >= region.x0 && x <= region.x1 && y + 8.0 >= region.y0 && y <= region.y1
+}
+
+fn write_ocean_borders(svg: &mut String, region: &Region, pixel: f32) {
+    let _ = write!(
+        svg,
+        r#"<g stroke="{COLOR_ISLAND}" stroke-opacity="0.3" stroke-width="{pixel}">"#
+    );
+    for i in 1..10u16 {
+        let v = f32::from(i) * 100.0;
+        if region.x0 < v && v < region.x1 {
+            let _ = write!(
+                svg,
+                r#"<line x1="{v}" y1="{}" x2="{v}" y2="{}"/>"#,
+                region.y0, region.y1
+            );
+        }
+        if region.y0 < v && v < region.y1 {
+            let _ = write!(
+                svg,
+                r#"<line x1="{}" y1="{v}" x2="{}" y2="{v}"/>"#,
+                region.x0, region.x1
+            );
+        }
+    }
+    svg.push_str("</g>\n");
+}
+
+fn write_islands(svg: &mut String, snapshot: &DataTable, region: &Region) {
+    let slots = DataTable::offsets_by_island_type();
+    let _ = write!(svg, r#"<g fill="{COLOR_ISLAND}">"#);
+    for island in snapshot
+        .islands
+        .values()
+        .filter(|i| island_near_region(i, region))
+    {
+        let points = island_outline(island, slots.get(&island.typ));
+        svg.push_str(r#"<polygon points=""#);
+        for (x, y) in points {
+            let _ = write!(svg, "{x:.2},{y:.2} ");
+        }
+        svg.push_str(r#""/>"#);
+    }
+    svg.push_str("</g>\n");
+}
+
+/// Outline the contested oceans, mark the centroid of every alliance that is present in an ocean
+/// and label each ocean with its strongest alliance.
+fn write_territory(svg: &mut String, snapshot: &DataTable, region: &Region, pixel: f32) {
+    let territories = territories(snapshot);
+    let in_region = |ocean: u16| {
+        let o = Region::ocean(ocean);
+        o.x0 < region.x1 && region.x0 < o.x1 && o.y0 < region.y1 && region.y0 < o.y1
+    };
+    for c in contested(&territories)
+        .iter()
+        .filter(|c| in_region(c.ocean))
+    {
+        let o = Region::ocean(c.ocean);
+        let names: Vec<_> = c.alliances.iter().map(|t| t.alliance.as_str()).collect();
+        let _ = write!(
+            svg,
+            r#"<rect x="{}" y="{}" width="100" height="100" fill="none" stroke="{COLOR_CONTESTED}" stroke-width="{}" stroke-dasharray="{}"><title>Ocean {} is contested by {}</title></rect>"#,
+            o.x0,
+            o.y0,
+            3.0 * pixel,
+            8.0 * pixel,
+            c.ocean,
+            html::escape(&names.join(", ")),
+        );
+    }
+
+    let mut labelled = None;
+    for t in territories.iter().filter(|t| in_region(t.ocean)) {
+        if t.points_share >= CONTESTED_SHARE {
+            let size = 4.0 * pixel;
+            let (x, y) = t.centroid;
+            let _ = write!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}" stroke="{COLOR_GHOST}" stroke-width="{pixel}" transform="rotate(45 {x} {y})"><title>{}: {} towns, {:.0}% of the points in ocean {}</title></rect>"#,
+                x - size / 2.0,
+                y - size / 2.0,
+                size,
+                size,
+                hex(alliance_color(Some(t.alliance_id))),
+                html::escape(&t.alliance),
+                t.towns,
+                t.points_share * 100.0,
+                t.ocean,
+            );
+        }
+        // the territories are ordered by ocean with the strongest alliance first
+        if labelled != Some(t.ocean) {
+            labelled = Some(t.ocean);
+            let o = Region::ocean(t.ocean);
+            let _ = write!(
+                svg,
+                r#"<text x="{}" y="{}" font-size="{}" fill="{COLOR_GHOST}" fill-opacity="0.8">{} {:.0}%</text>"#,
+                o.x0 + 2.0,
+                o.y0 + 2.0 + 12.0 * pixel,
+                12.0 * pixel,
+                html::escape(&t.alliance),
+                t.points_share * 100.0,
+            );
+        }
+    }
+    svg.push('\n');
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn render_svg(
+    snapshot: &DataTable,
+    region: &Region,
+    width: u32,
+    appeared: &[OrmGS],
+    conquered: &[OrmGS],
+    territory: bool,
+) -> String {
+    let scale = width as f32 / region.width();
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let height = (region.height() * scale).round() as u32;
+    // keep the smallest things at least a few pixels large, no matter the zoom
+    let pixel = 1.0 / scale;
+    let town_radius = (1.5 * pixel).max(0.3);
+    let marker_radius = (5.0 * pixel).max(1.0);
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{x0} {y0} {w} {h}" width="{width}" height="{height}">
+<rect x="{x0}" y="{y0}" width="{w}" height="{h}" fill="{COLOR_SEA}"/>
+"#,
+        x0 = region.x0,
+        y0 = region.y0,
+        w = region.width(),
+        h = region.height(),
+    );
+
+    write_ocean_borders(&mut svg, region, pixel);
+    write_islands(&mut svg, snapshot, region);
+
+    for town in snapshot
+        .towns
+        .values()
+        .filter(|t| region.contains(t.actual_x, t.actual_y))
+    {
+        let player = town.player_id.and_then(|id| snapshot.players.get(&id));
+        let alliance_id = player.and_then(|p| p.alliance_id);
+        let (fill, stroke, radius) = if player.is_none() {
+            (
+                COLOR_GHOST.to_string(),
+                COLOR_GHOST_STROKE,
+                town_radius * 1.5,
+            )
+        } else {
+            (hex(alliance_color(alliance_id)), "none", town_radius)
+        };
+        let owner = match player {
+            Some(p) => {
+                let alliance = alliance_id
+                    .and_then(|id| snapshot.alliances.get(&id))
+                    .map(|a| format!(" [{}]", a.name))
+                    .unwrap_or_default();
+                format!("{}{alliance}", p.name)
+            }
+            None => String::from("ghost town"),
+        };
+        let _ = write!(
+            svg,
+            r#"<circle cx="{:.2}" cy="{:.2}" r="{radius}" fill="{fill}" stroke="{stroke}" stroke-width="{pixel}"><title>{} ({} points) - {}</title></circle>"#,
+            town.actual_x,
+            town.actual_y,
+            html::escape(&town.name),
+            town.points,
+            html::escape(&owner),
+        );
+    }
+    svg.push('\n');
+
+    for (events, color, what) in [
+        (appeared, COLOR_APPEARED, "became a ghost town"),
+        (conquered, COLOR_CONQUERED, "ghost town conquered"),
+    ] {
+        for gs in events.iter().filter(|gs| region.contains(gs.x, gs.y)) {
+            let _ = write!(
+                svg,
+                r#"<circle cx="{:.2}" cy="{:.2}" r="{marker_radius}" fill="none" stroke="{color}" stroke-width="{}"><title>{} {what} at {}</title></circle>"#,
+                gs.x,
+                gs.y,
+                2.0 * pixel,
+                html::escape(&gs.name),
+                gs.date.format("%Y-%m-%d %H:%M"),
+            );
+        }
+    }
+
+    if territory {
+        write_territory(&mut svg, snapshot, region, pixel);
+    }
+
+    svg.push_str("\n</svg>\n");
+    return svg;
+}
+
+pub async fn map_svg(
+    State(state): State<WebState>,
+    Query(region): Query<RegionParams>,
+    Query(params): Query<MapParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let region = region.region();
+    if region.width() <= 0.0 || region.height() <= 0.0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let width = params.width.unwrap_or(DEFAULT_WIDTH).clamp(1, MAX_WIDTH);
+    let since =
+        Utc::now() - Duration::try_hours(params.hours.unwrap_or(DEFAULT_HOURS)).unwrap_or_default();
+
+    let (appeared, conquered) = query_db(move |conn| {
+        Ok((
+            queries::gs_since(conn, "gs_appeared", since)?,
+            queries::gs_since(conn, "gs_conquered", since)?,
+        ))
+    })
+    .await?;
+
+    let svg = tokio::task::spawn_blocking(move || {
+        render_svg(
+            &snapshot,
+            &region,
+            width,
+            &appeared,
+            &conquered,
+            params.territory,
+        )
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], svg))
+}
+
+fn region_query(region: &Region) -> String {
+    format!(
+        "x0={}&y0={}&x1={}&y1={}",
+        region.x0, region.y0, region.x1, region.y1
+    )
+}
+
+/// a page around the svg with links to zoom and pan
+#[allow(clippy::unused_async)]
+pub async fn map_page(
+    Query(region): Query<RegionParams>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Html<String> {
+    let region = region.region();
+    let (w, h) = (region.width(), region.height());
+    let (cx, cy) = (region.x0 + w / 2.0, region.y0 + h / 2.0);
+    let zoomed = |factor: f32| {
+        Region::from_corners(
+            cx - w * factor / 2.0,
+            cy - h * factor / 2.0,
+            cx + w * factor / 2.0,
+            cy + h * factor / 2.0,
+        )
+    };
+    let moved = |dx: f32, dy: f32| {
+        Region::from_corners(
+            region.x0 + dx * w,
+            region.y0 + dy * h,
+            region.x1 + dx * w,
+            region.y1 + dy * h,
+        )
+    };
+    let hours = params
+        .get("hours")
+        .map(|h| format!("&hours={}", html::escape(h)))
+        .unwrap_or_default();
+    let territory = params.get("territory").is_some_and(|t| t == "true");
+    let extra = format!("{hours}{}", if territory { "&territory=true" } else { "" });
+    let link =
+        |r: Region, text: &str| format!(r#"<a href="/map?{}{extra}">{text}</a>"#, region_query(&r));
+    let toggle = format!(
+        r#"<a href="/map?{}{hours}{}">{} territory</a>"#,
+        region_query(&region),
+        if territory { "" } else { "&territory=true" },
+        if territory { "hide" } else { "show" },
+    );
+
+    let body = format!(
+        r#"<h1>Map</h1>
+<p>{zoom_in} / {zoom_out} / {left} / {right} / {up} / {down} / <a href="/map">whole world</a> / {toggle}</p>
+<p>White dots are ghost towns, orange rings mark new ghost towns and green rings ghost towns that were conquered recently. With the territory shown every ocean is labelled with its strongest alliance, diamonds mark the centre of the towns of every alliance with a large share of an ocean and dashed red borders the oceans contested by several alliances.</p>
+<object type="image/svg+xml" data="/map.svg?{query}{extra}" style="width: 100%"></object>"#,
+        zoom_in = link(zoomed(0.5), "zoom in"),
+        zoom_out = link(zoomed(2.0), "zoom out"),
+        left = link(moved(-0.5, 0.0), "left"),
+        right = link(moved(0.5, 0.0), "right"),
+        up = link(moved(0.0, -0.5), "up"),
+        down = link(moved(0.0, 0.5), "down"),
+        query = region_query(&region),
+    );
+    html::page("Map", &body)
+}
@@ -1,73 +1,368 @@
-use std::sync::{mpsc::Receiver, Arc, Mutex};
+use std::sync::{
+    mpsc::{Receiver, RecvTimeoutError},
+    Arc, Mutex,
+};
 
-use axum::{extract::State, routing::get, Router};
-use tracing::debug;
-use tracing::info;
+use anyhow::Context;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, put},
+    Router,
+};
+use chrono::Utc;
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tracing::{error, info};
 
 use crate::{
-    db::orm::{OrmGS, OrmPlayer},
+    db::{
+        self,
+        orm::{OrmGS, OrmPlayer},
+    },
     messages::MessageFromDBToWeb,
+    metrics,
+    model::{
+        database::DataTable,
+        region::Region,
+        travel::{Travel, Unit},
+    },
 };
 
+mod auth;
+mod bbcode;
+mod dashboard;
+mod entity;
+mod export;
+mod feeds;
+mod geojson;
+mod ghost_stats;
+mod html;
+mod island;
+mod leaderboard;
+mod live;
+mod map;
+mod search;
+mod status;
+mod targets;
+mod territory;
+mod tiles;
+mod watchlists;
+mod webhooks;
+
+/// how long open connections may take to finish when shutting down
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// how often the thread checks that the server still runs while it waits for messages
+const SERVER_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub struct CachedDBState {
     pub gs_conquered: Vec<OrmGS>,
     pub gs_appeared: Vec<OrmGS>,
     pub players_left: Vec<OrmPlayer>,
 }
 
+/// everything the request handlers have access to
+#[derive(Clone)]
+pub struct WebState {
+    cache: Arc<Mutex<CachedDBState>>,
+    snapshot: Arc<Mutex<Option<Arc<DataTable>>>>,
+    search: Arc<Mutex<Arc<search::SearchIndex>>>,
+    tiles: Arc<Mutex<tiles::TileCache>>,
+    /// every committed event, for the live stream
+    events: broadcast::Sender<Arc<live::LiveEvent>>,
+}
+
+impl WebState {
+    /// the latest snapshot of the world, if the model has sent one yet
+    fn snapshot(&self) -> Option<Arc<DataTable>> {
+        self.snapshot.lock().unwrap().clone()
+    }
+}
+
+/// query parameters to restrict a request to a part of the world. Either an `ocean` or the
+/// corners `x0`, `y0`, `x1` and `y1`, where missing corners default to the edges of the world.
+#[derive(Debug, Default, Deserialize)]
+pub struct RegionParams {
+    ocean: Option<u16>,
+    x0: Option<f32>,
+    y0: Option<f32>,
+    x1: Option<f32>,
+    y1: Option<f32>,
+}
+
+impl RegionParams {
+    fn region(&self) -> Region {
+        Region::from_filter(self.ocean, self.x0, self.y0, self.x1, self.y1)
+    }
+}
+
+/// query parameters for travel times: the `unit`, a colony ship by default, its `unit_speed`
+/// including bonuses and the `world_speed`
+#[derive(Debug, Default, Deserialize)]
+pub struct TravelParams {
+    unit: Option<Unit>,
+    unit_speed: Option<f32>,
+    world_speed: Option<f32>,
+}
+
+impl TravelParams {
+    fn travel(&self) -> Travel {
+        Travel::new(
+            self.unit.unwrap_or_default(),
+            self.unit_speed,
+            self.world_speed,
+        )
+    }
+}
+
+/// run the given closure with a read only connection to the database on a thread where blocking
+/// is allowed. Any error is logged and turned into a 500.
+async fn query_db<T, F>(f: F) -> Result<T, StatusCode>
+where
+    T: Send + 'static,
+    F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+{
+    with_db(db::open_read_only, f).await
+}
+
+/// like `query_db`, but with a connection that may write
+async fn write_db<T, F>(f: F) -> Result<T, StatusCode>
+where
+    T: Send + 'static,
+    F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+{
+    with_db(db::open_read_write, f).await
+}
+
+async fn with_db<T, F>(
+    open: fn() -> rusqlite::Result<rusqlite::Connection>,
+    f: F,
+) -> Result<T, StatusCode>
+where
+    T: Send + 'static,
+    F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+{
+    let res = tokio::task::spawn_blocking(move || {
+        let conn = open()?;
+        f(&conn)
+    })
+    .await;
+    match res {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => {
+            error!("Failed to query the database: {err:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(err) => {
+            error!("Database query panicked: {err:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// checks a subscription or watchlist off the runtime, validating resolves the host of its url
+async fn validate<T>(
+    value: T,
+    check: fn(&T) -> Result<(), String>,
+) -> Result<T, (StatusCode, String)>
+where
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || check(&value).map(|()| value))
+        .await
+        .map_err(|err| {
+            error!("Validation panicked: {err:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                String::from("Failed to validate"),
+            )
+        })?
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))
+}
+
 pub struct Web {
     rx: Receiver<MessageFromDBToWeb>,
-    cached_db_state: Arc<Mutex<CachedDBState>>,
+    state: WebState,
 }
 
 impl Web {
     pub fn new(rx: Receiver<MessageFromDBToWeb>) -> Self {
         Self {
             rx,
-            cached_db_state: Arc::new(Mutex::new(CachedDBState {
-                gs_conquered: Vec::new(),
-                gs_appeared: Vec::new(),
-                players_left: Vec::new(),
-            })),
+            state: WebState {
+                cache: Arc::new(Mutex::new(CachedDBState {
+                    gs_conquered: Vec::new(),
+                    gs_appeared: Vec::new(),
+                    players_left: Vec::new(),
+                })),
+                snapshot: Arc::new(Mutex::new(None)),
+                search: Arc::new(Mutex::new(Arc::new(search::SearchIndex::default()))),
+                tiles: Arc::new(Mutex::new(tiles::TileCache::default())),
+                events: broadcast::channel(live::CHANNEL_CAPACITY).0,
+            },
         }
     }
 
+    #[allow(clippy::too_many_lines)]
     pub fn start(self) {
         let rt = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
 
-        let cache_server = Arc::clone(&self.cached_db_state);
-        rt.spawn(async {
+        let state_server = self.state.clone();
+        let (tx_stop, rx_stop) = tokio::sync::oneshot::channel::<()>();
+        let server = rt.spawn(async {
             info!("Starting server to listen on [::]:10204");
             // setup and start the axum server
             let app = Router::new()
-                .route("/", get(Self::serve_main_page))
-                .with_state(cache_server);
-            axum::Server::bind(&"[::]:10204".parse().unwrap())
+                .route("/", get(dashboard::dashboard))
+                .route("/events/stream", get(live::stream))
+                .route("/player/:id", get(entity::player_page))
+                .route("/alliance/:id", get(entity::alliance_page))
+                .route("/api/player/:id", get(entity::api_player))
+                .route("/api/alliance/:id", get(entity::api_alliance))
+                .route("/island/:x/:y", get(island::island_page))
+                .route("/api/island/:x/:y", get(island::api_island))
+                .route("/islands", get(island::islands_page))
+                .route("/api/islands", get(island::api_islands))
+                .route("/map", get(map::map_page))
+                .route("/map.svg", get(map::map_svg))
+                .route("/worldmap", get(tiles::slippy_map_page))
+                .route("/tiles/:z/:x/:y", get(tiles::tile))
+                .route("/geojson/towns", get(geojson::towns))
+                .route("/geojson/islands", get(geojson::islands))
+                .route("/geojson/gs_appeared", get(geojson::gs_appeared))
+                .route("/geojson/gs_conquered", get(geojson::gs_conquered))
+                .route("/feeds/gs_appeared.atom", get(feeds::gs_appeared))
+                .route("/feeds/gs_conquered.atom", get(feeds::gs_conquered))
+                .route(
+                    "/feeds/player_disappeared.atom",
+                    get(feeds::players_disappeared),
+                )
+                .route("/export/:table", get(export::export))
+                .route("/bbcode/ghost_towns", get(bbcode::ghost_towns))
+                .route("/bbcode/:table", get(bbcode::events))
+                .route("/metrics", get(Self::metrics))
+                .route("/healthz", get(status::healthz))
+                .route("/status", get(status::status_page))
+                .route("/api/status", get(status::api_status))
+                .route("/stats/ghost_towns", get(ghost_stats::ghost_towns_page))
+                .route("/api/stats/ghost_towns", get(ghost_stats::api_ghost_towns))
+                .route("/leaderboard", get(leaderboard::leaderboard_page))
+                .route("/api/leaderboard", get(leaderboard::api_leaderboard))
+                .route("/api/territory", get(territory::api_territory))
+                .route("/targets", get(targets::targets_page))
+                .route("/api/targets", get(targets::api_targets))
+                .route("/search", get(search::search_page))
+                .route("/api/search", get(search::api_search))
+                .route("/api/webhooks", get(webhooks::list).post(webhooks::create))
+                .route("/api/webhooks/:id", delete(webhooks::remove))
+                .route("/watchlists", get(watchlists::watchlists_page))
+                .route("/watchlist/:id", get(watchlists::watchlist_page))
+                .route("/feeds/watchlist/:file", get(feeds::watchlist))
+                .route(
+                    "/api/watchlists",
+                    get(watchlists::list).post(watchlists::create),
+                )
+                .route(
+                    "/api/watchlists/:id",
+                    get(watchlists::get)
+                        .put(watchlists::update)
+                        .delete(watchlists::remove),
+                )
+                .route("/api/watchlists/:id/events", get(watchlists::api_events))
+                .route("/login", get(auth::login_page).post(auth::login))
+                .route("/logout", get(auth::logout))
+                .route("/api/account", get(auth::me))
+                .route(
+                    "/api/tokens",
+                    get(auth::list_tokens).post(auth::create_token),
+                )
+                .route("/api/tokens/:id", delete(auth::revoke_token))
+                .route(
+                    "/api/accounts",
+                    get(auth::list_accounts).post(auth::create_account),
+                )
+                .route(
+                    "/api/accounts/:id",
+                    put(auth::update_account).delete(auth::remove_account),
+                )
+                .with_state(state_server);
+            axum::Server::try_bind(&"[::]:10204".parse()?)
+                .context("Failed to listen on [::]:10204")?
                 .serve(app.into_make_service())
+                .with_graceful_shutdown(async {
+                    let _ = rx_stop.await;
+                })
                 .await
-                .unwrap();
+                .context("The server failed")
         });
 
-        for msg in self.rx {
+        loop {
+            let msg = match self.rx.recv_timeout(SERVER_CHECK_INTERVAL) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    // the server only stops on its own if it failed, end the thread so that the
+                    // supervisor notices
+                    if server.is_finished() {
+                        match rt.block_on(server) {
+                            Ok(Ok(())) => panic!("The server stopped"),
+                            Ok(Err(err)) => panic!("{err:#}"),
+                            Err(err) => panic!("The server crashed: {err}"),
+                        }
+                    }
+                    continue;
+                }
+            };
+            metrics::received(metrics::DB_TO_WEB);
             info!("Got Message from DB to Web: {}", msg);
             match msg {
                 MessageFromDBToWeb::NewData(state) => {
-                    *self.cached_db_state.lock().unwrap() = state;
+                    *self.state.cache.lock().unwrap() = state;
+                }
+                MessageFromDBToWeb::NewSnapshot(dt) => {
+                    let index = search::SearchIndex::new(&dt);
+                    *self.state.search.lock().unwrap() = Arc::new(index);
+                    *self.state.snapshot.lock().unwrap() = Some(dt);
+                    self.state.tiles.lock().unwrap().clear();
+                }
+                MessageFromDBToWeb::EventsCommitted(msg) => {
+                    for event in live::live_events(&msg) {
+                        // fails only if nobody is listening, which is fine
+                        let _ = self.state.events.send(event);
+                    }
                 }
             }
         }
+
+        // the DB has shut down, finish the requests in flight. Streams never finish on their
+        // own, so they are cut off after a while.
+        info!("Shutting down the webserver");
+        let _ = tx_stop.send(());
+        let res = rt.block_on(async { tokio::time::timeout(SHUTDOWN_TIMEOUT, server).await });
+        if res.is_err() {
+            info!("Cutting off the remaining connections");
+        }
     }
 
-    async fn serve_main_page(
-        State(cache): State<Arc<Mutex<CachedDBState>>>,
-    ) -> Result<String, axum::http::StatusCode> {
-        debug!("Serving a request!");
-        let inner = cache.lock().unwrap();
-        Ok(format!(
-            "Hello World! DB Contents: OldGS {} / NewGS {} / Players {}",
-            inner.gs_conquered.len(),
-            inner.gs_appeared.len(),
-            inner.players_left.len()
+    #[allow(clippy::unused_async)]
+    async fn metrics(State(state): State<WebState>) -> Result<impl IntoResponse, StatusCode> {
+        if let Some(snapshot) = state.snapshot() {
+            let age = Utc::now() - snapshot.loaded;
+            #[allow(clippy::cast_precision_loss)]
+            metrics::SNAPSHOT_AGE.set(age.num_milliseconds() as f64 / 1000.0);
+        }
+        let encoder = TextEncoder::new();
+        let text = encoder
+            .encode_to_string(&prometheus::gather())
+            .map_err(|err| {
+                error!("Failed to encode the metrics: {err:?}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        Ok((
+            [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+            text,
         ))
     }
 }
@@ -0,0 +1,254 @@
+//! In-memory search index over the names of players, alliances and towns. It is rebuilt from every
+//! new snapshot and matches case and accent insensitive prefixes of the names or of any word in
+//! the names.
+
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::model::database::DataTable;
+
+use super::{html, WebState};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Player,
+    Alliance,
+    Town,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub kind: Kind,
+    pub id: u32,
+    pub name: String,
+    pub points: u32,
+    /// the page with details about this hit. Towns link to their owner, ghost towns have none.
+    pub url: Option<String>,
+}
+
+/// case and accent insensitive form of a name, i.e. "Ärger" and "arger" are the same
+pub fn normalize(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    hits: Vec<SearchHit>,
+    /// normalized name and the index into `hits`, sorted by key. Every name is inserted once in
+    /// full and once for every word after the first, so that prefixes of any word match.
+    keys: Vec<(String, usize)>,
+}
+
+impl SearchIndex {
+    pub fn new(dt: &DataTable) -> Self {
+        let mut hits = Vec::with_capacity(dt.players.len() + dt.alliances.len() + dt.towns.len());
+        hits.extend(dt.players.values().map(|p| SearchHit {
+            kind: Kind::Player,
+            id: p.id,
+            name: p.name.clone(),
+            points: p.points,
+            url: Some(format!("/player/{}", p.id)),
+        }));
+        hits.extend(dt.alliances.values().map(|a| SearchHit {
+            kind: Kind::Alliance,
+            id: a.id,
+            name: a.name.clone(),
+            points: a.points,
+            url: Some(format!("/alliance/{}", a.id)),
+        }));
+        hits.extend(dt.towns.values().map(|t| SearchHit {
+            kind: Kind::Town,
+            id: t.id,
+            name: t.name.clone(),
+            points: u32::from(t.points),
+            url: t.player_id.map(|id| format!("/player/{id}")),
+        }));
+
+        let mut keys = Vec::with_capacity(hits.len() * 2);
+        for (i, hit) in hits.iter().enumerate() {
+            let normalized = normalize(&hit.name);
+            for (start, _) in normalized
+                .char_indices()
+                .filter(|(start, c)| *start > 0 && !c.is_alphanumeric())
+            {
+                let rest = normalized[start..].trim_start_matches(|c: char| !c.is_alphanumeric());
+                if !rest.is_empty() {
+                    keys.push((rest.to_string(), i));
+                }
+            }
+            keys.push((normalized, i));
+        }
+        keys.sort_unstable();
+
+        Self { hits, keys }
+    }
+
+    /// all hits where the name or any word in it starts with `query`. Names that match in full
+    /// come first, then names that start with the query, then everything else. Ties are broken by
+    /// points.
+    pub fn search(&self, query: &str, kind: Option<Kind>, limit: usize) -> Vec<SearchHit> {
+        let query = normalize(query.trim());
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let start = self
+            .keys
+            .partition_point(|(key, _)| key.as_str() < query.as_str());
+        let mut seen = HashSet::new();
+        let mut matches: Vec<(u8, &SearchHit)> = self.keys[start..]
+            .iter()
+            .take_while(|(key, _)| key.starts_with(&query))
+            .filter(|(_, i)| seen.insert(*i))
+            .map(|(_, i)| &self.hits[*i])
+            .filter(|hit| kind.is_none() || kind == Some(hit.kind))
+            .map(|hit| {
+                let name = normalize(&hit.name);
+                let quality = if name == query {
+                    0
+                } else if name.starts_with(&query) {
+                    1
+                } else {
+                    2
+                };
+                (quality, hit)
+            })
+            .collect();
+        matches.sort_by(|(qa, a), (qb, b)| qa.cmp(qb).then(b.points.cmp(&a.points)));
+
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, hit)| hit.clone())
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    #[serde(default)]
+    q: String,
+    kind: Option<Kind>,
+    limit: Option<usize>,
+}
+
+fn run_search(state: &WebState, params: &SearchParams) -> Vec<SearchHit> {
+    let index = Arc::clone(&state.search.lock().unwrap());
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    index.search(&params.q, params.kind, limit)
+}
+
+#[allow(clippy::unused_async)]
+pub async fn api_search(
+    State(state): State<WebState>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<Vec<SearchHit>>, StatusCode> {
+    Ok(Json(run_search(&state, &params)))
+}
+
+#[allow(clippy::unused_async)]
+pub async fn search_page(
+    State(state): State<WebState>,
+    Query(params): Query<SearchParams>,
+) -> Result<Html<String>, StatusCode> {
+    let hits = run_search(&state, &params);
+
+    let mut body = format!(
+        r#"<h1>Search</h1>
+<form action="/search"><input type="text" name="q" value="{}" autofocus> <input type="submit" value="Search"></form>"#,
+        html::escape(&params.q)
+    );
+    if !params.q.trim().is_empty() {
+        let rows: Vec<_> = hits
+            .iter()
+            .map(|hit| {
+                let name = match &hit.url {
+                    Some(url) => format!(r#"<a href="{url}">{}</a>"#, html::escape(&hit.name)),
+                    None => html::escape(&hit.name),
+                };
+                let kind = match hit.kind {
+                    Kind::Player => "Player",
+                    Kind::Alliance => "Alliance",
+                    Kind::Town => "Town",
+                };
+                vec![kind.to_string(), name, hit.points.to_string()]
+            })
+            .collect();
+        body.push_str(&html::table(&["Type", "Name", "Points"], &rows));
+    }
+    Ok(html::page("Search", &body))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::model::fixtures::{alliance, player, town, world};
+
+    fn index() -> SearchIndex {
+        let mut big = player(2, "Ärgerlich", None);
+        big.points = 9000;
+        let dt = world(
+            Utc::now(),
+            vec![alliance(1, "Die Ärger-Bande")],
+            vec![player(1, "Ärger", Some(1)), big],
+            vec![town(1, "ÉLYSION", Some(1)), town(2, "Ruins", None)],
+        );
+        SearchIndex::new(&dt)
+    }
+
+    fn found(hits: &[SearchHit]) -> Vec<(Kind, u32)> {
+        hits.iter().map(|h| (h.kind, h.id)).collect()
+    }
+
+    #[test]
+    fn names_are_folded() {
+        assert_eq!(normalize("Ärger"), "arger");
+        assert_eq!(normalize("ÉLYSION"), "elysion");
+        assert_eq!(normalize("Zoë's"), "zoe's");
+    }
+
+    #[test]
+    fn prefixes_of_any_word_match() {
+        let index = index();
+        // the exact name first, then by points, then the alliance where a later word matches
+        assert_eq!(
+            found(&index.search(" ARGER", None, 10)),
+            vec![(Kind::Player, 1), (Kind::Player, 2), (Kind::Alliance, 1)]
+        );
+        assert_eq!(
+            found(&index.search("bande", None, 10)),
+            vec![(Kind::Alliance, 1)]
+        );
+        assert_eq!(
+            found(&index.search("arg", Some(Kind::Alliance), 10)),
+            vec![(Kind::Alliance, 1)]
+        );
+        assert_eq!(
+            found(&index.search("arg", None, 1)),
+            vec![(Kind::Player, 2)]
+        );
+
+        let towns = index.search("ely", None, 10);
+        assert_eq!(towns[0].url.as_deref(), Some("/player/1"));
+        assert_eq!(index.search("ruins", None, 10)[0].url, None);
+        assert!(index.search("  ", None, 10).is_empty());
+        assert!(index.search("troy", None, 10).is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+//! Health of the whole application: when the world was last fetched, when the DB last committed
+//! and what went wrong recently. `/healthz` fails once the data is stale, for docker health checks.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{self, queries::EVENT_TABLES},
+    status::{self, Status},
+};
+
+use super::{html, query_db, WebState};
+
+/// data older than this is considered stale
+const DEFAULT_MAX_AGE_MINUTES: i64 = 180;
+
+#[derive(Deserialize)]
+pub struct HealthParams {
+    /// consider the data stale after this many minutes
+    max_age: Option<i64>,
+}
+
+impl HealthParams {
+    fn max_age(&self) -> Duration {
+        Duration::try_minutes(self.max_age.unwrap_or(DEFAULT_MAX_AGE_MINUTES)).unwrap_or_default()
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatusReport {
+    #[serde(flatten)]
+    status: Status,
+    /// when the snapshot the webserver shows was fetched
+    snapshot_loaded: Option<DateTime<Utc>>,
+    schema_version: i32,
+    row_counts: BTreeMap<&'static str, u64>,
+    /// why the data is considered stale, empty if it is fine
+    problems: Vec<String>,
+}
+
+/// everything that is wrong with the freshness of the data
+fn problems(
+    status: &Status,
+    snapshot_loaded: Option<DateTime<Utc>>,
+    max_age: Duration,
+) -> Vec<String> {
+    let now = Utc::now();
+    let mut problems = Vec::new();
+    match snapshot_loaded {
+        None => problems.push(String::from("No snapshot has been loaded yet")),
+        Some(loaded) if now - loaded > max_age => {
+            problems.push(format!("The snapshot from {loaded} is stale"));
+        }
+        Some(_) => {}
+    }
+    for (world, fetched) in &status.last_fetch {
+        if now - *fetched > max_age {
+            problems.push(format!("{world} was last fetched at {fetched}"));
+        }
+    }
+    return problems;
+}
+
+async fn report(state: &WebState, params: &HealthParams) -> Result<StatusReport, StatusCode> {
+    let status = status::current();
+    let snapshot_loaded = state.snapshot().map(|s| s.loaded);
+    let (schema_version, row_counts) = query_db(|conn| {
+        let mut counts = BTreeMap::new();
+        for table in &EVENT_TABLES {
+            let count =
+                conn.query_row(&format!("SELECT COUNT(*) FROM {}", table.name), [], |r| {
+                    r.get(0)
+                })?;
+            counts.insert(table.name, count);
+        }
+        Ok((db::schema_version(conn)?, counts))
+    })
+    .await?;
+    let problems = problems(&status, snapshot_loaded, params.max_age());
+    Ok(StatusReport {
+        status,
+        snapshot_loaded,
+        schema_version,
+        row_counts,
+        problems,
+    })
+}
+
+/// 200 if the data is fresh, 503 with the reasons otherwise
+#[allow(clippy::unused_async)]
+pub async fn healthz(
+    State(state): State<WebState>,
+    Query(params): Query<HealthParams>,
+) -> (StatusCode, String) {
+    let snapshot_loaded = state.snapshot().map(|s| s.loaded);
+    let problems = problems(&status::current(), snapshot_loaded, params.max_age());
+    if problems.is_empty() {
+        (StatusCode::OK, String::from("ok\n"))
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{}\n", problems.join("\n")),
+        )
+    }
+}
+
+pub async fn api_status(
+    State(state): State<WebState>,
+    Query(params): Query<HealthParams>,
+) -> Result<Json<StatusReport>, StatusCode> {
+    Ok(Json(report(&state, &params).await?))
+}
+
+fn date(date: Option<DateTime<Utc>>) -> String {
+    date.map_or_else(
+        || String::from("never"),
+        |d| d.format("%Y-%m-%d %H:%M:%S").to_string(),
+    )
+}
+
+pub async fn status_page(
+    State(state): State<WebState>,
+    Query(params): Query<HealthParams>,
+) -> Result<(StatusCode, Html<String>), StatusCode> {
+    let report = report(&state, &params).await?;
+
+    let mut body = String::from("<h1>Status</h1>");
+    if report.problems.is_empty() {
+        body.push_str("<p>All data is up to date.</p>");
+    } else {
+        body.push_str("<ul>");
+        for problem in &report.problems {
+            let _ = write!(body, "<li>{}</li>", html::escape(problem));
+        }
+        body.push_str("</ul>");
+    }
+
+    let mut rows = vec![
+        vec![
+            String::from("Snapshot loaded"),
+            date(report.snapshot_loaded),
+        ],
+        vec![
+            String::from("Last database commit"),
+            date(report.status.last_commit),
+        ],
+        vec![
+            String::from("Schema version"),
+            report.schema_version.to_string(),
+        ],
+    ];
+    for (world, fetched) in &report.status.last_fetch {
+        rows.push(vec![
+            format!("Last fetch of {}", html::escape(world)),
+            date(Some(*fetched)),
+        ]);
+    }
+    body.push_str(&html::table(&["", ""], &rows));
+
+    body.push_str("<h2>Rows</h2>");
+    let rows: Vec<_> = report
+        .row_counts
+        .iter()
+        .map(|(table, count)| vec![(*table).to_string(), count.to_string()])
+        .collect();
+    body.push_str(&html::table(&["Table", "Rows"], &rows));
+
+    body.push_str("<h2>Recent warnings and errors</h2>");
+    let rows: Vec<_> = report
+        .status
+        .recent_errors
+        .iter()
+        .map(|entry| {
+            vec![
+                date(Some(entry.date)),
+                html::escape(&entry.level),
+                html::escape(&entry.target),
+                html::escape(&entry.message),
+            ]
+        })
+        .collect();
+    body.push_str(&html::table(&["Date", "Level", "Source", "Message"], &rows));
+
+    let code = if report.problems.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((code, html::page("Status", &body)))
+}
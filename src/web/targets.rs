@@ -0,0 +1,212 @@
+//! Ranks the open ghost towns, and on request the towns of inactive players, around an origin.
+//! See `model::targets` for the score. Only for accounts, the ghost town lists stay public.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{activity, queries},
+    model::{
+        targets::{find_targets, Origin, Target, TargetQuery, Weights},
+        travel::{format_seconds, Travel},
+    },
+};
+
+use super::{auth::Auth, html, query_db, TravelParams, WebState};
+
+const DEFAULT_LIMIT: usize = 50;
+const DEFAULT_MAX_DISTANCE: f32 = 50.0;
+const DEFAULT_RADIUS: f32 = 10.0;
+
+#[derive(Deserialize)]
+pub struct TargetParams {
+    /// start from this town, its owner and alliance are not targets
+    town_id: Option<u32>,
+    /// or from these coordinates
+    x: Option<f32>,
+    y: Option<f32>,
+    /// towns of this alliance are neither targets nor enemies, the alliance of the town by default
+    alliance_id: Option<u32>,
+    /// also the towns of players whose points did not change for this many hours
+    inactive_hours: Option<i64>,
+    max_distance: Option<f32>,
+    /// the radius around a target in which enemy towns are counted
+    radius: Option<f32>,
+    limit: Option<usize>,
+    w_points: Option<f64>,
+    w_distance: Option<f64>,
+    w_ocean: Option<f64>,
+    w_enemies: Option<f64>,
+    w_open: Option<f64>,
+}
+
+impl TargetParams {
+    fn weights(&self) -> Weights {
+        let default = Weights::default();
+        Weights {
+            points: self.w_points.unwrap_or(default.points),
+            distance: self.w_distance.unwrap_or(default.distance),
+            same_ocean: self.w_ocean.unwrap_or(default.same_ocean),
+            enemies: self.w_enemies.unwrap_or(default.enemies),
+            open: self.w_open.unwrap_or(default.open),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TargetList {
+    pub x: f32,
+    pub y: f32,
+    pub weights: Weights,
+    pub travel: Travel,
+    /// time at which the snapshot was loaded
+    pub loaded: DateTime<Utc>,
+    pub targets: Vec<Target>,
+}
+
+async fn target_list(
+    state: &WebState,
+    params: TargetParams,
+    travel: &TravelParams,
+) -> Result<TargetList, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let origin = match (params.town_id, params.x, params.y) {
+        (Some(town_id), _, _) => {
+            let town = snapshot.towns.get(&town_id).ok_or(StatusCode::NOT_FOUND)?;
+            let alliance_id = town
+                .player_id
+                .and_then(|id| snapshot.players.get(&id))
+                .and_then(|p| p.alliance_id);
+            Origin {
+                x: town.actual_x,
+                y: town.actual_y,
+                player_id: town.player_id,
+                alliance_id: params.alliance_id.or(alliance_id),
+            }
+        }
+        (None, Some(x), Some(y)) => Origin {
+            x,
+            y,
+            player_id: None,
+            alliance_id: params.alliance_id,
+        },
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let now = Utc::now();
+    let inactive_before = params
+        .inactive_hours
+        .map(|h| now - Duration::try_hours(h).unwrap_or_default());
+    let (ghost_towns_since, inactive) = query_db(move |conn| {
+        let inactive = match inactive_before {
+            Some(before) => activity::inactive_players(conn, before)?,
+            None => HashMap::new(),
+        };
+        Ok((queries::ghost_towns_since(conn)?, inactive))
+    })
+    .await?;
+
+    let query = TargetQuery {
+        origin,
+        weights: params.weights(),
+        max_distance: params.max_distance.unwrap_or(DEFAULT_MAX_DISTANCE),
+        radius: params.radius.unwrap_or(DEFAULT_RADIUS),
+        travel: travel.travel(),
+        now,
+    };
+    let mut targets = find_targets(&snapshot, &query, &ghost_towns_since, &inactive);
+    targets.truncate(params.limit.unwrap_or(DEFAULT_LIMIT));
+    Ok(TargetList {
+        x: origin.x,
+        y: origin.y,
+        weights: query.weights,
+        travel: query.travel,
+        loaded: snapshot.loaded,
+        targets,
+    })
+}
+
+pub async fn api_targets(
+    _auth: Auth,
+    State(state): State<WebState>,
+    Query(params): Query<TargetParams>,
+    Query(travel): Query<TravelParams>,
+) -> Result<Json<TargetList>, StatusCode> {
+    Ok(Json(target_list(&state, params, &travel).await?))
+}
+
+pub async fn targets_page(
+    _auth: Auth,
+    State(state): State<WebState>,
+    Query(params): Query<TargetParams>,
+    Query(travel): Query<TravelParams>,
+) -> Result<Html<String>, StatusCode> {
+    let list = target_list(&state, params, &travel).await?;
+    let rows: Vec<_> = list
+        .targets
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let owner = match (t.player_id, &t.player) {
+                (Some(id), Some(name)) => html::player_link(id, name),
+                _ => String::from("ghost town"),
+            };
+            vec![
+                (i + 1).to_string(),
+                html::escape(&t.name),
+                t.points.to_string(),
+                format!("{:.1}|{:.1}", t.x, t.y),
+                t.ocean.to_string(),
+                owner,
+                t.open_since
+                    .map_or_else(String::new, |d| d.format("%Y-%m-%d %H:%M").to_string()),
+                format!("{:.1}", t.distance),
+                format!("in {}", format_seconds(t.travel_seconds)),
+                t.enemies.to_string(),
+                format!("{:.2}", t.score),
+            ]
+        })
+        .collect();
+    let w = list.weights;
+    let body = format!(
+        "<h1>Targets around {x:.1}|{y:.1}</h1>
+<p>Start from <code>town_id</code> or <code>x</code> and <code>y</code>, add the towns of inactive players with <code>inactive_hours=48</code>. The score adds up points, closeness, the same ocean and how long a town is open and subtracts the enemy towns nearby, weighted with <code>w_points={}</code>, <code>w_distance={}</code>, <code>w_ocean={}</code>, <code>w_enemies={}</code> and <code>w_open={}</code>. Arrival of a {unit} with speed {unit_speed} on a world with speed {world_speed}, change them with <code>unit</code>, <code>unit_speed</code> and <code>world_speed</code>. As of {loaded}.</p>
+{table}",
+        w.points,
+        w.distance,
+        w.same_ocean,
+        w.enemies,
+        w.open,
+        x = list.x,
+        y = list.y,
+        unit = list.travel.unit.name(),
+        unit_speed = list.travel.unit_speed,
+        world_speed = list.travel.world_speed,
+        loaded = list.loaded,
+        table = html::table(
+            &[
+                "#",
+                "Town",
+                "Points",
+                "Position",
+                "Ocean",
+                "Owner",
+                "Open since",
+                "Distance",
+                "Arrival",
+                "Enemies",
+                "Score"
+            ],
+            &rows
+        ),
+    );
+    Ok(html::page("Targets", &body))
+}
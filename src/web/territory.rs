@@ -0,0 +1,73 @@
+//! The territory of the alliances per ocean in the current snapshot, the contested oceans and how
+//! the shares changed since an earlier snapshot.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::territory::{self, TerritoryChanges},
+    model::territory::{contested, territories, Contested, Territory},
+};
+
+use super::{query_db, WebState};
+
+/// changes of the points share below this are left out, about one town in a full ocean
+const DEFAULT_MIN_CHANGE: f64 = 0.01;
+
+#[derive(Deserialize)]
+pub struct TerritoryParams {
+    /// only this ocean
+    ocean: Option<u16>,
+    /// only the alliance with this id
+    alliance_id: Option<u32>,
+    /// compare with the territory at this time, 24 hours ago by default
+    since: Option<DateTime<Utc>>,
+    /// leave out changes of the points share smaller than this
+    min_change: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct TerritoryReport {
+    loaded: DateTime<Utc>,
+    territories: Vec<Territory>,
+    contested: Vec<Contested>,
+    #[serde(flatten)]
+    changes: TerritoryChanges,
+}
+
+pub async fn api_territory(
+    State(state): State<WebState>,
+    Query(params): Query<TerritoryParams>,
+) -> Result<Json<TerritoryReport>, StatusCode> {
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let since = params
+        .since
+        .unwrap_or_else(|| Utc::now() - Duration::try_hours(24).unwrap_or_default());
+    let min_change = params.min_change.unwrap_or(DEFAULT_MIN_CHANGE);
+    let mut changes =
+        query_db(move |conn| territory::territory_changes(conn, since, min_change)).await?;
+
+    let all = territories(&snapshot);
+    let mut contested = contested(&all);
+    let matches = |ocean: u16, alliance_id: u32| {
+        params.ocean.is_none_or(|o| o == ocean)
+            && params.alliance_id.is_none_or(|a| a == alliance_id)
+    };
+    // a contested ocean is kept as a whole if the alliance is one of the contenders
+    contested.retain(|c| c.alliances.iter().any(|t| matches(c.ocean, t.alliance_id)));
+    changes.changes.retain(|c| matches(c.ocean, c.alliance_id));
+    Ok(Json(TerritoryReport {
+        loaded: snapshot.loaded,
+        territories: all
+            .into_iter()
+            .filter(|t| matches(t.ocean, t.alliance_id))
+            .collect(),
+        contested,
+        changes,
+    }))
+}
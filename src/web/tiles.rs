@@ -0,0 +1,371 @@
+//! Raster tiles of the world for slippy maps like leaflet, served under `/tiles/{z}/{x}/{y}.png`.
+//!
+//! At zoom level 0 a single tile shows the whole world, every further level doubles the
+//! resolution. The `layers` query parameter selects what is drawn, so that a frontend can stack
+//! the transparent overlays on top of the islands. Rendered tiles are cached until the next
+//! snapshot arrives.
+
+use std::collections::HashMap;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse},
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{
+    db::{orm::OrmGS, queries},
+    model::{
+        database::DataTable,
+        region::{Region, WORLD_SIZE},
+    },
+};
+
+use super::{
+    html,
+    map::{alliance_color, island_near_region, island_outline},
+    query_db, WebState,
+};
+
+pub const TILE_SIZE: u32 = 256;
+const MAX_ZOOM: u8 = 6;
+/// the cache is emptied once it holds this many tiles
+const MAX_CACHED_TILES: usize = 4096;
+/// ghost towns that appeared or were conquered within this many hours are marked
+const EVENT_HOURS: i64 = 24;
+
+const LAYER_ISLANDS: u8 = 1;
+const LAYER_ALLIANCES: u8 = 1 << 1;
+const LAYER_GHOSTS: u8 = 1 << 2;
+const LAYER_EVENTS: u8 = 1 << 3;
+const LAYERS_ALL: u8 = LAYER_ISLANDS | LAYER_ALLIANCES | LAYER_GHOSTS | LAYER_EVENTS;
+
+const COLOR_SEA: [u8; 3] = [0x1e, 0x3a, 0x5f];
+const COLOR_ISLAND: [u8; 3] = [0xd6, 0xc0, 0x8a];
+const COLOR_GHOST: [u8; 3] = [0xff, 0xff, 0xff];
+const COLOR_GHOST_STROKE: [u8; 3] = [0xdc, 0x26, 0x26];
+const COLOR_APPEARED: [u8; 3] = [0xf9, 0x73, 0x16];
+const COLOR_CONQUERED: [u8; 3] = [0x22, 0xc5, 0x5e];
+
+/// rendered png tiles of the current snapshot, keyed by zoom, x, y and layers
+#[derive(Default)]
+pub struct TileCache {
+    loaded: Option<DateTime<Utc>>,
+    tiles: HashMap<(u8, u32, u32, u8), Bytes>,
+}
+
+impl TileCache {
+    pub fn clear(&mut self) {
+        self.loaded = None;
+        self.tiles.clear();
+    }
+
+    fn get(&self, loaded: DateTime<Utc>, key: (u8, u32, u32, u8)) -> Option<Bytes> {
+        if self.loaded != Some(loaded) {
+            return None;
+        }
+        self.tiles.get(&key).cloned()
+    }
+
+    fn insert(&mut self, loaded: DateTime<Utc>, key: (u8, u32, u32, u8), tile: Bytes) {
+        if self.loaded != Some(loaded) || self.tiles.len() >= MAX_CACHED_TILES {
+            self.tiles.clear();
+            self.loaded = Some(loaded);
+        }
+        self.tiles.insert(key, tile);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TileParams {
+    /// comma separated list of `islands`, `alliances`, `ghosts` and `events`. Defaults to all.
+    layers: Option<String>,
+}
+
+fn parse_layers(layers: Option<&str>) -> Result<u8, StatusCode> {
+    let Some(layers) = layers else {
+        return Ok(LAYERS_ALL);
+    };
+    let mut re = 0;
+    for layer in layers.split(',').filter(|l| !l.is_empty()) {
+        re |= match layer {
+            "islands" => LAYER_ISLANDS,
+            "alliances" => LAYER_ALLIANCES,
+            "ghosts" => LAYER_GHOSTS,
+            "events" => LAYER_EVENTS,
+            _ => return Err(StatusCode::BAD_REQUEST),
+        };
+    }
+    return Ok(re);
+}
+
+/// the part of the world covered by the given tile
+#[allow(clippy::cast_precision_loss)]
+fn tile_region(z: u8, x: u32, y: u32) -> Region {
+    let size = WORLD_SIZE / f32::from(1u16 << z);
+    Region {
+        x0: x as f32 * size,
+        y0: y as f32 * size,
+        x1: (x + 1) as f32 * size,
+        y1: (y + 1) as f32 * size,
+    }
+}
+
+/// a square rgba image that maps a region of the world onto its pixels
+struct Canvas {
+    pixels: Vec<u8>,
+    region: Region,
+    scale: f32,
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss,
+    clippy::cast_possible_wrap
+)]
+impl Canvas {
+    fn new(region: Region, background: Option<[u8; 3]>) -> Self {
+        let mut pixels = vec![0; (TILE_SIZE * TILE_SIZE * 4) as usize];
+        if let Some([r, g, b]) = background {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&[r, g, b, 255]);
+            }
+        }
+        Self {
+            pixels,
+            scale: TILE_SIZE as f32 / region.width(),
+            region,
+        }
+    }
+
+    fn to_pixel(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            (x - self.region.x0) * self.scale,
+            (y - self.region.y0) * self.scale,
+        )
+    }
+
+    fn put(&mut self, px: i64, py: i64, [r, g, b]: [u8; 3]) {
+        let size = i64::from(TILE_SIZE);
+        if px < 0 || py < 0 || px >= size || py >= size {
+            return;
+        }
+        let i = ((py * size + px) * 4) as usize;
+        self.pixels[i..i + 4].copy_from_slice(&[r, g, b, 255]);
+    }
+
+    /// fill all pixels whose centre lies within `inner..=outer` pixels of the given world position
+    fn circle(&mut self, x: f32, y: f32, inner: f32, outer: f32, color: [u8; 3]) {
+        let (cx, cy) = self.to_pixel(x, y);
+        let reach = outer.ceil() as i64 + 1;
+        let (cxi, cyi) = (cx.floor() as i64, cy.floor() as i64);
+        for py in cyi - reach..=cyi + reach {
+            for px in cxi - reach..=cxi + reach {
+                let dx = px as f32 + 0.5 - cx;
+                let dy = py as f32 + 0.5 - cy;
+                let distance = (dx * dx + dy * dy).sqrt();
+                if inner <= distance && distance <= outer {
+                    self.put(px, py, color);
+                }
+            }
+        }
+    }
+
+    /// fill the polygon with the given corners in world coordinates, using the even-odd rule
+    fn polygon(&mut self, points: &[(f32, f32)], color: [u8; 3]) {
+        let points: Vec<(f32, f32)> = points.iter().map(|p| self.to_pixel(p.0, p.1)).collect();
+        let min_y = points.iter().map(|p| p.1).fold(f32::MAX, f32::min).floor() as i64;
+        let max_y = points.iter().map(|p| p.1).fold(f32::MIN, f32::max).ceil() as i64;
+        let mut crossings = Vec::new();
+        for py in min_y.max(0)..=max_y.min(i64::from(TILE_SIZE) - 1) {
+            let y = py as f32 + 0.5;
+            crossings.clear();
+            for (i, a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                if (a.1 <= y) != (b.1 <= y) {
+                    crossings.push(a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0));
+                }
+            }
+            crossings.sort_by(f32::total_cmp);
+            for pair in crossings.chunks_exact(2) {
+                for px in (pair[0] - 0.5).ceil() as i64..=(pair[1] - 0.5).floor() as i64 {
+                    self.put(px, py, color);
+                }
+            }
+        }
+        // islands smaller than a pixel would vanish otherwise
+        if max_y - min_y <= 1 {
+            let (x, y) = points[0];
+            self.put(x as i64, y as i64, color);
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut re = Vec::new();
+        let mut encoder = png::Encoder::new(&mut re, TILE_SIZE, TILE_SIZE);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        return Ok(re);
+    }
+}
+
+fn render_tile(
+    snapshot: &DataTable,
+    region: Region,
+    layers: u8,
+    appeared: &[OrmGS],
+    conquered: &[OrmGS],
+) -> Result<Vec<u8>, png::EncodingError> {
+    let background = (layers & LAYER_ISLANDS != 0).then_some(COLOR_SEA);
+    let mut canvas = Canvas::new(region, background);
+    // keep towns visible when zoomed out, and let them grow with the islands when zoomed in
+    let town_radius = (canvas.scale * 0.3).max(1.0);
+
+    if layers & LAYER_ISLANDS != 0 {
+        let slots = DataTable::offsets_by_island_type();
+        for island in snapshot
+            .islands
+            .values()
+            .filter(|i| island_near_region(i, &region))
+        {
+            canvas.polygon(
+                &island_outline(island, slots.get(&island.typ)),
+                COLOR_ISLAND,
+            );
+        }
+    }
+
+    if layers & (LAYER_ALLIANCES | LAYER_GHOSTS) != 0 {
+        // a little margin, so that towns on the edge are drawn on both tiles
+        let margin = 2.0 * town_radius / canvas.scale;
+        let area = Region::from_corners(
+            region.x0 - margin,
+            region.y0 - margin,
+            region.x1 + margin,
+            region.y1 + margin,
+        );
+        for town in snapshot
+            .towns
+            .values()
+            .filter(|t| area.contains(t.actual_x, t.actual_y))
+        {
+            match town.player_id.and_then(|id| snapshot.players.get(&id)) {
+                Some(player) if layers & LAYER_ALLIANCES != 0 => {
+                    let (r, g, b) = alliance_color(player.alliance_id);
+                    canvas.circle(town.actual_x, town.actual_y, 0.0, town_radius, [r, g, b]);
+                }
+                None if layers & LAYER_GHOSTS != 0 => {
+                    let outer = town_radius * 1.5;
+                    canvas.circle(town.actual_x, town.actual_y, 0.0, outer, COLOR_GHOST);
+                    let inner = outer - 1.0;
+                    canvas.circle(
+                        town.actual_x,
+                        town.actual_y,
+                        inner,
+                        outer,
+                        COLOR_GHOST_STROKE,
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if layers & LAYER_EVENTS != 0 {
+        let outer = town_radius * 4.0;
+        for (events, color) in [(appeared, COLOR_APPEARED), (conquered, COLOR_CONQUERED)] {
+            for gs in events {
+                canvas.circle(gs.x, gs.y, outer - 2.0, outer, color);
+            }
+        }
+    }
+
+    canvas.encode()
+}
+
+pub async fn tile(
+    State(state): State<WebState>,
+    Path((z, x, y)): Path<(u8, u32, String)>,
+    Query(params): Query<TileParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let y: u32 = y
+        .strip_suffix(".png")
+        .and_then(|y| y.parse().ok())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if z > MAX_ZOOM || x >= 1 << z || y >= 1 << z {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let layers = parse_layers(params.layers.as_deref())?;
+    let snapshot = state.snapshot().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let key = (z, x, y, layers);
+
+    let cached = state.tiles.lock().unwrap().get(snapshot.loaded, key);
+    let png = if let Some(png) = cached {
+        png
+    } else {
+        let (appeared, conquered) = if layers & LAYER_EVENTS == 0 {
+            (Vec::new(), Vec::new())
+        } else {
+            let since = Utc::now() - Duration::try_hours(EVENT_HOURS).unwrap();
+            query_db(move |conn| {
+                Ok((
+                    queries::gs_since(conn, "gs_appeared", since)?,
+                    queries::gs_since(conn, "gs_conquered", since)?,
+                ))
+            })
+            .await?
+        };
+        let loaded = snapshot.loaded;
+        let region = tile_region(z, x, y);
+        let png = tokio::task::spawn_blocking(move || {
+            render_tile(&snapshot, region, layers, &appeared, &conquered)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|err| {
+            error!("Failed to encode tile {z}/{x}/{y}: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let png = Bytes::from(png);
+        state.tiles.lock().unwrap().insert(loaded, key, png.clone());
+        png
+    };
+
+    Ok(([(header::CONTENT_TYPE, "image/png")], png))
+}
+
+/// an interactive map that shows the tiles with leaflet
+#[allow(clippy::unused_async)]
+pub async fn slippy_map_page() -> Html<String> {
+    let body = format!(
+        r#"<link rel="stylesheet" href="https://unpkg.com/leaflet@1.9.4/dist/leaflet.css" />
+<script src="https://unpkg.com/leaflet@1.9.4/dist/leaflet.js"></script>
+<h1>World map</h1>
+<div id="map" style="height: 80vh"></div>
+<script>
+  // one tile of {TILE_SIZE} pixels covers the whole world at zoom 0
+  const map = L.map('map', {{ crs: L.CRS.Simple, minZoom: 0, maxZoom: {MAX_ZOOM} }});
+  const tiles = (layers) => L.tileLayer('/tiles/{{z}}/{{x}}/{{y}}.png?layers=' + layers, {{
+    tileSize: {TILE_SIZE}, noWrap: true, bounds: [[0, 0], [-{TILE_SIZE}, {TILE_SIZE}]]
+  }});
+  tiles('islands').addTo(map);
+  const overlays = {{
+    'Alliances': tiles('alliances').addTo(map),
+    'Ghost towns': tiles('ghosts').addTo(map),
+    'Recent events': tiles('events').addTo(map),
+  }};
+  L.control.layers(null, overlays).addTo(map);
+  map.setView([-{half}, {half}], 2);
+</script>"#,
+        half = TILE_SIZE / 2,
+    );
+    html::page("World map", &body)
+}
@@ -0,0 +1,205 @@
+//! Management of the watchlists and their personal feeds. The events are matched and recorded by
+//! the DB thread, see `crate::db::watchlist`. Every account sees the watchlists of its alliance,
+//! members may change them and admins see and change all of them.
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use serde::Deserialize;
+
+use crate::db::{
+    accounts::Role,
+    watchlist::{self, Watchlist, WatchlistEvent},
+};
+
+use super::{auth::Auth, html, query_db, validate, write_db};
+
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct FeedParams {
+    limit: Option<usize>,
+}
+
+/// the watchlists the account may see
+async fn visible(auth: &Auth) -> Result<Vec<Watchlist>, StatusCode> {
+    let mut watchlists = query_db(watchlist::list).await?;
+    watchlists.retain(|w| auth.0.can_see(w.alliance_id));
+    Ok(watchlists)
+}
+
+/// the watchlist, a 404 if it doesn't exist or belongs to another alliance
+async fn get_watchlist(auth: &Auth, id: i64) -> Result<Watchlist, StatusCode> {
+    query_db(move |conn| watchlist::get(conn, id))
+        .await?
+        .filter(|w| auth.0.can_see(w.alliance_id))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// only admins may give a watchlist to another alliance or to none at all
+fn scope(auth: &Auth, watchlist: &mut Watchlist) -> Result<(), (StatusCode, String)> {
+    if auth.0.role == Role::Admin {
+        return Ok(());
+    }
+    if auth.0.alliance_id.is_none() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            String::from("Accounts without an alliance can't have watchlists"),
+        ));
+    }
+    watchlist.alliance_id = auth.0.alliance_id;
+    Ok(())
+}
+
+pub async fn list(auth: Auth) -> Result<Json<Vec<Watchlist>>, StatusCode> {
+    Ok(Json(visible(&auth).await?))
+}
+
+pub async fn get(auth: Auth, Path(id): Path<i64>) -> Result<Json<Watchlist>, StatusCode> {
+    Ok(Json(get_watchlist(&auth, id).await?))
+}
+
+pub async fn create(
+    auth: Auth,
+    Json(new): Json<Watchlist>,
+) -> Result<(StatusCode, Json<Watchlist>), (StatusCode, String)> {
+    auth.require(Role::Member).map_err(|status| {
+        (
+            status,
+            String::from("Read only accounts can't create watchlists"),
+        )
+    })?;
+    let mut new = validate(new, Watchlist::validate).await?;
+    scope(&auth, &mut new)?;
+    let stored = new.clone();
+    new.id = write_db(move |conn| watchlist::insert(conn, &stored))
+        .await
+        .map_err(|status| (status, String::from("Failed to store the watchlist")))?;
+    Ok((StatusCode::CREATED, Json(new)))
+}
+
+pub async fn update(
+    auth: Auth,
+    Path(id): Path<i64>,
+    Json(changed): Json<Watchlist>,
+) -> Result<Json<Watchlist>, (StatusCode, String)> {
+    auth.require(Role::Member).map_err(|status| {
+        (
+            status,
+            String::from("Read only accounts can't change watchlists"),
+        )
+    })?;
+    get_watchlist(&auth, id)
+        .await
+        .map_err(|status| (status, format!("No watchlist {id}")))?;
+    let mut changed = validate(changed, Watchlist::validate).await?;
+    scope(&auth, &mut changed)?;
+    changed.id = id;
+    let stored = changed.clone();
+    let existed = write_db(move |conn| watchlist::update(conn, id, &stored))
+        .await
+        .map_err(|status| (status, String::from("Failed to store the watchlist")))?;
+    if !existed {
+        return Err((StatusCode::NOT_FOUND, format!("No watchlist {id}")));
+    }
+    Ok(Json(changed))
+}
+
+pub async fn remove(auth: Auth, Path(id): Path<i64>) -> StatusCode {
+    if let Err(status) = auth.require(Role::Member) {
+        return status;
+    }
+    if let Err(status) = get_watchlist(&auth, id).await {
+        return status;
+    }
+    match write_db(move |conn| watchlist::delete(conn, id)).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(status) => status,
+    }
+}
+
+async fn feed(id: i64, params: &FeedParams) -> Result<Vec<WatchlistEvent>, StatusCode> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    query_db(move |conn| watchlist::events(conn, id, limit)).await
+}
+
+pub async fn api_events(
+    auth: Auth,
+    Path(id): Path<i64>,
+    Query(params): Query<FeedParams>,
+) -> Result<Json<Vec<WatchlistEvent>>, StatusCode> {
+    get_watchlist(&auth, id).await?;
+    Ok(Json(feed(id, &params).await?))
+}
+
+fn describe(w: &Watchlist) -> String {
+    let mut parts = Vec::new();
+    if !w.player_ids.is_empty() {
+        parts.push(format!("{} players", w.player_ids.len()));
+    }
+    if !w.alliance_ids.is_empty() {
+        parts.push(format!("{} alliances", w.alliance_ids.len()));
+    }
+    if !w.regions.is_empty() {
+        parts.push(format!("{} regions", w.regions.len()));
+    }
+    parts.join(", ")
+}
+
+pub async fn watchlists_page(auth: Auth) -> Result<Html<String>, StatusCode> {
+    let watchlists = visible(&auth).await?;
+    let rows: Vec<_> = watchlists
+        .iter()
+        .map(|w| {
+            vec![
+                format!(
+                    r#"<a href="/watchlist/{}">{}</a>"#,
+                    w.id,
+                    html::escape(&w.name)
+                ),
+                describe(w),
+                html::escape(w.webhook_url.as_deref().unwrap_or_default()),
+                html::escape(w.email.as_deref().unwrap_or_default()),
+            ]
+        })
+        .collect();
+    let body = format!(
+        "<h1>Watchlists</h1>
+<p>Create a watchlist by posting <code>{{\"name\": \"enemy alliance\", \"alliance_ids\": [12], \"player_ids\": [], \"regions\": [{{\"x0\": 500, \"y0\": 500, \"x1\": 600, \"y1\": 600}}]}}</code> to <code>/api/watchlists</code>, optionally with a <code>webhook_url</code> and an <code>email</code> that get every event.</p>
+{}",
+        html::table(&["Name", "Watching", "Webhook", "Email"], &rows)
+    );
+    Ok(html::page("Watchlists", &body))
+}
+
+pub async fn watchlist_page(
+    auth: Auth,
+    Path(id): Path<i64>,
+    Query(params): Query<FeedParams>,
+) -> Result<Html<String>, StatusCode> {
+    let w = get_watchlist(&auth, id).await?;
+    let events = feed(id, &params).await?;
+    let rows: Vec<_> = events
+        .iter()
+        .map(|e| {
+            vec![
+                e.date.format("%Y-%m-%d %H:%M").to_string(),
+                html::escape(&e.typ),
+                html::escape(&e.summary),
+            ]
+        })
+        .collect();
+    let body = format!(
+        r#"<h1>{name}</h1>
+<p>Watching {watching}. <a href="/api/watchlists/{id}/events">json</a> / <a href="/feeds/watchlist/{id}.atom">atom</a></p>
+{table}"#,
+        name = html::escape(&w.name),
+        watching = describe(&w),
+        table = html::table(&["Date", "Type", "Event"], &rows),
+    );
+    Ok(html::page(&w.name, &body))
+}
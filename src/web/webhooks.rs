@@ -0,0 +1,42 @@
+//! Management of the webhook subscriptions, for admins only. The deliveries themselves are handled
+//! by the webhook thread, see `crate::webhooks`.
+
+use axum::{extract::Path, http::StatusCode, Json};
+
+use crate::{
+    db::accounts::Role,
+    webhooks::subscription::{self, Subscription},
+};
+
+use super::{auth::Auth, query_db, validate, write_db};
+
+pub async fn list(auth: Auth) -> Result<Json<Vec<Subscription>>, StatusCode> {
+    auth.require(Role::Admin)?;
+    let subscriptions = query_db(subscription::list).await?;
+    Ok(Json(subscriptions))
+}
+
+pub async fn create(
+    auth: Auth,
+    Json(new): Json<Subscription>,
+) -> Result<(StatusCode, Json<Subscription>), (StatusCode, String)> {
+    auth.require(Role::Admin)
+        .map_err(|status| (status, String::from("Only admins manage webhooks")))?;
+    let mut new = validate(new, Subscription::validate).await?;
+    let stored = new.clone();
+    new.id = write_db(move |conn| subscription::insert(conn, &stored))
+        .await
+        .map_err(|status| (status, String::from("Failed to store the subscription")))?;
+    Ok((StatusCode::CREATED, Json(new)))
+}
+
+pub async fn remove(auth: Auth, Path(id): Path<i64>) -> StatusCode {
+    if let Err(status) = auth.require(Role::Admin) {
+        return status;
+    }
+    match write_db(move |conn| subscription::delete(conn, id)).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(status) => status,
+    }
+}
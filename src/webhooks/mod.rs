@@ -0,0 +1,478 @@
+//! Delivers the queued webhooks. Deliveries are queued by the DB in the same transaction as the
+//! events themselves, this thread posts them and retries failed deliveries with an exponential
+//! backoff until `MAX_ATTEMPTS` is reached. Deliveries to `mailto:` urls are mailed instead.
+
+use std::{
+    net::IpAddr,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    db::{self, watchlist::MAILTO},
+    messages::MessageFromDBToWebhooks,
+    metrics,
+};
+
+use self::mail::Mailer;
+
+mod mail;
+pub mod subscription;
+
+/// how often the queue is checked for retries if no new deliveries come in
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// give up on a delivery after this many failed attempts
+const MAX_ATTEMPTS: u32 = 8;
+/// wait this long after the first failed attempt, doubled after every further failure
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// the number of deliveries posted per round, the rest waits for the next one
+const BATCH_SIZE: usize = 100;
+/// give up on a single post after this long
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Webhooks {
+    rx: Receiver<MessageFromDBToWebhooks>,
+    mailer: Option<Mailer>,
+}
+
+impl Webhooks {
+    pub fn new(rx: Receiver<MessageFromDBToWebhooks>) -> Self {
+        Self {
+            rx,
+            mailer: Mailer::from_env(),
+        }
+    }
+
+    pub fn start(self) {
+        // the DB tells us once the schema exists, don't touch the queue before that
+        match self.rx.recv() {
+            Ok(msg) => {
+                metrics::received(metrics::DB_TO_WEBHOOKS);
+                info!("Got Message from DB to Webhooks: {msg}");
+            }
+            Err(_) => return,
+        }
+        let conn = db::open_read_write().expect("Failed to open the database for webhooks");
+
+        loop {
+            let res = deliver_due(
+                &conn,
+                subscription::is_public,
+                self.mailer.as_ref(),
+                Utc::now(),
+            );
+            if let Err(err) = res {
+                error!("Failed to process the webhook queue: {err:?}");
+            }
+
+            match self.rx.recv_timeout(POLL_INTERVAL) {
+                Ok(msg) => {
+                    metrics::received(metrics::DB_TO_WEBHOOKS);
+                    info!("Got Message from DB to Webhooks: {msg}");
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+/// the time to wait before the next attempt, after `attempts` failed attempts
+fn retry_delay(attempts: u32) -> chrono::Duration {
+    let delay = FIRST_RETRY_DELAY * 2u32.pow(attempts.saturating_sub(1).min(16));
+    chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::max_value())
+}
+
+/// Post the payload to the url if its host only resolves to `allowed` addresses. The client only
+/// connects to the checked addresses and doesn't follow redirects, so neither a changed DNS entry
+/// nor a redirect leads it anywhere else.
+fn post(url: &str, payload: &str, allowed: fn(IpAddr) -> bool) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|err| format!("Not a url: {url} ({err})"))?;
+    let (host, addrs) = subscription::resolve(&parsed, allowed)?;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(&host, &addrs)
+        .build()
+        .map_err(|err| err.to_string())?;
+    let response = client
+        .post(parsed)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .send()
+        .map_err(|err| err.to_string())?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(format!("{status}"))
+}
+
+fn deliver(
+    allowed: fn(IpAddr) -> bool,
+    mailer: Option<&Mailer>,
+    url: &str,
+    payload: &str,
+) -> Result<(), String> {
+    match url.strip_prefix(MAILTO) {
+        Some(to) => mailer
+            .ok_or_else(|| String::from("No mail server configured"))?
+            .send(to, payload),
+        None => post(url, payload, allowed),
+    }
+}
+
+/// post every delivery that is due at `now` to the `allowed` addresses and record the outcome.
+/// Returns the number of successful deliveries.
+fn deliver_due(
+    conn: &Connection,
+    allowed: fn(IpAddr) -> bool,
+    mailer: Option<&Mailer>,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<usize> {
+    let due = conn
+        .prepare(
+            "SELECT id, url, payload, attempts FROM webhook_deliveries
+                WHERE delivered IS NULL AND attempts < ?1 AND next_attempt <= ?2
+                ORDER BY id LIMIT ?3",
+        )?
+        .query((MAX_ATTEMPTS, now, BATCH_SIZE))?
+        .mapped(|r| {
+            Ok((
+                r.get::<_, i64>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, u32>(3)?,
+            ))
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut delivered = 0;
+    for (id, url, payload, attempts) in due {
+        let attempts = attempts + 1;
+        match deliver(allowed, mailer, &url, &payload) {
+            Ok(()) => {
+                debug!("Delivered webhook {id} to {url}");
+                conn.execute(
+                    "UPDATE webhook_deliveries SET attempts = ?2, delivered = ?3 WHERE id = ?1",
+                    (id, attempts, now),
+                )?;
+                delivered += 1;
+            }
+            Err(err) => {
+                if attempts >= MAX_ATTEMPTS {
+                    warn!("Giving up on webhook {id} to {url} after {attempts} attempts: {err}");
+                } else {
+                    warn!("Failed to deliver webhook {id} to {url}, will retry: {err}");
+                }
+                conn.execute(
+                    "UPDATE webhook_deliveries SET attempts = ?2, next_attempt = ?3, last_error = ?4
+                        WHERE id = ?1",
+                    (id, attempts, now + retry_delay(attempts), err),
+                )?;
+            }
+        }
+    }
+    return Ok(delivered);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use chrono::{DateTime, Utc};
+    use rusqlite::Connection;
+
+    use super::{deliver_due, retry_delay, subscription, IpAddr};
+    use crate::{
+        db::orm::{OrmGS, OrmPlayer},
+        messages::MessageFromModelToDB,
+        model::region::Region,
+    };
+
+    /// a minimal http server that answers a single request with the given status and returns the
+    /// request body
+    fn stand_in(status: u16) -> (String, JoinHandle<String>) {
+        stand_in_with(status, String::new())
+    }
+
+    /// like `stand_in`, with additional header lines in the response
+    fn stand_in_with(status: u16, headers: String) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let body_start = loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+            let length: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:"))
+                .map_or(0, |l| l.trim().parse().unwrap());
+            while request.len() < body_start + length {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {status} Whatever\r\n{headers}Content-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .unwrap();
+            String::from_utf8(request[body_start..].to_vec()).unwrap()
+        });
+        (url, handle)
+    }
+
+    fn date() -> DateTime<Utc> {
+        "2024-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn gs(name: &str, points: u16, x: f32, y: f32) -> OrmGS {
+        OrmGS {
+            date: date(),
+            name: name.to_string(),
+            points,
+            x,
+            y,
+            player_name: Some(String::from("Achilles")),
+            alliance_name: Some(String::from("Myrmidons")),
+            town_id: Some(1),
+            player_id: Some(2),
+            alliance_id: Some(3),
+            observed_between: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    fn setup(subscriptions: &[subscription::Subscription]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        subscription::ensure_schema(&conn).unwrap();
+        for s in subscriptions {
+            subscription::insert(&conn, s).unwrap();
+        }
+        conn
+    }
+
+    fn subscription(url: &str, event_types: &[&str]) -> subscription::Subscription {
+        subscription::Subscription {
+            id: 0,
+            url: url.to_string(),
+            event_types: event_types.iter().map(ToString::to_string).collect(),
+            region: None,
+            min_points: 0,
+        }
+    }
+
+    fn enqueue(conn: &mut Connection, msg: &MessageFromModelToDB) -> usize {
+        let transaction = conn.transaction().unwrap();
+        let queued = subscription::enqueue(&transaction, msg, date());
+        transaction.commit().unwrap();
+        queued
+    }
+
+    /// the stand-ins listen on localhost
+    fn anywhere(_: IpAddr) -> bool {
+        true
+    }
+
+    #[test]
+    fn thousands_separator() {
+        assert_eq!(crate::messages::thousands(0), "0");
+        assert_eq!(crate::messages::thousands(999), "999");
+        assert_eq!(crate::messages::thousands(8234), "8,234");
+        assert_eq!(crate::messages::thousands(1_234_567), "1,234,567");
+    }
+
+    #[test]
+    fn validation() {
+        assert!(subscription("ftp://example.com", &["gs_appeared"])
+            .validate()
+            .is_err());
+        assert!(subscription("https://example.com", &[]).validate().is_err());
+        assert!(subscription("https://example.com", &["gs_vanished"])
+            .validate()
+            .is_err());
+        assert!(subscription("https://93.184.216.34/hook", &["gs_appeared"])
+            .validate()
+            .is_ok());
+        // nothing on the server itself or the network it runs in
+        for url in [
+            "http://127.0.0.1:8080/",
+            "http://localhost/",
+            "http://10.1.2.3/",
+            "http://192.168.0.1/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://0.0.0.0/",
+        ] {
+            assert!(
+                subscription(url, &["gs_appeared"]).validate().is_err(),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn subscriptions_filter_events() {
+        let mut by_region = subscription("http://a", &["gs_appeared", "player_disappeared"]);
+        by_region.region = Some(Region::ocean(54));
+        let mut by_points = subscription("http://b", &["gs_appeared", "player_disappeared"]);
+        by_points.min_points = 5000;
+        let only_conquered = subscription("http://c", &["gs_conquered"]);
+        let mut conn = setup(&[by_region, by_points, only_conquered]);
+
+        let msg = MessageFromModelToDB::GSAppeared(vec![
+            gs("Inside", 100, 512.0, 487.0),
+            gs("Outside", 8234, 100.0, 100.0),
+        ]);
+        assert_eq!(enqueue(&mut conn, &msg), 2);
+        let urls: Vec<(String, String)> = conn
+            .prepare("SELECT url, payload FROM webhook_deliveries ORDER BY id")
+            .unwrap()
+            .query([])
+            .unwrap()
+            .mapped(|r| Ok((r.get(0)?, r.get(1)?)))
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(urls[0].0, "http://a");
+        assert!(urls[0].1.contains("Inside"));
+        assert_eq!(urls[1].0, "http://b");
+        assert!(urls[1]
+            .1
+            .contains("New ghost town Outside 8,234 pts at 100|100 (ocean 11)"));
+
+        // departed players have no position, so only the points subscription gets them
+        let msg = MessageFromModelToDB::PlayersDisappeared(vec![OrmPlayer {
+            date: date(),
+            observed_between: None,
+            player_id: Some(4),
+            alliance_id: None,
+            name: String::from("Hector"),
+            towns: 3,
+            points: 12000,
+            rank: 17,
+            alliance: None,
+        }]);
+        assert_eq!(enqueue(&mut conn, &msg), 1);
+
+        let msg = MessageFromModelToDB::GSConquered(vec![gs("Taken", 10, 1.0, 1.0)]);
+        assert_eq!(enqueue(&mut conn, &msg), 1);
+    }
+
+    #[test]
+    fn delivers_to_endpoint() {
+        let (url, server) = stand_in(200);
+        let mut conn = setup(&[subscription(&url, &["gs_appeared"])]);
+        let msg = MessageFromModelToDB::GSAppeared(vec![gs("Troy", 8234, 512.0, 487.0)]);
+        assert_eq!(enqueue(&mut conn, &msg), 1);
+
+        assert_eq!(deliver_due(&conn, anywhere, None, date()).unwrap(), 1);
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        let text = "New ghost town Troy 8,234 pts at 512|487 (ocean 54), was owned by Achilles [Myrmidons]";
+        assert_eq!(body["content"], text);
+        assert_eq!(body["text"], text);
+        assert_eq!(body["event"]["type"], "gs_appeared");
+        assert_eq!(body["event"]["data"]["points"], 8234);
+
+        // nothing is delivered twice
+        assert_eq!(deliver_due(&conn, anywhere, None, date()).unwrap(), 0);
+    }
+
+    fn last_error(conn: &Connection) -> String {
+        conn.query_row("SELECT last_error FROM webhook_deliveries", [], |r| {
+            r.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn delivers_only_to_public_addresses() {
+        let mut conn = setup(&[subscription("http://127.0.0.1:9/hook", &["gs_appeared"])]);
+        let msg = MessageFromModelToDB::GSAppeared(vec![gs("Troy", 8234, 512.0, 487.0)]);
+        enqueue(&mut conn, &msg);
+
+        assert_eq!(
+            deliver_due(&conn, subscription::is_public, None, date()).unwrap(),
+            0
+        );
+        assert!(last_error(&conn).contains("not a public address"));
+    }
+
+    #[test]
+    fn does_not_follow_redirects() {
+        // the target is never contacted, so its thread isn't joined
+        let (target, _) = stand_in(200);
+        let (url, server) = stand_in_with(302, format!("Location: {target}\r\n"));
+        let mut conn = setup(&[subscription(&url, &["gs_appeared"])]);
+        let msg = MessageFromModelToDB::GSAppeared(vec![gs("Troy", 8234, 512.0, 487.0)]);
+        enqueue(&mut conn, &msg);
+
+        assert_eq!(deliver_due(&conn, anywhere, None, date()).unwrap(), 0);
+        server.join().unwrap();
+        assert!(last_error(&conn).contains("302"));
+    }
+
+    #[test]
+    fn retries_failed_deliveries() {
+        let (url, server) = stand_in(500);
+        let mut conn = setup(&[subscription(&url, &["gs_appeared"])]);
+        let msg = MessageFromModelToDB::GSAppeared(vec![gs("Troy", 8234, 512.0, 487.0)]);
+        enqueue(&mut conn, &msg);
+
+        assert_eq!(deliver_due(&conn, anywhere, None, date()).unwrap(), 0);
+        server.join().unwrap();
+        let (attempts, next_attempt, last_error): (u32, DateTime<Utc>, String) = conn
+            .query_row(
+                "SELECT attempts, next_attempt, last_error FROM webhook_deliveries",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(next_attempt, date() + retry_delay(1));
+        assert!(last_error.contains("500"));
+
+        // not due yet, so the endpoint isn't contacted
+        assert_eq!(deliver_due(&conn, anywhere, None, date()).unwrap(), 0);
+
+        let (url, server) = stand_in(204);
+        conn.execute("UPDATE webhook_deliveries SET url = ?1", [&url])
+            .unwrap();
+        let later = date() + retry_delay(1);
+        assert_eq!(deliver_due(&conn, anywhere, None, later).unwrap(), 1);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn deleting_a_subscription_drops_its_queue() {
+        let mut conn = setup(&[subscription("http://a", &["gs_appeared"])]);
+        let msg = MessageFromModelToDB::GSAppeared(vec![gs("Troy", 8234, 512.0, 487.0)]);
+        enqueue(&mut conn, &msg);
+        let id = subscription::list(&conn).unwrap()[0].id;
+        assert!(subscription::delete(&conn, id).unwrap());
+        assert!(!subscription::delete(&conn, id).unwrap());
+        let pending: u32 = conn
+            .query_row("SELECT COUNT(*) FROM webhook_deliveries", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(pending, 0);
+    }
+}
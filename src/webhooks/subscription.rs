@@ -0,0 +1,287 @@
+//! Webhook subscriptions and the queue of pending deliveries, both stored in the database.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, Row, Transaction};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, trace};
+
+use crate::{
+    messages::{Event, MessageFromModelToDB},
+    model::region::Region,
+};
+
+/// the event types a subscription can ask for
+pub const EVENT_TYPES: [&str; 7] = [
+    "gs_appeared",
+    "gs_conquered",
+    "player_disappeared",
+    "player_renamed",
+    "alliance_renamed",
+    "player_changed_alliance",
+    "town_changed_owner",
+];
+
+/// whether the address is reachable from the internet, webhooks must not reach the server itself
+/// or the network it runs in
+pub fn is_public(ip: IpAddr) -> bool {
+    let v4 = |ip: Ipv4Addr| {
+        let [a, b, ..] = ip.octets();
+        // 100.64.0.0/10 is shared by carriers
+        let shared = a == 100 && b & 0xc0 == 64;
+        !(ip.is_loopback()
+            || ip.is_private()
+            || ip.is_link_local()
+            || ip.is_unspecified()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || shared)
+    };
+    match ip {
+        IpAddr::V4(ip) => v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => v4(mapped),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Checks that the url is http(s) and that its host only resolves to public addresses. Blocks
+/// while the host is resolved.
+pub fn validate_url(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|err| format!("Not a url: {url} ({err})"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("Not a http(s) url: {url}"));
+    }
+    resolve(&parsed, is_public).map(|_| ())
+}
+
+/// The host of the url and the addresses it resolves to, unless one of them isn't `allowed`.
+/// Blocks while the host is resolved.
+pub fn resolve(
+    url: &reqwest::Url,
+    allowed: fn(IpAddr) -> bool,
+) -> Result<(String, Vec<SocketAddr>), String> {
+    let Some(host) = url.host_str() else {
+        return Err(format!("No host in {url}"));
+    };
+    // ipv6 addresses are written in brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<_> = (host, port)
+        .to_socket_addrs()
+        .map_err(|err| format!("Failed to resolve {host}: {err}"))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("Failed to resolve {host}"));
+    }
+    if let Some(addr) = addrs.iter().find(|a| !allowed(a.ip())) {
+        return Err(format!("{host} is not a public address ({})", addr.ip()));
+    }
+    return Ok((host.to_string(), addrs));
+}
+
+pub fn ensure_schema(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS webhook_subscriptions(
+            id INTEGER PRIMARY KEY,
+            url TEXT NOT NULL,
+            event_types TEXT NOT NULL,
+            x0 REAL,
+            y0 REAL,
+            x1 REAL,
+            y1 REAL,
+            min_points INTEGER NOT NULL
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS webhook_deliveries(
+            id INTEGER PRIMARY KEY,
+            subscription_id INTEGER NOT NULL,
+            url TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            created TEXT NOT NULL,
+            attempts INTEGER NOT NULL,
+            next_attempt TEXT NOT NULL,
+            last_error TEXT,
+            delivered TEXT
+        );",
+        (),
+    )?;
+    return Ok(());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    /// assigned by the database, ignored when creating a subscription
+    #[serde(default)]
+    pub id: i64,
+    pub url: String,
+    /// any of [`EVENT_TYPES`]
+    pub event_types: Vec<String>,
+    /// only events within this region. Events without a position are skipped if this is set.
+    pub region: Option<Region>,
+    /// only events with at least this many points. Events without points are skipped if this is
+    /// larger than zero.
+    #[serde(default)]
+    pub min_points: u32,
+}
+
+impl<'a> TryFrom<&Row<'a>> for Subscription {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'a>) -> Result<Self, Self::Error> {
+        let event_types: String = row.get(2)?;
+        let corners: (Option<f32>, Option<f32>, Option<f32>, Option<f32>) =
+            (row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?);
+        let region = match corners {
+            (Some(x0), Some(y0), Some(x1), Some(y1)) => Some(Region::from_corners(x0, y0, x1, y1)),
+            _ => None,
+        };
+        Ok(Self {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            event_types: event_types.split(',').map(String::from).collect(),
+            region,
+            min_points: row.get(7)?,
+        })
+    }
+}
+
+impl Subscription {
+    /// checks the url and event types, returns a description of the problem if there is one
+    /// blocks while the host of the url is resolved, see `validate_url`
+    pub fn validate(&self) -> Result<(), String> {
+        validate_url(&self.url)?;
+        if self.event_types.is_empty() {
+            return Err(String::from("No event types given"));
+        }
+        for typ in &self.event_types {
+            if !EVENT_TYPES.contains(&typ.as_str()) {
+                return Err(format!(
+                    "Unknown event type {typ}, expected any of {}",
+                    EVENT_TYPES.join(", ")
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        if !self.event_types.iter().any(|t| t == event.typ) {
+            return false;
+        }
+        if let Some(region) = self.region {
+            match event.position {
+                Some((x, y)) if region.contains(x, y) => {}
+                _ => return false,
+            }
+        }
+        if self.min_points > 0 && event.points.unwrap_or(0) < self.min_points {
+            return false;
+        }
+        return true;
+    }
+}
+
+pub fn list(conn: &Connection) -> rusqlite::Result<Vec<Subscription>> {
+    conn.prepare("SELECT * FROM webhook_subscriptions ORDER BY id")?
+        .query([])?
+        .mapped(|r| Subscription::try_from(r))
+        .collect()
+}
+
+/// store the subscription and return its id
+pub fn insert(conn: &Connection, subscription: &Subscription) -> rusqlite::Result<i64> {
+    let region = subscription.region;
+    conn.execute(
+        "INSERT INTO webhook_subscriptions(url, event_types, x0, y0, x1, y1, min_points)
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            subscription.url.as_str(),
+            subscription.event_types.join(","),
+            region.map(|r| r.x0),
+            region.map(|r| r.y0),
+            region.map(|r| r.x1),
+            region.map(|r| r.y1),
+            subscription.min_points,
+        ),
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// remove the subscription and its pending deliveries. Returns whether it existed.
+pub fn delete(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.execute(
+        "DELETE FROM webhook_deliveries WHERE subscription_id = ?1 AND delivered IS NULL",
+        [id],
+    )?;
+    let deleted = conn.execute("DELETE FROM webhook_subscriptions WHERE id = ?1", [id])?;
+    Ok(deleted > 0)
+}
+
+/// the json body that is posted. `content` is what discord shows, `text` what slack shows and
+/// `event` carries the full event for everyone else.
+pub fn payload(event: &Event) -> serde_json::Value {
+    json!({
+        "username": "gregswatch",
+        "content": event.summary,
+        "text": event.summary,
+        "event": {
+            "type": event.typ,
+            "data": event.data,
+        },
+    })
+}
+
+/// queue a delivery for every event in the message and every subscription that wants it.
+/// Returns the number of queued deliveries.
+pub fn enqueue(transaction: &Transaction, msg: &MessageFromModelToDB, now: DateTime<Utc>) -> usize {
+    let subscriptions = match list(transaction) {
+        Ok(subscriptions) => subscriptions,
+        Err(err) => {
+            error!("Failed to load webhook subscriptions: {err:?}");
+            return 0;
+        }
+    };
+    if subscriptions.is_empty() {
+        return 0;
+    }
+
+    let mut prepared_statement = transaction
+        .prepare(
+            "INSERT INTO webhook_deliveries(subscription_id, url, payload, created, attempts, next_attempt)
+                VALUES(?1, ?2, ?3, ?4, 0, ?4)",
+        )
+        .expect("failed to prepare statement");
+    let mut queued = 0;
+    for event in msg.events() {
+        let payload = payload(&event).to_string();
+        for subscription in subscriptions.iter().filter(|s| s.matches(&event)) {
+            trace!(
+                "Queueing webhook for subscription {}: {payload}",
+                subscription.id
+            );
+            let res = prepared_statement.execute((
+                subscription.id,
+                subscription.url.as_str(),
+                payload.as_str(),
+                now,
+            ));
+            match res {
+                Ok(_) => queued += 1,
+                Err(err) => error!("Failed to queue webhook delivery: {err:?}"),
+            }
+        }
+    }
+    return queued;
+}